@@ -43,6 +43,12 @@ pub enum Error {
 	IssuerInvalid,
 	ExpirationInvalid,
 	AudienceInvalid,
+	AlgorithmInvalid(String),
+	AlgorithmNotAllowed(String),
+	KeyMismatch,
+	DecryptionFailed,
+	KeyNotFound(String),
+	KeyInvalid(String),
 	FormatInvalid(String),
 	IoError(String),
 	OpenSslError(String),
@@ -58,6 +64,12 @@ impl fmt::Display for Error {
 			Error::IssuerInvalid => write!(f, "Issuer invalid."),
 			Error::ExpirationInvalid => write!(f, "Expiration invalid."),
 			Error::AudienceInvalid => write!(f, "Audience invalid."),
+			Error::AlgorithmInvalid(alg) => write!(f, "Algorithm invalid: {}.", alg),
+			Error::AlgorithmNotAllowed(alg) => write!(f, "Algorithm not allowed: {}.", alg),
+			Error::KeyMismatch => write!(f, "Key does not match the algorithm."),
+			Error::DecryptionFailed => write!(f, "Decryption failed."),
+			Error::KeyNotFound(kid) => write!(f, "Key not found: {}.", kid),
+			Error::KeyInvalid(msg) => write!(f, "Key invalid: {}.", msg),
 			Error::FormatInvalid(msg) => write!(f, "Format invalid: {}.", msg),
 			Error::IoError(msg) => write!(f, "IO error: {}.", msg),
 			Error::OpenSslError(msg) => write!(f, "Open SSL error: {}.", msg),
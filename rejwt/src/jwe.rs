@@ -0,0 +1,280 @@
+//! JSON Web Encryption (RFC 7516), compact serialization only.
+//!
+//! The content encryption key is wrapped with `RSA-OAEP`, the payload
+//! is encrypted with either `A256GCM` or `A128CBC-HS256`. Signed tokens
+//! can be wrapped in a JWE with [`encode_nested`] and unwrapped with
+//! [`decode_nested`].
+
+use std::fmt;
+use std::str::FromStr;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rand::rand_bytes;
+use openssl::rsa::{Padding, Rsa};
+use openssl::sign::Signer;
+use openssl::symm::{self, Cipher};
+use serde_json::Value as JsonValue;
+use base64::{encode_config as b64_enc, decode_config as b64_dec};
+
+use crate::error::Error;
+use crate::jwk::Jwk;
+use crate::{decode, encode, secure_compare, Algorithm, DecodingKey, EncodingKey};
+
+const SEGMENTS_COUNT: usize = 5;
+
+const KEY_MANAGEMENT: &str = "RSA-OAEP";
+
+/// content encryption algorithms
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncryption {
+	A256GCM,
+	A128CbcHs256,
+}
+
+impl ContentEncryption {
+	fn key_len(self) -> usize {
+		32
+	}
+
+	fn iv_len(self) -> usize {
+		match self {
+			ContentEncryption::A256GCM => 12,
+			ContentEncryption::A128CbcHs256 => 16,
+		}
+	}
+}
+
+impl fmt::Display for ContentEncryption {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
+			ContentEncryption::A256GCM => "A256GCM",
+			ContentEncryption::A128CbcHs256 => "A128CBC-HS256",
+		})
+	}
+}
+
+impl FromStr for ContentEncryption {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<ContentEncryption, Error> {
+		match s {
+			"A256GCM" => Ok(ContentEncryption::A256GCM),
+			"A128CBC-HS256" => Ok(ContentEncryption::A128CbcHs256),
+			other => Err(Error::AlgorithmInvalid(other.to_owned())),
+		}
+	}
+}
+
+/// RSA public key of the recipient
+#[derive(Clone)]
+pub struct EncryptingKey(Rsa<Public>);
+
+/// RSA private key of the recipient
+#[derive(Clone)]
+pub struct DecryptingKey(Rsa<Private>);
+
+impl EncryptingKey {
+	pub fn from_rsa_pem(pem: &[u8]) -> Result<EncryptingKey, Error> {
+		Ok(EncryptingKey(PKey::public_key_from_pem(pem)?.rsa()?))
+	}
+
+	pub fn from_jwk(jwk: &Jwk) -> Result<EncryptingKey, Error> {
+		Ok(EncryptingKey(jwk.to_pkey()?.rsa()?))
+	}
+}
+
+impl DecryptingKey {
+	pub fn from_rsa_pem(pem: &[u8]) -> Result<DecryptingKey, Error> {
+		Ok(DecryptingKey(PKey::private_key_from_pem(pem)?.rsa()?))
+	}
+}
+
+/// Encrypts `plaintext` for the owner of `key`.
+pub fn encrypt(
+	mut header: JsonValue,
+	plaintext: &[u8],
+	key: &EncryptingKey,
+	enc: ContentEncryption,
+) -> Result<String, Error> {
+	header["alg"] = JsonValue::String(KEY_MANAGEMENT.to_owned());
+	header["enc"] = JsonValue::String(enc.to_string());
+
+	let mut cek = vec![0; enc.key_len()];
+	rand_bytes(&mut cek)?;
+	let mut iv = vec![0; enc.iv_len()];
+	rand_bytes(&mut iv)?;
+
+	let mut encrypted_key = vec![0; key.0.size() as usize];
+	let len = key.0.public_encrypt(&cek, &mut encrypted_key, Padding::PKCS1_OAEP)?;
+	encrypted_key.truncate(len);
+
+	let encoded_header = b64(serde_json::to_string(&header)?.as_bytes());
+	let (ciphertext, tag) = seal(enc, &cek, &iv, encoded_header.as_bytes(), plaintext)?;
+
+	Ok(format!(
+		"{}.{}.{}.{}.{}",
+		encoded_header,
+		b64(&encrypted_key),
+		b64(&iv),
+		b64(&ciphertext),
+		b64(&tag),
+	))
+}
+
+/// Decrypts a token, returning its header and plaintext.
+///
+/// Only `RSA-OAEP` is accepted as the key management algorithm
+/// and `enc` has to be one of `allowed`.
+pub fn decrypt(
+	encoded_token: &str,
+	key: &DecryptingKey,
+	allowed: &[ContentEncryption],
+) -> Result<(JsonValue, Vec<u8>), Error> {
+	let segments: Vec<&str> = encoded_token.split('.').collect();
+	if segments.len() != SEGMENTS_COUNT {
+		return Err(Error::JWTInvalid);
+	}
+
+	let header: JsonValue = serde_json::from_slice(&unb64(segments[0])?)?;
+	match header["alg"].as_str() {
+		Some(KEY_MANAGEMENT) => (),
+		Some(other) => return Err(Error::AlgorithmNotAllowed(other.to_owned())),
+		None => return Err(Error::AlgorithmInvalid("missing".to_owned())),
+	}
+
+	let enc = ContentEncryption::from_str(
+		header["enc"].as_str().ok_or_else(|| Error::AlgorithmInvalid("missing".to_owned()))?,
+	)?;
+	if !allowed.contains(&enc) {
+		return Err(Error::AlgorithmNotAllowed(enc.to_string()));
+	}
+
+	let encrypted_key = unb64(segments[1])?;
+	let iv = unb64(segments[2])?;
+	let ciphertext = unb64(segments[3])?;
+	let tag = unb64(segments[4])?;
+	if iv.len() != enc.iv_len() {
+		return Err(Error::DecryptionFailed);
+	}
+
+	// a failed unwrap must look exactly like a failed tag check,
+	// so carry on with a random key instead of bailing out (RFC 7516 11.5)
+	let mut cek = vec![0; key.0.size() as usize];
+	let cek = match key.0.private_decrypt(&encrypted_key, &mut cek, Padding::PKCS1_OAEP) {
+		Ok(len) if len == enc.key_len() => cek[..len].to_vec(),
+		_ => {
+			let mut random = vec![0; enc.key_len()];
+			rand_bytes(&mut random)?;
+			random
+		}
+	};
+
+	let plaintext = open(enc, &cek, &iv, segments[0].as_bytes(), &ciphertext, &tag)?;
+	Ok((header, plaintext))
+}
+
+/// Signs `payload` and encrypts the resulting JWS for the owner of `recipient`.
+pub fn encode_nested(
+	header: JsonValue,
+	signing_key: &EncodingKey,
+	payload: &JsonValue,
+	algorithm: Algorithm,
+	recipient: &EncryptingKey,
+	enc: ContentEncryption,
+) -> Result<String, Error> {
+	let jws = encode(header, signing_key, payload, algorithm)?;
+	encrypt(json!({ "cty": "JWT" }), jws.as_bytes(), recipient, enc)
+}
+
+/// Decrypts a nested token and verifies the JWS inside it.
+pub fn decode_nested(
+	encoded_token: &str,
+	decrypting_key: &DecryptingKey,
+	allowed_enc: &[ContentEncryption],
+	verifying_key: &DecodingKey,
+	allowed: &[Algorithm],
+) -> Result<(JsonValue, JsonValue), Error> {
+	let (header, plaintext) = decrypt(encoded_token, decrypting_key, allowed_enc)?;
+	if !header["cty"].as_str().is_some_and(|cty| cty.eq_ignore_ascii_case("JWT")) {
+		return Err(Error::FormatInvalid("JWE does not contain a JWT".to_owned()));
+	}
+
+	let jws = String::from_utf8(plaintext)
+		.map_err(|_| Error::FormatInvalid("JWT is not valid UTF-8".to_owned()))?;
+	decode(&jws, verifying_key, allowed)
+}
+
+fn seal(
+	enc: ContentEncryption,
+	cek: &[u8],
+	iv: &[u8],
+	aad: &[u8],
+	plaintext: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+	match enc {
+		ContentEncryption::A256GCM => {
+			let mut tag = vec![0; 16];
+			let ciphertext = symm::encrypt_aead(Cipher::aes_256_gcm(), cek, Some(iv), aad, plaintext, &mut tag)?;
+			Ok((ciphertext, tag))
+		}
+		ContentEncryption::A128CbcHs256 => {
+			let (mac_key, enc_key) = cek.split_at(16);
+			let ciphertext = symm::encrypt(Cipher::aes_128_cbc(), enc_key, Some(iv), plaintext)?;
+			let tag = cbc_hmac_tag(mac_key, aad, iv, &ciphertext)?;
+			Ok((ciphertext, tag))
+		}
+	}
+}
+
+fn open(
+	enc: ContentEncryption,
+	cek: &[u8],
+	iv: &[u8],
+	aad: &[u8],
+	ciphertext: &[u8],
+	tag: &[u8],
+) -> Result<Vec<u8>, Error> {
+	match enc {
+		ContentEncryption::A256GCM => {
+			if tag.len() != 16 {
+				return Err(Error::DecryptionFailed);
+			}
+
+			symm::decrypt_aead(Cipher::aes_256_gcm(), cek, Some(iv), aad, ciphertext, tag)
+				.map_err(|_| Error::DecryptionFailed)
+		}
+		ContentEncryption::A128CbcHs256 => {
+			let (mac_key, enc_key) = cek.split_at(16);
+			if !secure_compare(tag, &cbc_hmac_tag(mac_key, aad, iv, ciphertext)?) {
+				return Err(Error::DecryptionFailed);
+			}
+
+			symm::decrypt(Cipher::aes_128_cbc(), enc_key, Some(iv), ciphertext)
+				.map_err(|_| Error::DecryptionFailed)
+		}
+	}
+}
+
+/// AES_CBC_HMAC_SHA2 authentication tag, RFC 7518 section 5.2.2.1
+fn cbc_hmac_tag(mac_key: &[u8], aad: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+	let aad_bits = (aad.len() as u64 * 8).to_be_bytes();
+
+	let key = PKey::hmac(mac_key)?;
+	let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+	signer.update(aad)?;
+	signer.update(iv)?;
+	signer.update(ciphertext)?;
+	signer.update(&aad_bits)?;
+
+	let mut tag = signer.sign_to_vec()?;
+	tag.truncate(16);
+	Ok(tag)
+}
+
+fn b64(bytes: &[u8]) -> String {
+	b64_enc(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(s: &str) -> Result<Vec<u8>, Error> {
+	Ok(b64_dec(s, base64::URL_SAFE_NO_PAD)?)
+}
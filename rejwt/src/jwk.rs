@@ -0,0 +1,202 @@
+//! JSON Web Keys (RFC 7517) and key sets.
+//!
+//! Only public keys are handled: a [`Jwk`] can be built from a PEM public key,
+//! serialized, parsed back and turned into a PEM again for verification.
+
+use std::str::FromStr;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::rsa::Rsa;
+use serde::{Deserialize, Serialize};
+use base64::{encode_config as b64_enc, decode_config as b64_dec};
+
+use crate::error::Error;
+use crate::key::DecodingKey;
+use crate::Algorithm;
+
+/// key type specific parameters, tagged by `kty`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kty")]
+pub enum KeyParams {
+	#[serde(rename = "RSA")]
+	Rsa { n: String, e: String },
+	#[serde(rename = "EC")]
+	Ec { crv: String, x: String, y: String },
+	#[serde(rename = "OKP")]
+	Okp { crv: String, x: String },
+}
+
+/// a single public JSON Web Key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwk {
+	#[serde(flatten)]
+	pub params: KeyParams,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub kid: Option<String>,
+	#[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
+	pub usage: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub alg: Option<String>,
+}
+
+/// a JWKS document, `{"keys": [...]}`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwkSet {
+	pub keys: Vec<Jwk>,
+}
+
+impl Jwk {
+	/// Builds a JWK from a PEM encoded RSA, EC or Ed25519 public key.
+	pub fn from_pem(pem: &[u8]) -> Result<Jwk, Error> {
+		Jwk::from_pkey(&PKey::public_key_from_pem(pem)?)
+	}
+
+	/// Builds a JWK from a verification key, HMAC secrets are refused.
+	pub fn from_decoding_key(key: &DecodingKey) -> Result<Jwk, Error> {
+		match *key {
+			DecodingKey::Rsa(ref key) | DecodingKey::Ec(ref key) | DecodingKey::Ed(ref key) =>
+				Jwk::from_pkey(key),
+			DecodingKey::Hmac(_) => Err(Error::KeyInvalid("secrets can't be published".to_owned())),
+		}
+	}
+
+	/// Builds a JWK from a parsed openssl public key.
+	pub fn from_pkey(key: &PKey<Public>) -> Result<Jwk, Error> {
+		let params = match key.id() {
+			Id::RSA => {
+				let rsa = key.rsa()?;
+				KeyParams::Rsa {
+					n: b64(&rsa.n().to_vec()),
+					e: b64(&rsa.e().to_vec()),
+				}
+			}
+			Id::EC => {
+				let ec = key.ec_key()?;
+				let nid = ec.group().curve_name()
+					.ok_or_else(|| Error::KeyInvalid("unnamed curve".to_owned()))?;
+				let (crv, size) = curve_params(nid)?;
+
+				let mut ctx = BigNumContext::new()?;
+				let mut x = BigNum::new()?;
+				let mut y = BigNum::new()?;
+				ec.public_key().affine_coordinates_gfp(ec.group(), &mut x, &mut y, &mut ctx)?;
+
+				KeyParams::Ec {
+					crv: crv.to_owned(),
+					x: b64(&x.to_vec_padded(size)?),
+					y: b64(&y.to_vec_padded(size)?),
+				}
+			}
+			Id::ED25519 => KeyParams::Okp {
+				crv: "Ed25519".to_owned(),
+				x: b64(&key.raw_public_key()?),
+			},
+			_ => return Err(Error::KeyInvalid("unsupported key type".to_owned())),
+		};
+
+		let mut jwk = Jwk { params, kid: None, usage: Some("sig".to_owned()), alg: None };
+		jwk.alg = Some(jwk.algorithm()?.to_string());
+		Ok(jwk)
+	}
+
+	/// Sets the key identifier.
+	pub fn kid<S: Into<String>>(mut self, kid: S) -> Jwk {
+		self.kid = Some(kid.into());
+		self
+	}
+
+	/// Algorithm this key is meant for; `alg` if present,
+	/// otherwise inferred from the key type and curve.
+	pub fn algorithm(&self) -> Result<Algorithm, Error> {
+		if let Some(ref alg) = self.alg {
+			return Algorithm::from_str(alg);
+		}
+
+		match self.params {
+			KeyParams::Rsa { .. } => Ok(Algorithm::RS256),
+			KeyParams::Ec { ref crv, .. } => match crv.as_str() {
+				"P-256" => Ok(Algorithm::ES256),
+				"P-384" => Ok(Algorithm::ES384),
+				"P-521" => Ok(Algorithm::ES512),
+				other => Err(Error::KeyInvalid(format!("unsupported curve {}", other))),
+			},
+			KeyParams::Okp { ref crv, .. } if crv == "Ed25519" => Ok(Algorithm::EdDSA),
+			KeyParams::Okp { ref crv, .. } =>
+				Err(Error::KeyInvalid(format!("unsupported curve {}", crv))),
+		}
+	}
+
+	/// Converts the key into an openssl public key.
+	pub fn to_pkey(&self) -> Result<PKey<Public>, Error> {
+		match self.params {
+			KeyParams::Rsa { ref n, ref e } => {
+				let rsa = Rsa::from_public_components(bignum(n)?, bignum(e)?)?;
+				Ok(PKey::from_rsa(rsa)?)
+			}
+			KeyParams::Ec { ref crv, ref x, ref y } => {
+				let nid = match crv.as_str() {
+					"P-256" => Nid::X9_62_PRIME256V1,
+					"P-384" => Nid::SECP384R1,
+					"P-521" => Nid::SECP521R1,
+					other => return Err(Error::KeyInvalid(format!("unsupported curve {}", other))),
+				};
+				let group = EcGroup::from_curve_name(nid)?;
+				let (x, y) = (bignum(x)?, bignum(y)?);
+				let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+				ec.check_key()?;
+				Ok(PKey::from_ec_key(ec)?)
+			}
+			KeyParams::Okp { ref crv, ref x } if crv == "Ed25519" =>
+				Ok(PKey::public_key_from_raw_bytes(&unb64(x)?, Id::ED25519)?),
+			KeyParams::Okp { ref crv, .. } =>
+				Err(Error::KeyInvalid(format!("unsupported curve {}", crv))),
+		}
+	}
+
+	/// Converts the key into a PEM encoded public key.
+	pub fn to_pem(&self) -> Result<Vec<u8>, Error> {
+		Ok(self.to_pkey()?.public_key_to_pem()?)
+	}
+}
+
+impl JwkSet {
+	pub fn new(keys: Vec<Jwk>) -> JwkSet {
+		JwkSet { keys }
+	}
+
+	/// Finds a key by its `kid`.
+	pub fn find(&self, kid: &str) -> Option<&Jwk> {
+		self.keys.iter().find(|k| k.kid.as_deref() == Some(kid))
+	}
+}
+
+impl FromStr for JwkSet {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<JwkSet, Error> {
+		serde_json::from_str(s).map_err(Error::from)
+	}
+}
+
+fn curve_params(nid: Nid) -> Result<(&'static str, i32), Error> {
+	match nid {
+		Nid::X9_62_PRIME256V1 => Ok(("P-256", 32)),
+		Nid::SECP384R1 => Ok(("P-384", 48)),
+		Nid::SECP521R1 => Ok(("P-521", 66)),
+		_ => Err(Error::KeyInvalid("unsupported curve".to_owned())),
+	}
+}
+
+fn b64(bytes: &[u8]) -> String {
+	b64_enc(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(s: &str) -> Result<Vec<u8>, Error> {
+	Ok(b64_dec(s, base64::URL_SAFE_NO_PAD)?)
+}
+
+fn bignum(s: &str) -> Result<BigNum, Error> {
+	Ok(BigNum::from_slice(&unb64(s)?)?)
+}
@@ -0,0 +1,182 @@
+//! Typed signing and verification keys.
+//!
+//! A key knows which algorithm family it belongs to, so an RSA public key
+//! can never end up being used as an HMAC secret and vice versa.
+//! Keys are parsed once on construction and are cheap to clone,
+//! so they can be kept around and reused for many tokens.
+
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
+
+use crate::error::Error;
+use crate::jwk::Jwk;
+use crate::Algorithm;
+
+/// algorithm families, a key of one family can only verify
+/// signatures made by algorithms of the same family
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyFamily {
+	Hmac,
+	Rsa,
+	Ec,
+	Ed,
+}
+
+impl Algorithm {
+	pub fn family(self) -> KeyFamily {
+		match self {
+			Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => KeyFamily::Hmac,
+			Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => KeyFamily::Rsa,
+			Algorithm::ES256 | Algorithm::ES384 | Algorithm::ES512 => KeyFamily::Ec,
+			Algorithm::EdDSA => KeyFamily::Ed,
+		}
+	}
+}
+
+/// key used to sign tokens
+#[derive(Clone)]
+pub enum EncodingKey {
+	Hmac(Vec<u8>),
+	Rsa(PKey<Private>),
+	Ec(PKey<Private>),
+	Ed(PKey<Private>),
+}
+
+impl EncodingKey {
+	/// A shared HMAC secret.
+	pub fn from_secret(secret: &[u8]) -> EncodingKey {
+		EncodingKey::Hmac(secret.to_vec())
+	}
+
+	/// A PEM encoded RSA private key, fails for any other key type.
+	pub fn from_rsa_pem(pem: &[u8]) -> Result<EncodingKey, Error> {
+		EncodingKey::from_pkey(PKey::private_key_from_pem(pem)?, KeyFamily::Rsa)
+	}
+
+	/// A PEM encoded EC private key, fails for any other key type.
+	pub fn from_ec_pem(pem: &[u8]) -> Result<EncodingKey, Error> {
+		EncodingKey::from_pkey(PKey::private_key_from_pem(pem)?, KeyFamily::Ec)
+	}
+
+	/// A PEM encoded Ed25519 private key, fails for any other key type.
+	pub fn from_ed_pem(pem: &[u8]) -> Result<EncodingKey, Error> {
+		EncodingKey::from_pkey(PKey::private_key_from_pem(pem)?, KeyFamily::Ed)
+	}
+
+	/// Any supported PEM encoded private key, the family follows the key type.
+	pub fn from_pem(pem: &[u8]) -> Result<EncodingKey, Error> {
+		let key = PKey::private_key_from_pem(pem)?;
+		let family = key_family(&key)?;
+		EncodingKey::from_pkey(key, family)
+	}
+
+	/// Like [`EncodingKey::from_pem`], but for an encrypted PEM.
+	pub fn from_encrypted_pem(pem: &[u8], passphrase: &[u8]) -> Result<EncodingKey, Error> {
+		let key = PKey::private_key_from_pem_passphrase(pem, passphrase)?;
+		let family = key_family(&key)?;
+		EncodingKey::from_pkey(key, family)
+	}
+
+	fn from_pkey(key: PKey<Private>, expected: KeyFamily) -> Result<EncodingKey, Error> {
+		Ok(match check_family(&key, expected)? {
+			KeyFamily::Rsa => EncodingKey::Rsa(key),
+			KeyFamily::Ec => EncodingKey::Ec(key),
+			KeyFamily::Ed => EncodingKey::Ed(key),
+			KeyFamily::Hmac => unreachable!("asymmetric keys are never HMAC secrets"),
+		})
+	}
+
+	pub fn family(&self) -> KeyFamily {
+		match *self {
+			EncodingKey::Hmac(_) => KeyFamily::Hmac,
+			EncodingKey::Rsa(_) => KeyFamily::Rsa,
+			EncodingKey::Ec(_) => KeyFamily::Ec,
+			EncodingKey::Ed(_) => KeyFamily::Ed,
+		}
+	}
+
+	/// The matching verification key; for HMAC that's the same secret.
+	pub fn decoding_key(&self) -> Result<DecodingKey, Error> {
+		let public = |key: &PKey<Private>| PKey::public_key_from_pem(&key.public_key_to_pem()?);
+
+		Ok(match *self {
+			EncodingKey::Hmac(ref secret) => DecodingKey::Hmac(secret.clone()),
+			EncodingKey::Rsa(ref key) => DecodingKey::Rsa(public(key)?),
+			EncodingKey::Ec(ref key) => DecodingKey::Ec(public(key)?),
+			EncodingKey::Ed(ref key) => DecodingKey::Ed(public(key)?),
+		})
+	}
+}
+
+/// key used to verify token signatures
+#[derive(Clone)]
+pub enum DecodingKey {
+	Hmac(Vec<u8>),
+	Rsa(PKey<Public>),
+	Ec(PKey<Public>),
+	Ed(PKey<Public>),
+}
+
+impl DecodingKey {
+	/// A shared HMAC secret.
+	pub fn from_secret(secret: &[u8]) -> DecodingKey {
+		DecodingKey::Hmac(secret.to_vec())
+	}
+
+	/// A PEM encoded RSA public key, fails for any other key type.
+	pub fn from_rsa_pem(pem: &[u8]) -> Result<DecodingKey, Error> {
+		DecodingKey::from_pkey(PKey::public_key_from_pem(pem)?, KeyFamily::Rsa)
+	}
+
+	/// A PEM encoded EC public key, fails for any other key type.
+	pub fn from_ec_pem(pem: &[u8]) -> Result<DecodingKey, Error> {
+		DecodingKey::from_pkey(PKey::public_key_from_pem(pem)?, KeyFamily::Ec)
+	}
+
+	/// A PEM encoded Ed25519 public key, fails for any other key type.
+	pub fn from_ed_pem(pem: &[u8]) -> Result<DecodingKey, Error> {
+		DecodingKey::from_pkey(PKey::public_key_from_pem(pem)?, KeyFamily::Ed)
+	}
+
+	/// A public key from a JWK, the family follows the `kty`.
+	pub fn from_jwk(jwk: &Jwk) -> Result<DecodingKey, Error> {
+		let key = jwk.to_pkey()?;
+		let family = key_family(&key)?;
+		DecodingKey::from_pkey(key, family)
+	}
+
+	fn from_pkey(key: PKey<Public>, expected: KeyFamily) -> Result<DecodingKey, Error> {
+		Ok(match check_family(&key, expected)? {
+			KeyFamily::Rsa => DecodingKey::Rsa(key),
+			KeyFamily::Ec => DecodingKey::Ec(key),
+			KeyFamily::Ed => DecodingKey::Ed(key),
+			KeyFamily::Hmac => unreachable!("public keys are never HMAC secrets"),
+		})
+	}
+
+	pub fn family(&self) -> KeyFamily {
+		match *self {
+			DecodingKey::Hmac(_) => KeyFamily::Hmac,
+			DecodingKey::Rsa(_) => KeyFamily::Rsa,
+			DecodingKey::Ec(_) => KeyFamily::Ec,
+			DecodingKey::Ed(_) => KeyFamily::Ed,
+		}
+	}
+}
+
+fn check_family<T: HasPublic>(key: &PKeyRef<T>, expected: KeyFamily) -> Result<KeyFamily, Error> {
+	let family = key_family(key)?;
+	if family != expected {
+		return Err(Error::KeyInvalid(format!("expected {:?} key, got {:?}", expected, family)));
+	}
+
+	Ok(family)
+}
+
+fn key_family<T: HasPublic>(key: &PKeyRef<T>) -> Result<KeyFamily, Error> {
+	match key.id() {
+		Id::RSA => Ok(KeyFamily::Rsa),
+		Id::EC => Ok(KeyFamily::Ec),
+		Id::ED25519 => Ok(KeyFamily::Ed),
+		_ => Err(Error::KeyInvalid("unsupported key type".to_owned())),
+	}
+}
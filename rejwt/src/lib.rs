@@ -23,22 +23,32 @@ extern crate serde;
 extern crate base64;
 
 #[cfg(test)]
+#[macro_use]
 extern crate serde_json;
 
 #[cfg(not(test))]
+#[macro_use]
 extern crate serde_json;
 
 pub mod error;
+pub mod jwe;
+pub mod jwk;
+pub mod key;
 
+use std::fmt;
 use std::str;
+use std::str::FromStr;
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
 use openssl::hash::MessageDigest;
 use openssl::pkey::{PKey, Private};
-use openssl::rsa::Rsa;
 use openssl::sign::{Signer, Verifier};
 use serde_json::Value as JsonValue;
 use base64::{encode_config as b64_enc, decode_config as b64_dec};
 
 pub use crate::error::Error;
+pub use crate::jwk::{Jwk, JwkSet, KeyParams};
+pub use crate::key::{DecodingKey, EncodingKey, KeyFamily};
 
 const SEGMENTS_COUNT: usize = 3;
 
@@ -52,47 +62,56 @@ pub enum Algorithm {
 	RS256,
 	RS384,
 	RS512,
+	ES256,
+	ES384,
+	ES512,
+	EdDSA,
 }
 
-impl ToString for Algorithm {
-	fn to_string(&self) -> String {
-		match *self {
+impl fmt::Display for Algorithm {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
 			Algorithm::HS256 => "HS256",
 			Algorithm::HS384 => "HS384",
 			Algorithm::HS512 => "HS512",
 			Algorithm::RS256 => "RS256",
 			Algorithm::RS384 => "RS384",
 			Algorithm::RS512 => "RS512",
-		}
-		.to_string()
-	}
-}
-
-pub trait ToKey {
-	fn to_key(&self) -> Result<Vec<u8>, Error>;
-}
-
-impl ToKey for String {
-	fn to_key(&self) -> Result<Vec<u8>, Error> {
-		Ok(self.as_bytes().to_vec())
-	}
-}
-
-impl ToKey for &str {
-	fn to_key(&self) -> Result<Vec<u8>, Error> {
-		Ok(self.as_bytes().to_vec())
+			Algorithm::ES256 => "ES256",
+			Algorithm::ES384 => "ES384",
+			Algorithm::ES512 => "ES512",
+			Algorithm::EdDSA => "EdDSA",
+		})
 	}
 }
 
-impl ToKey for Vec<u8> {
-	fn to_key(&self) -> Result<Vec<u8>, Error> {
-		Ok(self.clone())
+impl FromStr for Algorithm {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Algorithm, Error> {
+		match s {
+			"HS256" => Ok(Algorithm::HS256),
+			"HS384" => Ok(Algorithm::HS384),
+			"HS512" => Ok(Algorithm::HS512),
+			"RS256" => Ok(Algorithm::RS256),
+			"RS384" => Ok(Algorithm::RS384),
+			"RS512" => Ok(Algorithm::RS512),
+			"ES256" => Ok(Algorithm::ES256),
+			"ES384" => Ok(Algorithm::ES384),
+			"ES512" => Ok(Algorithm::ES512),
+			"EdDSA" => Ok(Algorithm::EdDSA),
+			other => Err(Error::AlgorithmInvalid(other.to_owned())),
+		}
 	}
 }
 
-pub fn encode<P: ToKey>(
+/// Signs a token.
+///
+/// `key` has to belong to the family of `algorithm`,
+/// e.g. an RSA private key for RS256.
+pub fn encode(
 	mut header: JsonValue,
-	signing_key: &P,
+	key: &EncodingKey,
 	payload: &JsonValue,
 	algorithm: Algorithm,
 ) -> Result<String, Error> {
@@ -100,37 +119,109 @@ pub fn encode<P: ToKey>(
 	if header["typ"].is_null() {
 		header["typ"] = JsonValue::String(STANDARD_HEADER_TYPE.to_owned());
 	}
-	let signing_input = get_signing_input(&payload, &header)?;
-	let signature = match algorithm {
-		Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 =>
-			sign_hmac(&signing_input, signing_key, algorithm)?,
-		Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 =>
-			sign_rsa(&signing_input, signing_key, algorithm)?,
+	let signing_input = get_signing_input(payload, &header)?;
+	let signature = match (algorithm, key) {
+		(Algorithm::HS256, EncodingKey::Hmac(secret))
+		| (Algorithm::HS384, EncodingKey::Hmac(secret))
+		| (Algorithm::HS512, EncodingKey::Hmac(secret)) =>
+			sign_hmac(&signing_input, secret, algorithm)?,
+		(Algorithm::RS256, EncodingKey::Rsa(key))
+		| (Algorithm::RS384, EncodingKey::Rsa(key))
+		| (Algorithm::RS512, EncodingKey::Rsa(key)) =>
+			sign(&signing_input, key, get_sha_algorithm(algorithm))?,
+		(Algorithm::ES256, EncodingKey::Ec(key))
+		| (Algorithm::ES384, EncodingKey::Ec(key))
+		| (Algorithm::ES512, EncodingKey::Ec(key)) =>
+			sign_ec(&signing_input, key, algorithm)?,
+		(Algorithm::EdDSA, EncodingKey::Ed(key)) =>
+			sign_eddsa(&signing_input, key)?,
+		_ => return Err(Error::KeyMismatch),
 	};
 
-	Ok(format!("{}.{}", signing_input, signature))
+	Ok(format!("{}.{}", signing_input, b64_enc(signature.as_slice(), base64::URL_SAFE_NO_PAD)))
 }
 
-pub fn decode<P: ToKey>(
+/// Decodes and verifies a token.
+///
+/// The `alg` in the token header must be one of `allowed` and belong
+/// to the same family as `key`, otherwise the token is rejected before
+/// any signature checking takes place.
+pub fn decode(
 	encoded_token: &str,
-	signing_key: &P,
-	algorithm: Algorithm,
+	key: &DecodingKey,
+	allowed: &[Algorithm],
 ) -> Result<(JsonValue, JsonValue), Error> {
 	let (header, payload, signature, signing_input) = decode_segments(encoded_token)?;
-	if !verify_signature(algorithm, signing_input, &signature, signing_key)? {
+	let algorithm = check_algorithm(&header, key, allowed)?;
+
+	if !verify_signature(algorithm, signing_input, &signature, key)? {
 		Err(Error::SignatureInvalid)
 	} else {
 		Ok((header, payload))
 	}
 }
 
-pub fn validate_signature<P: ToKey>(
+/// Decodes a token, picking the verification key from `key_set`
+/// by the `kid` in the token header.
+///
+/// If the key declares an `alg`, the token has to use exactly that one.
+pub fn decode_with_jwks(
 	encoded_token: &str,
-	signing_key: &P,
-	algorithm: Algorithm,
+	key_set: &JwkSet,
+	allowed: &[Algorithm],
+) -> Result<(JsonValue, JsonValue), Error> {
+	let (header, payload, signature, signing_input) = decode_segments(encoded_token)?;
+	let kid = header["kid"].as_str().ok_or(Error::KeyNotFound(String::new()))?;
+	let jwk = key_set.find(kid).ok_or_else(|| Error::KeyNotFound(kid.to_owned()))?;
+	let key = DecodingKey::from_jwk(jwk)?;
+	let algorithm = check_algorithm(&header, &key, allowed)?;
+
+	if jwk.alg.is_some() && jwk.algorithm()? != algorithm {
+		return Err(Error::AlgorithmNotAllowed(algorithm.to_string()));
+	}
+
+	if !verify_signature(algorithm, signing_input, &signature, &key)? {
+		Err(Error::SignatureInvalid)
+	} else {
+		Ok((header, payload))
+	}
+}
+
+pub fn validate_signature(
+	encoded_token: &str,
+	key: &DecodingKey,
+	allowed: &[Algorithm],
 ) -> Result<bool, Error> {
-	let (signature, signing_input) = decode_signature_segments(encoded_token)?;
-	verify_signature(algorithm, signing_input, &signature, signing_key)
+	let (header, _, signature, signing_input) = decode_segments(encoded_token)?;
+	let algorithm = check_algorithm(&header, key, allowed)?;
+	verify_signature(algorithm, signing_input, &signature, key)
+}
+
+/// Reads `alg` from the header and makes sure it is allowed
+/// and usable with the given key.
+fn check_algorithm(
+	header: &JsonValue,
+	key: &DecodingKey,
+	allowed: &[Algorithm],
+) -> Result<Algorithm, Error> {
+	let alg = header["alg"]
+		.as_str()
+		.ok_or_else(|| Error::AlgorithmInvalid("missing".to_owned()))?;
+
+	// "none" is not even an Algorithm, but let's be explicit about it
+	if alg.eq_ignore_ascii_case("none") {
+		return Err(Error::AlgorithmInvalid(alg.to_owned()));
+	}
+
+	let algorithm = Algorithm::from_str(alg)?;
+	if !allowed.contains(&algorithm) {
+		return Err(Error::AlgorithmNotAllowed(alg.to_owned()));
+	}
+	if algorithm.family() != key.family() {
+		return Err(Error::KeyMismatch);
+	}
+
+	Ok(algorithm)
 }
 
 fn get_signing_input(payload: &JsonValue, header: &JsonValue) -> Result<String, Error> {
@@ -141,51 +232,35 @@ fn get_signing_input(payload: &JsonValue, header: &JsonValue) -> Result<String,
 	Ok(format!("{}.{}", encoded_header, encoded_payload))
 }
 
-fn sign_hmac<P: ToKey>(
+fn sign_ec(
 	data: &str,
-	key_path: &P,
+	key: &PKey<Private>,
 	algorithm: Algorithm,
-) -> Result<String, Error> {
-	let stp = match algorithm {
-		Algorithm::HS256 => MessageDigest::sha256(),
-		Algorithm::HS384 => MessageDigest::sha384(),
-		Algorithm::HS512 => MessageDigest::sha512(),
-		_ => panic!("Invalid hmac algorithm"),
-	};
-
-	let key = PKey::hmac(&key_path.to_key()?)?;
-	let mut signer = Signer::new(stp, &key)?;
+) -> Result<Vec<u8>, Error> {
+	let mut signer = Signer::new(get_sha_algorithm(algorithm), key)?;
 	signer.update(data.as_bytes())?;
-	let hmac = signer.sign_to_vec()?;
-	Ok(b64_enc(hmac.as_slice(), base64::URL_SAFE_NO_PAD))
-}
 
-fn sign_rsa<P: ToKey>(
-	data: &str,
-	private_key_path: &P,
-	algorithm: Algorithm,
-) -> Result<String, Error> {
-	let stp = match algorithm {
-		Algorithm::RS256 => MessageDigest::sha256(),
-		Algorithm::RS384 => MessageDigest::sha384(),
-		Algorithm::RS512 => MessageDigest::sha512(),
-		_ => panic!("Invalid hmac algorithm"),
-	};
+	// openssl produces a DER sequence, JWS wants the raw r || s
+	let sig = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+	let size = ec_component_size(algorithm);
+	let mut raw = sig.r().to_vec_padded(size)?;
+	raw.extend(sig.s().to_vec_padded(size)?);
+	Ok(raw)
+}
 
-	let rsa = Rsa::private_key_from_pem(&private_key_path.to_key()?)?;
-	let key = PKey::from_rsa(rsa)?;
-	sign(data, key, stp)
+fn sign_eddsa(data: &str, key: &PKey<Private>) -> Result<Vec<u8>, Error> {
+	let mut signer = Signer::new_without_digest(key)?;
+	signer.sign_oneshot_to_vec(data.as_bytes()).map_err(Error::from)
 }
 
 fn sign(
 	data: &str,
-	private_key: PKey<Private>,
+	private_key: &PKey<Private>,
 	digest: MessageDigest,
-) -> Result<String, Error> {
-	let mut signer = Signer::new(digest, &private_key)?;
+) -> Result<Vec<u8>, Error> {
+	let mut signer = Signer::new(digest, private_key)?;
 	signer.update(data.as_bytes())?;
-	let signature = signer.sign_to_vec()?;
-	Ok(b64_enc(signature.as_slice(), base64::URL_SAFE_NO_PAD))
+	signer.sign_to_vec().map_err(Error::from)
 }
 
 pub fn decode_segments(
@@ -205,20 +280,6 @@ pub fn decode_segments(
 	Ok((header, payload, signature.clone(), signing_input))
 }
 
-fn decode_signature_segments(encoded_token: &str) -> Result<(Vec<u8>, String), Error> {
-	let raw_segments: Vec<&str> = encoded_token.split(".").collect();
-	if raw_segments.len() != SEGMENTS_COUNT {
-		return Err(Error::JWTInvalid);
-	}
-
-	let header_segment = raw_segments[0];
-	let payload_segment = raw_segments[1];
-	let crypto_segment = raw_segments[2];
-	let signature = b64_dec(crypto_segment.as_bytes(), base64::URL_SAFE_NO_PAD)?;
-	let signing_input = format!("{}.{}", header_segment, payload_segment);
-	Ok((signature.clone(), signing_input))
-}
-
 fn decode_header_and_payload(
 	header_segment: &str,
 	payload_segment: &str,
@@ -233,7 +294,7 @@ fn decode_header_and_payload(
 	Ok((header_json, payload_json))
 }
 
-fn sign_hmac2(data: &str, key: &Vec<u8>, algorithm: Algorithm) -> Result<Vec<u8>, Error> {
+fn sign_hmac(data: &str, key: &[u8], algorithm: Algorithm) -> Result<Vec<u8>, Error> {
 	let stp = match algorithm {
 		Algorithm::HS256 => MessageDigest::sha256(),
 		Algorithm::HS384 => MessageDigest::sha384(),
@@ -247,40 +308,73 @@ fn sign_hmac2(data: &str, key: &Vec<u8>, algorithm: Algorithm) -> Result<Vec<u8>
 	signer.sign_to_vec().map_err(Error::from)
 }
 
-fn verify_signature<P: ToKey>(
+fn verify_signature(
 	algorithm: Algorithm,
 	signing_input: String,
 	signature: &[u8],
-	public_key: &P,
+	key: &DecodingKey,
 ) -> Result<bool, Error> {
-	match algorithm {
-		Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
-			let signature2 =
-				sign_hmac2(&signing_input, &public_key.to_key()?, algorithm)?;
+	match (algorithm, key) {
+		(Algorithm::HS256, DecodingKey::Hmac(secret))
+		| (Algorithm::HS384, DecodingKey::Hmac(secret))
+		| (Algorithm::HS512, DecodingKey::Hmac(secret)) => {
+			let signature2 = sign_hmac(&signing_input, secret, algorithm)?;
 			Ok(secure_compare(signature, &signature2))
 		}
-		Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => {
-			let rsa = Rsa::public_key_from_pem(&public_key.to_key()?)?;
-			let key = PKey::from_rsa(rsa)?;
-
+		(Algorithm::RS256, DecodingKey::Rsa(key))
+		| (Algorithm::RS384, DecodingKey::Rsa(key))
+		| (Algorithm::RS512, DecodingKey::Rsa(key)) => {
 			let digest = get_sha_algorithm(algorithm);
-			let mut verifier = Verifier::new(digest, &key)?;
+			let mut verifier = Verifier::new(digest, key)?;
 			verifier.update(signing_input.as_bytes())?;
 			verifier.verify(&signature).map_err(Error::from)
 		}
+		(Algorithm::ES256, DecodingKey::Ec(key))
+		| (Algorithm::ES384, DecodingKey::Ec(key))
+		| (Algorithm::ES512, DecodingKey::Ec(key)) => {
+			let size = ec_component_size(algorithm) as usize;
+			if signature.len() != size * 2 {
+				return Ok(false);
+			}
+
+			let sig = EcdsaSig::from_private_components(
+				BigNum::from_slice(&signature[..size])?,
+				BigNum::from_slice(&signature[size..])?,
+			)?;
+
+			let mut verifier = Verifier::new(get_sha_algorithm(algorithm), key)?;
+			verifier.update(signing_input.as_bytes())?;
+			verifier.verify(&sig.to_der()?).map_err(Error::from)
+		}
+		(Algorithm::EdDSA, DecodingKey::Ed(key)) => {
+			let mut verifier = Verifier::new_without_digest(key)?;
+			verifier
+				.verify_oneshot(signature, signing_input.as_bytes())
+				.map_err(Error::from)
+		}
+		_ => Err(Error::KeyMismatch),
 	}
 }
 
 fn get_sha_algorithm(alg: Algorithm) -> MessageDigest {
 	match alg {
-		Algorithm::RS256 => MessageDigest::sha256(),
-		Algorithm::RS384 => MessageDigest::sha384(),
-		Algorithm::RS512 => MessageDigest::sha512(),
-		_ => panic!("Invalid RSA algorithm"),
+		Algorithm::RS256 | Algorithm::ES256 => MessageDigest::sha256(),
+		Algorithm::RS384 | Algorithm::ES384 => MessageDigest::sha384(),
+		Algorithm::RS512 | Algorithm::ES512 => MessageDigest::sha512(),
+		_ => panic!("Invalid RSA/ECDSA algorithm"),
+	}
+}
+
+fn ec_component_size(alg: Algorithm) -> i32 {
+	match alg {
+		Algorithm::ES256 => 32,
+		Algorithm::ES384 => 48,
+		Algorithm::ES512 => 66,
+		_ => panic!("Invalid ECDSA algorithm"),
 	}
 }
 
-fn secure_compare(a: &[u8], b: &[u8]) -> bool {
+pub(crate) fn secure_compare(a: &[u8], b: &[u8]) -> bool {
 	if a.len() != b.len() {
 		return false;
 	}
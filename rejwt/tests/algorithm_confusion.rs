@@ -0,0 +1,148 @@
+//! Regression tests for algorithm confusion attacks.
+extern crate base64;
+extern crate openssl;
+extern crate rejwt;
+#[macro_use]
+extern crate serde_json;
+
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use rejwt::{
+	decode, decode_with_jwks, encode, Algorithm, DecodingKey, EncodingKey, Error, Jwk, JwkSet,
+};
+use serde_json::Value as JsonValue;
+
+fn rsa_keys() -> (EncodingKey, Vec<u8>) {
+	let rsa = Rsa::generate(2048).unwrap();
+	let private = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+	(private, rsa.public_key_to_pem().unwrap())
+}
+
+fn b64(bytes: &[u8]) -> String {
+	base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// builds a token by hand, so that the header can contain anything
+fn forge(header: JsonValue, payload: JsonValue, signature: &[u8]) -> String {
+	format!(
+		"{}.{}.{}",
+		b64(header.to_string().as_bytes()),
+		b64(payload.to_string().as_bytes()),
+		b64(signature),
+	)
+}
+
+#[test]
+fn valid_rs256_token_is_accepted() {
+	let (private, public) = rsa_keys();
+	let token = encode(json!({}), &private, &json!({"id": 1}), Algorithm::RS256).unwrap();
+	let key = DecodingKey::from_rsa_pem(&public).unwrap();
+
+	let (_, payload) = decode(&token, &key, &[Algorithm::RS256]).unwrap();
+	assert_eq!(payload, json!({"id": 1}));
+}
+
+#[test]
+fn hmac_signed_with_public_key_is_rejected() {
+	let (_, public) = rsa_keys();
+	// the classic attack: HS256 with the (publicly known) RSA key as secret
+	let secret = EncodingKey::from_secret(&public);
+	let token = encode(json!({}), &secret, &json!({"id": 1}), Algorithm::HS256).unwrap();
+	let key = DecodingKey::from_rsa_pem(&public).unwrap();
+
+	assert_eq!(
+		decode(&token, &key, &[Algorithm::RS256]),
+		Err(Error::AlgorithmNotAllowed("HS256".to_owned())),
+	);
+	// even a careless allow-list does not help, the key is typed
+	assert_eq!(
+		decode(&token, &key, &[Algorithm::RS256, Algorithm::HS256]),
+		Err(Error::KeyMismatch),
+	);
+}
+
+#[test]
+fn alg_none_is_rejected() {
+	let (_, public) = rsa_keys();
+	let key = DecodingKey::from_rsa_pem(&public).unwrap();
+
+	for alg in &["none", "None", "NONE", "nOnE"] {
+		let token = forge(json!({"alg": alg, "typ": "JWT"}), json!({"id": 1}), b"");
+		assert_eq!(
+			decode(&token, &key, &[Algorithm::RS256]),
+			Err(Error::AlgorithmInvalid(alg.to_string())),
+		);
+	}
+}
+
+#[test]
+fn missing_or_unknown_alg_is_rejected() {
+	let secret = DecodingKey::from_secret(b"secret");
+
+	let token = forge(json!({"typ": "JWT"}), json!({}), b"");
+	assert!(decode(&token, &secret, &[Algorithm::HS256]).is_err());
+
+	let token = forge(json!({"alg": "HS1"}), json!({}), b"");
+	assert_eq!(
+		decode(&token, &secret, &[Algorithm::HS256]),
+		Err(Error::AlgorithmInvalid("HS1".to_owned())),
+	);
+
+	let token = forge(json!({"alg": 256}), json!({}), b"");
+	assert!(decode(&token, &secret, &[Algorithm::HS256]).is_err());
+}
+
+#[test]
+fn algorithm_outside_allow_list_is_rejected() {
+	let (private, public) = rsa_keys();
+	let token = encode(json!({}), &private, &json!({}), Algorithm::RS512).unwrap();
+	let key = DecodingKey::from_rsa_pem(&public).unwrap();
+
+	assert_eq!(
+		decode(&token, &key, &[Algorithm::RS256]),
+		Err(Error::AlgorithmNotAllowed("RS512".to_owned())),
+	);
+	assert!(decode(&token, &key, &[Algorithm::RS512]).is_ok());
+}
+
+#[test]
+fn typed_keys_refuse_other_key_types() {
+	let ec = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+	let ec_public = PKey::from_ec_key(ec).unwrap().public_key_to_pem().unwrap();
+	let (_, rsa_public) = rsa_keys();
+
+	assert!(DecodingKey::from_rsa_pem(&ec_public).is_err());
+	assert!(DecodingKey::from_ed_pem(&ec_public).is_err());
+	assert!(DecodingKey::from_ec_pem(&rsa_public).is_err());
+	assert!(DecodingKey::from_ec_pem(&ec_public).is_ok());
+}
+
+#[test]
+fn jwks_keys_cannot_be_confused_either() {
+	let (private, public) = rsa_keys();
+	let jwks = JwkSet::new(vec![Jwk::from_pem(&public).unwrap().kid("k1")]);
+
+	let secret = EncodingKey::from_secret(&public);
+	let forged = encode(json!({"kid": "k1"}), &secret, &json!({}), Algorithm::HS256).unwrap();
+	assert!(decode_with_jwks(&forged, &jwks, &[Algorithm::RS256, Algorithm::HS256]).is_err());
+
+	// the JWK pins RS256, RS512 signed with the right key is still refused
+	let other = encode(json!({"kid": "k1"}), &private, &json!({}), Algorithm::RS512).unwrap();
+	assert!(decode_with_jwks(&other, &jwks, &[Algorithm::RS256, Algorithm::RS512]).is_err());
+
+	let good = encode(json!({"kid": "k1"}), &private, &json!({}), Algorithm::RS256).unwrap();
+	assert!(decode_with_jwks(&good, &jwks, &[Algorithm::RS256]).is_ok());
+}
+
+#[test]
+fn encoding_key_must_match_algorithm() {
+	let (private, _) = rsa_keys();
+
+	assert_eq!(encode(json!({}), &private, &json!({}), Algorithm::HS256), Err(Error::KeyMismatch));
+	assert_eq!(
+		encode(json!({}), &EncodingKey::from_secret(b"secret"), &json!({}), Algorithm::RS256),
+		Err(Error::KeyMismatch),
+	);
+}
@@ -0,0 +1,252 @@
+//! Tests for JWE encryption and nested JWS-in-JWE tokens.
+extern crate base64;
+extern crate openssl;
+extern crate rejwt;
+#[macro_use]
+extern crate serde_json;
+
+use openssl::bn::BigNum;
+use openssl::rsa::Rsa;
+use rejwt::jwe::{
+	decode_nested, decrypt, encode_nested, encrypt, ContentEncryption, DecryptingKey, EncryptingKey,
+};
+use rejwt::{encode, Algorithm, DecodingKey, EncodingKey, Error};
+
+const BOTH: [ContentEncryption; 2] = [ContentEncryption::A256GCM, ContentEncryption::A128CbcHs256];
+
+fn rsa_keys() -> (EncryptingKey, DecryptingKey) {
+	let rsa = Rsa::generate(2048).unwrap();
+	(
+		EncryptingKey::from_rsa_pem(&rsa.public_key_to_pem().unwrap()).unwrap(),
+		DecryptingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap(),
+	)
+}
+
+fn signing_keys() -> (EncodingKey, DecodingKey) {
+	let private = EncodingKey::from_secret(b"nested secret");
+	let public = private.decoding_key().unwrap();
+	(private, public)
+}
+
+fn b64(bytes: &[u8]) -> String {
+	base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(segment: &str) -> Vec<u8> {
+	base64::decode_config(segment, base64::URL_SAFE_NO_PAD).unwrap()
+}
+
+/// flips one bit in the given segment of a compact token
+fn tamper(token: &str, segment: usize) -> String {
+	let mut segments: Vec<String> = token.split('.').map(str::to_owned).collect();
+	let mut bytes = unb64(&segments[segment]);
+	bytes[0] ^= 1;
+	segments[segment] = b64(&bytes);
+	segments.join(".")
+}
+
+/// replaces the protected header of a token, keeping the rest intact
+fn with_header(token: &str, header: serde_json::Value) -> String {
+	let mut segments: Vec<String> = token.split('.').map(str::to_owned).collect();
+	segments[0] = b64(header.to_string().as_bytes());
+	segments.join(".")
+}
+
+#[test]
+fn round_trip() {
+	let (public, private) = rsa_keys();
+	for &enc in &BOTH {
+		let token = encrypt(json!({"kid": "k1"}), b"tajne", &public, enc).unwrap();
+		assert_eq!(token.split('.').count(), 5);
+
+		let (header, plaintext) = decrypt(&token, &private, &[enc]).unwrap();
+		assert_eq!(plaintext, b"tajne");
+		assert_eq!(header["alg"], "RSA-OAEP");
+		assert_eq!(header["enc"], enc.to_string());
+		assert_eq!(header["kid"], "k1");
+	}
+}
+
+#[test]
+fn tampered_segments_are_rejected() {
+	let (public, private) = rsa_keys();
+	for &enc in &BOTH {
+		let token = encrypt(json!({}), b"tajne", &public, enc).unwrap();
+		// encrypted key, iv, ciphertext and tag
+		for segment in 1..5 {
+			assert_eq!(
+				decrypt(&tamper(&token, segment), &private, &[enc]),
+				Err(Error::DecryptionFailed),
+				"{} segment {}",
+				enc,
+				segment,
+			);
+		}
+	}
+}
+
+#[test]
+fn tampered_protected_header_is_rejected() {
+	let (public, private) = rsa_keys();
+	for &enc in &BOTH {
+		let token = encrypt(json!({"kid": "k1"}), b"tajne", &public, enc).unwrap();
+		let forged = with_header(&token, json!({"alg": "RSA-OAEP", "enc": enc.to_string(), "kid": "k2"}));
+		assert_eq!(decrypt(&forged, &private, &[enc]), Err(Error::DecryptionFailed));
+	}
+}
+
+#[test]
+fn unknown_or_disallowed_algorithms_are_rejected() {
+	let (public, private) = rsa_keys();
+	let token = encrypt(json!({}), b"tajne", &public, ContentEncryption::A256GCM).unwrap();
+
+	let unknown_enc = with_header(&token, json!({"alg": "RSA-OAEP", "enc": "A192GCM"}));
+	assert!(matches!(decrypt(&unknown_enc, &private, &BOTH), Err(Error::AlgorithmInvalid(_))));
+
+	let missing_enc = with_header(&token, json!({"alg": "RSA-OAEP"}));
+	assert!(matches!(decrypt(&missing_enc, &private, &BOTH), Err(Error::AlgorithmInvalid(_))));
+
+	let other_alg = with_header(&token, json!({"alg": "RSA1_5", "enc": "A256GCM"}));
+	assert_eq!(
+		decrypt(&other_alg, &private, &BOTH),
+		Err(Error::AlgorithmNotAllowed("RSA1_5".to_owned()))
+	);
+
+	let missing_alg = with_header(&token, json!({"enc": "A256GCM"}));
+	assert!(matches!(decrypt(&missing_alg, &private, &BOTH), Err(Error::AlgorithmInvalid(_))));
+
+	assert_eq!(
+		decrypt(&token, &private, &[ContentEncryption::A128CbcHs256]),
+		Err(Error::AlgorithmNotAllowed("A256GCM".to_owned()))
+	);
+}
+
+#[test]
+fn wrong_key_is_rejected() {
+	let (public, _) = rsa_keys();
+	let (_, other) = rsa_keys();
+	for &enc in &BOTH {
+		let token = encrypt(json!({}), b"tajne", &public, enc).unwrap();
+		assert_eq!(decrypt(&token, &other, &[enc]), Err(Error::DecryptionFailed));
+	}
+}
+
+#[test]
+fn malformed_tokens_are_rejected() {
+	let (public, private) = rsa_keys();
+	let token = encrypt(json!({}), b"tajne", &public, ContentEncryption::A256GCM).unwrap();
+	let jws = token.splitn(4, '.').take(3).collect::<Vec<_>>().join(".");
+	assert_eq!(decrypt(&jws, &private, &BOTH), Err(Error::JWTInvalid));
+
+	let mut segments: Vec<&str> = token.split('.').collect();
+	segments[2] = "AAAA";
+	assert_eq!(decrypt(&segments.join("."), &private, &BOTH), Err(Error::DecryptionFailed));
+}
+
+#[test]
+fn nested_round_trip() {
+	let (public, private) = rsa_keys();
+	let (signing, verifying) = signing_keys();
+	for &enc in &BOTH {
+		let token = encode_nested(
+			json!({"kid": "k1"}),
+			&signing,
+			&json!({"id": 1}),
+			Algorithm::HS256,
+			&public,
+			enc,
+		)
+		.unwrap();
+
+		let (header, payload) = decode_nested(&token, &private, &[enc], &verifying, &[Algorithm::HS256]).unwrap();
+		assert_eq!(header["kid"], "k1");
+		assert_eq!(payload, json!({"id": 1}));
+	}
+}
+
+#[test]
+fn nested_token_needs_valid_signature() {
+	let (public, private) = rsa_keys();
+	let (signing, _) = signing_keys();
+	let other = DecodingKey::from_secret(b"other secret");
+	let enc = ContentEncryption::A256GCM;
+	let token = encode_nested(json!({}), &signing, &json!({"id": 1}), Algorithm::HS256, &public, enc).unwrap();
+
+	assert_eq!(
+		decode_nested(&token, &private, &[enc], &other, &[Algorithm::HS256]),
+		Err(Error::SignatureInvalid)
+	);
+	assert!(matches!(
+		decode_nested(&token, &private, &[enc], &signing.decoding_key().unwrap(), &[Algorithm::HS384]),
+		Err(Error::AlgorithmNotAllowed(_))
+	));
+}
+
+#[test]
+fn nested_token_needs_jwt_content_type() {
+	let (public, private) = rsa_keys();
+	let (signing, verifying) = signing_keys();
+	let enc = ContentEncryption::A256GCM;
+	let jws = encode(json!({}), &signing, &json!({"id": 1}), Algorithm::HS256).unwrap();
+
+	let token = encrypt(json!({}), jws.as_bytes(), &public, enc).unwrap();
+	assert!(matches!(
+		decode_nested(&token, &private, &[enc], &verifying, &[Algorithm::HS256]),
+		Err(Error::FormatInvalid(_))
+	));
+
+	let token = encrypt(json!({"cty": "jwt"}), jws.as_bytes(), &public, enc).unwrap();
+	assert!(decode_nested(&token, &private, &[enc], &verifying, &[Algorithm::HS256]).is_ok());
+}
+
+// RFC 7516 appendix A.1 (RSA-OAEP and A256GCM)
+const A1_N: &str = "oahUIoWw0K0usKNuOR6H4wkf4oBUXHTxRvgb48E-BVvxkeDNjbC4he8rUWcJoZmds2h7M70imEVhRU5djINXtqllXI4DFqcI1DgjT9LewND8MW2Krf3Spsk_ZkoFnilakGygTwpZ3uesH-PFABNIUYpOiN15dsQRkgr0vEhxN92i2asbOenSZeyaxziK72UwxrrKoExv6kc5twXTq4h-QChLOln0_mtUZwfsRaMStPs6mS6XrgxnxbWhojf663tuEQueGC-FCMfra36C9knDFGzKsNa7LZK2djYgyD3JR_MB_4NUJW_TqOQtwHYbxevoJArm-L5StowjzGy-_bq6Gw";
+const A1_E: &str = "AQAB";
+const A1_D: &str = "kLdtIj6GbDks_ApCSTYQtelcNttlKiOyPzMrXHeI-yk1F7-kpDxY4-WY5NWV5KntaEeXS1j82E375xxhWMHXyvjYecPT9fpwR_M9gV8n9Hrh2anTpTD93Dt62ypW3yDsJzBnTnrYu1iwWRgBKrEYY46qAZIrA2xAwnm2X7uGR1hghkqDp0Vqj3kbSCz1XyfCs6_LehBwtxHIyh8Ripy40p24moOAbgxVw3rxT_vlt3UVe4WO3JkJOzlpUf-KTVI2Ptgm-dARxTEtE-id-4OJr0h-K-VFs3VSndVTIznSxfyrj8ILL6MG_Uv8YAu7VILSB3lOW085-4qE3DzgrTjgyQ";
+const A1_P: &str = "1r52Xk46c-LsfB5P442p7atdPUrxQSy4mti_tZI3Mgf2EuFVbUoDBvaRQ-SWxkbkmoEzL7JXroSBjSrK3YIQgYdMgyAEPTPjXv_hI2_1eTSPVZfzL0lffNn03IXqWF5MDFuoUYE0hzb2vhrlN_rKrbfDIwUbTrjjgieRbwC6Cl0";
+const A1_Q: &str = "wLb35x7hmQWZsWJmB_vle87ihgZ19S8lBEROLIsZG4ayZVe9Hi9gDVCOBmUDdaDYVTSNx_8Fyw1YYa9XGrGnDew00J28cRUoeBB_jKI1oma0Orv1T9aXIWxKwd4gvxFImOWr3QRL9KEBRzk2RatUBnmDZJTIAfwTs0g68UZHvtc";
+const A1_DP: &str = "ZK-YwE7diUh0qR1tR7w8WHtolDx3MZ_OTowiFvgfeQ3SiresXjm9gZ5KLhMXvo-uz-KUJWDxS5pFQ_M0evdo1dKiRTjVw_x4NyqyXPM5nULPkcpU827rnpZzAJKpdhWAgqrXGKAECQH0Xt4taznjnd_zVpAmZZq60WPMBMfKcuE";
+const A1_DQ: &str = "Dq0gfgJ1DdFGXiLvQEZnuKEN0UUmsJBxkjydc3j4ZYdBiMRAy86x0vHCjywcMlYYg4yoC4YZa9hNVcsjqA3FeiL19rk8g6Qn29Tt0cj8qqyFpz9vNDBUfCAiJVeESOjJDZPYHdHY8v1b-o-Z2X5tvLx-TCekf7oxyeKDUqKWjis";
+const A1_QI: &str = "VIMpMYbPf47dT1w_zDUXfPimsSegnMOA1zTaX7aGk_8urY6R8-ZW1FxU7AlWAyLWybqq6t16VFd7hQd0y6flUK4SlOydB61gwanOsXGOAOv82cHq0E3eL4HrtZkUuKvnPrMnsUUFlfUdybVzxyjz9JF_XyaY14ardLSjf4L_FNY";
+const A1_TOKEN: &str = concat!(
+	"eyJhbGciOiJSU0EtT0FFUCIsImVuYyI6IkEyNTZHQ00ifQ.",
+	"OKOawDo13gRp2ojaHV7LFpZcgV7T6DVZKTyKOMTYUmKoTCVJRgckCL9kiMT03JGeipsEdY3mx_etLbbWSrFr05kLzcSr4qKAq7YN7e9jwQRb23nfa6c9d-StnImGyFDbSv04uVuxIp5Zms1gNxKKK2Da14B8S4rzVRltdYwam_lDp5XnZAYpQdb76FdIKLaVmqgfwX7XWRxv2322i-vDxRfqNzo_tETKzpVLzfiwQyeyPGLBIO56YJ7eObdv0je81860ppamavo35UgoRdbYaBcoh9QcfylQr66oc6vFWXRcZ_ZT2LawVCWTIy3brGPi6UklfCpIMfIjf7iGdXKHzg.",
+	"48V1_ALb6US04U3b.",
+	"5eym8TW_c8SuK0ltJ3rpYIzOeDQz7TALvtu6UG9oMo4vpzs9tX_EFShS8iB7j6jiSdiwkIr3ajwQzaBtQD_A.",
+	"XFBoMYUZodetZdvTiFvSkQ",
+);
+const A1_PLAINTEXT: &[u8] = b"The true sign of intelligence is not knowledge but imagination.";
+
+fn a1_key() -> DecryptingKey {
+	let bn = |segment: &str| BigNum::from_slice(&unb64(segment)).unwrap();
+	let rsa = Rsa::from_private_components(
+		bn(A1_N),
+		bn(A1_E),
+		bn(A1_D),
+		bn(A1_P),
+		bn(A1_Q),
+		bn(A1_DP),
+		bn(A1_DQ),
+		bn(A1_QI),
+	)
+	.unwrap();
+	DecryptingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap()
+}
+
+#[test]
+fn rfc7516_a1_vector_decrypts() {
+	let (header, plaintext) = decrypt(A1_TOKEN, &a1_key(), &[ContentEncryption::A256GCM]).unwrap();
+	assert_eq!(header, json!({"alg": "RSA-OAEP", "enc": "A256GCM"}));
+	assert_eq!(plaintext, A1_PLAINTEXT);
+}
+
+#[test]
+fn rfc7516_a1_vector_is_bound_to_its_tag() {
+	let key = a1_key();
+	for segment in 1..5 {
+		assert_eq!(
+			decrypt(&tamper(A1_TOKEN, segment), &key, &[ContentEncryption::A256GCM]),
+			Err(Error::DecryptionFailed)
+		);
+	}
+}
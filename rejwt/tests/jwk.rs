@@ -0,0 +1,221 @@
+//! Tests for JWK conversions, JWKS lookup and EC/EdDSA signatures.
+extern crate base64;
+extern crate openssl;
+extern crate rejwt;
+#[macro_use]
+extern crate serde_json;
+
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use rejwt::{
+	decode, decode_with_jwks, encode, Algorithm, DecodingKey, EncodingKey, Error, Jwk, JwkSet,
+	KeyParams,
+};
+
+fn rsa_key() -> PKey<Private> {
+	PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+}
+
+fn ec_key(nid: Nid) -> PKey<Private> {
+	let group = EcGroup::from_curve_name(nid).unwrap();
+	PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+fn ed_key() -> PKey<Private> {
+	PKey::generate_ed25519().unwrap()
+}
+
+fn public_pem(key: &PKey<Private>) -> Vec<u8> {
+	key.public_key_to_pem().unwrap()
+}
+
+/// signing key, verification key and JWK for the given algorithm
+fn keys(algorithm: Algorithm) -> (EncodingKey, DecodingKey, Jwk) {
+	let (key, private, public) = match algorithm {
+		Algorithm::ES256 | Algorithm::ES384 | Algorithm::ES512 => {
+			let nid = match algorithm {
+				Algorithm::ES256 => Nid::X9_62_PRIME256V1,
+				Algorithm::ES384 => Nid::SECP384R1,
+				_ => Nid::SECP521R1,
+			};
+			let key = ec_key(nid);
+			let private = EncodingKey::from_ec_pem(&key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+			let public = DecodingKey::from_ec_pem(&public_pem(&key)).unwrap();
+			(key, private, public)
+		}
+		Algorithm::EdDSA => {
+			let key = ed_key();
+			let private = EncodingKey::from_ed_pem(&key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+			let public = DecodingKey::from_ed_pem(&public_pem(&key)).unwrap();
+			(key, private, public)
+		}
+		_ => {
+			let key = rsa_key();
+			let private = EncodingKey::from_rsa_pem(&key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+			let public = DecodingKey::from_rsa_pem(&public_pem(&key)).unwrap();
+			(key, private, public)
+		}
+	};
+	let jwk = Jwk::from_pem(&public_pem(&key)).unwrap();
+	(private, public, jwk)
+}
+
+#[test]
+fn jwk_pem_round_trip() {
+	let cases = vec![
+		(rsa_key(), "RSA", Algorithm::RS256),
+		(ec_key(Nid::X9_62_PRIME256V1), "P-256", Algorithm::ES256),
+		(ec_key(Nid::SECP384R1), "P-384", Algorithm::ES384),
+		(ec_key(Nid::SECP521R1), "P-521", Algorithm::ES512),
+		(ed_key(), "Ed25519", Algorithm::EdDSA),
+	];
+
+	for (key, name, algorithm) in cases {
+		let pem = public_pem(&key);
+		let jwk = Jwk::from_pem(&pem).unwrap();
+		assert_eq!(jwk.algorithm().unwrap().to_string(), algorithm.to_string(), "{}", name);
+		assert_eq!(jwk.usage.as_deref(), Some("sig"));
+		assert_eq!(jwk.to_pem().unwrap(), pem, "{}", name);
+		assert!(jwk.to_pkey().unwrap().public_eq(&key), "{}", name);
+
+		let parsed: Jwk = serde_json::from_str(&serde_json::to_string(&jwk).unwrap()).unwrap();
+		assert_eq!(parsed, jwk, "{}", name);
+	}
+}
+
+#[test]
+fn ec_coordinates_are_padded() {
+	// P-521 coordinates are 66 bytes, leading zeros included
+	for _ in 0..8 {
+		let jwk = Jwk::from_pem(&public_pem(&ec_key(Nid::SECP521R1))).unwrap();
+		match jwk.params {
+			KeyParams::Ec { ref x, ref y, .. } => {
+				assert_eq!(base64::decode_config(x, base64::URL_SAFE_NO_PAD).unwrap().len(), 66);
+				assert_eq!(base64::decode_config(y, base64::URL_SAFE_NO_PAD).unwrap().len(), 66);
+			}
+			ref other => panic!("unexpected params {:?}", other),
+		}
+	}
+}
+
+#[test]
+fn jwk_json_matches_rfc7517() {
+	let jwk = Jwk::from_pem(&public_pem(&ed_key())).unwrap().kid("k1");
+	let value = serde_json::to_value(&jwk).unwrap();
+	assert_eq!(value["kty"], "OKP");
+	assert_eq!(value["crv"], "Ed25519");
+	assert_eq!(value["kid"], "k1");
+	assert_eq!(value["use"], "sig");
+	assert_eq!(value["alg"], "EdDSA");
+
+	let jwk: Jwk = serde_json::from_value(json!({"kty": "EC", "crv": "P-256", "x": "", "y": ""})).unwrap();
+	assert_eq!(jwk.kid, None);
+	assert_eq!(jwk.algorithm().unwrap().to_string(), "ES256");
+}
+
+#[test]
+fn invalid_jwks_are_refused() {
+	let secret = DecodingKey::from_secret(b"secret");
+	assert!(matches!(Jwk::from_decoding_key(&secret), Err(Error::KeyInvalid(_))));
+
+	let unknown_curve: Jwk = serde_json::from_value(json!({"kty": "OKP", "crv": "X25519", "x": ""})).unwrap();
+	assert!(matches!(unknown_curve.to_pkey(), Err(Error::KeyInvalid(_))));
+
+	// a point that is not on the curve
+	let mut jwk = Jwk::from_pem(&public_pem(&ec_key(Nid::X9_62_PRIME256V1))).unwrap();
+	if let KeyParams::Ec { ref mut y, .. } = jwk.params {
+		let mut bytes = base64::decode_config(&*y, base64::URL_SAFE_NO_PAD).unwrap();
+		bytes[31] ^= 1;
+		*y = base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD);
+	}
+	assert!(jwk.to_pkey().is_err());
+}
+
+#[test]
+fn jwks_lookup_by_kid() {
+	let (_, _, first) = keys(Algorithm::ES256);
+	let (_, _, second) = keys(Algorithm::EdDSA);
+	let set = JwkSet::new(vec![first.clone().kid("a"), second.clone().kid("b")]);
+
+	assert_eq!(set.find("a").unwrap().params, first.params);
+	assert_eq!(set.find("b").unwrap().params, second.params);
+	assert!(set.find("c").is_none());
+
+	let parsed: JwkSet = serde_json::to_string(&set).unwrap().parse().unwrap();
+	assert_eq!(parsed, set);
+	assert!("{\"keys\": 1}".parse::<JwkSet>().is_err());
+}
+
+#[test]
+fn decode_with_jwks_picks_key_by_kid() {
+	let (es_private, _, es_jwk) = keys(Algorithm::ES256);
+	let (ed_private, _, ed_jwk) = keys(Algorithm::EdDSA);
+	let set = JwkSet::new(vec![es_jwk.kid("es"), ed_jwk.kid("ed")]);
+	let allowed = [Algorithm::ES256, Algorithm::EdDSA];
+
+	let token = encode(json!({"kid": "es"}), &es_private, &json!({"id": 1}), Algorithm::ES256).unwrap();
+	let (_, payload) = decode_with_jwks(&token, &set, &allowed).unwrap();
+	assert_eq!(payload, json!({"id": 1}));
+
+	let token = encode(json!({"kid": "ed"}), &ed_private, &json!({"id": 2}), Algorithm::EdDSA).unwrap();
+	let (_, payload) = decode_with_jwks(&token, &set, &allowed).unwrap();
+	assert_eq!(payload, json!({"id": 2}));
+
+	// signed by the EdDSA key, but claims to be the other one
+	let token = encode(json!({"kid": "es"}), &ed_private, &json!({}), Algorithm::EdDSA).unwrap();
+	assert!(decode_with_jwks(&token, &set, &allowed).is_err());
+
+	let token = encode(json!({"kid": "nope"}), &es_private, &json!({}), Algorithm::ES256).unwrap();
+	assert_eq!(decode_with_jwks(&token, &set, &allowed), Err(Error::KeyNotFound("nope".to_owned())));
+
+	let token = encode(json!({}), &es_private, &json!({}), Algorithm::ES256).unwrap();
+	assert!(matches!(decode_with_jwks(&token, &set, &allowed), Err(Error::KeyNotFound(_))));
+}
+
+#[test]
+fn ec_and_eddsa_sign_and_verify() {
+	for &algorithm in &[Algorithm::ES256, Algorithm::ES384, Algorithm::ES512, Algorithm::EdDSA] {
+		let (private, public, jwk) = keys(algorithm);
+		let token = encode(json!({}), &private, &json!({"id": 1}), algorithm).unwrap();
+
+		let (header, payload) = decode(&token, &public, &[algorithm]).unwrap();
+		assert_eq!(header["alg"], algorithm.to_string());
+		assert_eq!(payload, json!({"id": 1}));
+
+		let from_jwk = DecodingKey::from_jwk(&jwk).unwrap();
+		assert!(decode(&token, &from_jwk, &[algorithm]).is_ok(), "{}", algorithm);
+	}
+}
+
+#[test]
+fn ecdsa_signatures_are_fixed_size() {
+	// JWS uses the raw r || s form (RFC 7518 3.4), not DER
+	for &(algorithm, size) in &[(Algorithm::ES256, 64), (Algorithm::ES384, 96), (Algorithm::ES512, 132)] {
+		let (private, _, _) = keys(algorithm);
+		let token = encode(json!({}), &private, &json!({}), algorithm).unwrap();
+		let signature = token.rsplit('.').next().unwrap();
+		assert_eq!(base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap().len(), size);
+	}
+}
+
+#[test]
+fn ec_and_eddsa_reject_wrong_key_or_signature() {
+	for &algorithm in &[Algorithm::ES256, Algorithm::ES384, Algorithm::EdDSA] {
+		let (private, public, _) = keys(algorithm);
+		let (_, other, _) = keys(algorithm);
+		let token = encode(json!({}), &private, &json!({"id": 1}), algorithm).unwrap();
+		assert_eq!(decode(&token, &other, &[algorithm]), Err(Error::SignatureInvalid), "{}", algorithm);
+
+		let (rest, signature) = token.split_at(token.rfind('.').unwrap() + 1);
+		let mut bytes = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+		bytes[0] ^= 1;
+		let tampered = format!("{}{}", rest, base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD));
+		assert_eq!(decode(&tampered, &public, &[algorithm]), Err(Error::SignatureInvalid), "{}", algorithm);
+
+		bytes.pop();
+		let truncated = format!("{}{}", rest, base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD));
+		assert!(decode(&truncated, &public, &[algorithm]).is_err(), "{}", algorithm);
+	}
+}
@@ -0,0 +1,591 @@
+//! Správa účtů
+//!
+//! Every person has one [`Account`] with their credentials, the records
+//! of their roles ([`Student`], [`Teacher`], ...) point back to it.
+//! Logging in resolves the account first and only then the role,
+//! so the client doesn't have to know who it is talking to.
+use crate::keys;
+use crate::totp;
+use crate::policy;
+use crate::onetime;
+use crate::config::CONFIG;
+use crate::auth::AuthToken;
+use crate::mail::{self, Mail};
+use crate::ratelimit::{self, ClientIp, TooManyRequests};
+use crate::db::{Database, NewEntry, NewEntryPartial, Table};
+use crate::models::{Account, Admin, Guardian, NewAccount, NewAdmin, Role, Student, Teacher, TokenPurpose, Totp};
+
+use uuid::Uuid;
+use serde_json::json;
+use rocket::Request;
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+use std::sync::RwLock;
+use std::collections::{BTreeMap, HashSet};
+
+lazy_static! {
+	/// disabled accounts, checked on every request
+	static ref DISABLED: RwLock<HashSet<Uuid>> = RwLock::new(
+		Database::<Account>::open()
+			.map(|db| db.read().iter().filter(|(_, x)| x.disabled).map(|(id, _)| id).collect())
+			.unwrap_or_default()
+	);
+}
+
+/// why logging in failed
+#[derive(Debug)]
+pub enum LoginError {
+	/// too many failed attempts
+	TooManyRequests(TooManyRequests),
+	/// wrong email or password, or the account doesn't have the requested role
+	InvalidCredentials,
+	/// the account has several roles and the client has to pick one
+	RoleRequired(Vec<Role>),
+	/// the password was right, now the TOTP code has to be sent with the challenge
+	TotpRequired(String),
+	/// the account has been disabled by an admin
+	Disabled,
+	/// the token couldn't be signed
+	Internal,
+}
+
+impl From<TooManyRequests> for LoginError {
+	fn from(e: TooManyRequests) -> Self {
+		LoginError::TooManyRequests(e)
+	}
+}
+
+impl<'r> Responder<'r> for LoginError {
+	fn respond_to(self, req: &Request) -> response::Result<'r> {
+		let (status, body) = match self {
+			LoginError::TooManyRequests(e) => return e.respond_to(req),
+			LoginError::InvalidCredentials => (
+				Status::Unauthorized,
+				json!({ "error": "invalid_credentials", "message": "wrong email or password" }),
+			),
+			LoginError::RoleRequired(roles) => (
+				Status::Conflict,
+				json!({ "error": "role_required", "message": "pick one of the roles", "roles": roles }),
+			),
+			LoginError::TotpRequired(challenge) => (
+				Status::Unauthorized,
+				json!({
+					"error": "totp_required",
+					"message": "send a code from the authenticator app to /login/totp",
+					"challenge": challenge,
+				}),
+			),
+			LoginError::Disabled => (
+				Status::Forbidden,
+				json!({ "error": "account_disabled", "message": "the account has been disabled" }),
+			),
+			LoginError::Internal => (
+				Status::InternalServerError,
+				json!({ "error": "internal", "message": "couldn't issue a token" }),
+			),
+		};
+
+		Response::build_from(Json(body).respond_to(req)?)
+			.status(status)
+			.ok()
+	}
+}
+
+/// what admins get to see about an account
+#[derive(Clone, Debug, Serialize)]
+pub struct AccountView {
+	pub id: Uuid,
+	pub email: String,
+	pub roles: BTreeMap<Role, Uuid>,
+	pub created: DateTime<Utc>,
+	pub verified: bool,
+	pub disabled: bool,
+	pub totp: bool,
+}
+
+impl From<Account> for AccountView {
+	fn from(x: Account) -> Self {
+		AccountView {
+			id: x.id,
+			email: x.email,
+			roles: x.roles,
+			created: x.created,
+			verified: x.verified,
+			disabled: x.disabled,
+			totp: x.totp.is_some_and(|t| t.confirmed),
+		}
+	}
+}
+
+/// emails are compared case-insensitively
+pub fn normalize_email(email: &str) -> String {
+	email.trim().to_lowercase()
+}
+
+/// finds the account with the given email
+pub fn find_by_email(db: &Database<Account>, email: &str) -> Option<Account> {
+	let email = normalize_email(email);
+
+	db.read()
+		.iter()
+		.map(|(_, x)| x)
+		.find(|x| x.email == email)
+}
+
+/// checks the credentials and issues a token for one of the account's roles
+pub fn login(email: &str, pass: &str, role: Option<Role>, ip: ClientIp) -> Result<String, LoginError> {
+	ratelimit::check(email, ip)?;
+
+	let account = Database::<Account>::open()
+		.and_then(|db| find_by_email(&db, email))
+		.filter(|x| totp::constant_time_eq(&x.pass, pass));
+	let account = match account {
+		Some(account) => account,
+		None => {
+			ratelimit::failure(email, ip);
+			return Err(LoginError::InvalidCredentials);
+		}
+	};
+	ratelimit::success(email);
+
+	if account.disabled {
+		return Err(LoginError::Disabled);
+	}
+
+	let (role, id) = resolve_role(&account, role)?;
+	if account.totp.as_ref().is_some_and(|t| t.confirmed) {
+		let challenge = onetime::issue(account.id, TokenPurpose::LoginChallenge).ok_or(LoginError::Internal)?;
+		return Err(LoginError::TotpRequired(challenge));
+	}
+
+	issue_token(account.id, id, role, false)
+}
+
+/// second step of logging in, `code` is either a TOTP code or a recovery code;
+/// a challenge can only be tried once, a wrong code means starting over
+pub fn login_totp(challenge: &str, code: &str, role: Option<Role>, ip: ClientIp) -> Result<String, LoginError> {
+	let id = onetime::redeem(challenge, TokenPurpose::LoginChallenge).ok_or(LoginError::InvalidCredentials)?;
+	let mut db = Database::<Account>::open().ok_or(LoginError::Internal)?;
+	let mut account = db.read().get(id).ok_or(LoginError::InvalidCredentials)?;
+	ratelimit::check(&account.email, ip)?;
+
+	if account.disabled {
+		return Err(LoginError::Disabled);
+	}
+	if !check_second_factor(&mut account, code) {
+		ratelimit::failure(&account.email, ip);
+		return Err(LoginError::InvalidCredentials);
+	}
+	db.write().insert(account.id, &account).map_err(|_| LoginError::Internal)?;
+
+	let (role, id) = resolve_role(&account, role)?;
+	issue_token(account.id, id, role, true)
+}
+
+/// picks the role to log in as, one has to be given only if there are several
+fn resolve_role(account: &Account, role: Option<Role>) -> Result<(Role, Uuid), LoginError> {
+	match role {
+		Some(role) => Ok((role, *account.roles.get(&role).ok_or(LoginError::InvalidCredentials)?)),
+		None if account.roles.len() > 1 => Err(LoginError::RoleRequired(account.roles.keys().cloned().collect())),
+		None => account.roles
+			.iter()
+			.map(|(r, id)| (*r, *id))
+			.next()
+			.ok_or(LoginError::InvalidCredentials),
+	}
+}
+
+/// checks a TOTP or recovery code of a confirmed TOTP,
+/// marks it as used so that it can't be used again
+fn check_second_factor(account: &mut Account, code: &str) -> bool {
+	let totp = match account.totp {
+		Some(ref mut totp) if totp.confirmed => totp,
+		_ => return false,
+	};
+
+	if let Some(step) = totp::verify(&totp.secret, code, Utc::now().timestamp(), totp.last_step) {
+		totp.last_step = step;
+		return true;
+	}
+
+	let hash = totp::hash_recovery_code(code);
+	let before = totp.recovery_codes.len();
+	totp.recovery_codes.retain(|x| *x != hash);
+	totp.recovery_codes.len() != before
+}
+
+/// signs a token for the record `id` of the given role
+pub fn issue_token(account: Uuid, id: Uuid, role: Role, mfa: bool) -> Result<String, LoginError> {
+	let (kid, key, alg) = keys::signing_key(id, &role.to_string()).ok_or(LoginError::Internal)?;
+	let header = json!({ "kid": kid });
+	let body = AuthToken::new(id, role.to_string()).account(account).mfa(mfa).make();
+
+	rejwt::encode(header, &key, &body, alg).map_err(|_| LoginError::Internal)
+}
+
+/// starts setting up TOTP with a new secret, returns the secret in base32
+/// and an `otpauth://` URI for a QR code; a confirmed TOTP is left alone
+pub fn enroll_totp(account: Uuid) -> Result<(String, String), Status> {
+	let mut db = Database::<Account>::open().ok_or(Status::InternalServerError)?;
+	let mut account = db.read().get(account).ok_or(Status::NotFound)?;
+
+	if account.totp.as_ref().is_some_and(|t| t.confirmed) {
+		return Err(Status::Conflict);
+	}
+
+	let secret = totp::generate_secret();
+	let result = (totp::base32(&secret), totp::otpauth_uri(&secret, &account.email));
+	account.totp = Some(Totp { secret, confirmed: false, last_step: 0, recovery_codes: vec![] });
+	db.write().insert(account.id, &account).map_err(|_| Status::InternalServerError)?;
+
+	Ok(result)
+}
+
+/// confirms the TOTP secret with a code from the app,
+/// returns recovery codes, which are never shown again
+pub fn confirm_totp(account: Uuid, code: &str) -> Result<Vec<String>, Status> {
+	let mut db = Database::<Account>::open().ok_or(Status::InternalServerError)?;
+	let mut account = db.read().get(account).ok_or(Status::NotFound)?;
+
+	let totp = match account.totp {
+		Some(ref mut totp) if !totp.confirmed => totp,
+		Some(_) => return Err(Status::Conflict),
+		None => return Err(Status::NotFound),
+	};
+	totp.last_step = totp::verify(&totp.secret, code, Utc::now().timestamp(), 0).ok_or(Status::BadRequest)?;
+	totp.confirmed = true;
+
+	let codes = totp::generate_recovery_codes();
+	totp.recovery_codes = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+	db.write().insert(account.id, &account).map_err(|_| Status::InternalServerError)?;
+
+	Ok(codes)
+}
+
+/// turns TOTP off, needs a valid code; teachers can't if it's mandatory
+pub fn disable_totp(account: Uuid, code: &str) -> Result<(), Status> {
+	let mut db = Database::<Account>::open().ok_or(Status::InternalServerError)?;
+	let mut account = db.read().get(account).ok_or(Status::NotFound)?;
+
+	if policy::current().require_teacher_2fa && account.roles.contains_key(&Role::Teacher) {
+		return Err(Status::Forbidden);
+	}
+	if !check_second_factor(&mut account, code) {
+		return Err(Status::BadRequest);
+	}
+
+	account.totp = None;
+	db.write().insert(account.id, &account).map_err(|_| Status::InternalServerError)?;
+	Ok(())
+}
+
+/// creates the account and the role's record, `create_record` gets the account id
+/// and returns the id of the new record; an existing account only gets another role
+/// when `owner` is its id, i.e. the request was made with one of its own tokens,
+/// its password is left as it is then
+pub fn register<F>(email: &str, pass: &str, role: Role, owner: Option<Uuid>, create_record: F) -> Result<Uuid, Status>
+where
+	F: FnOnce(Uuid) -> Option<Uuid>,
+{
+	let mut db = Database::<Account>::open().ok_or(Status::InternalServerError)?;
+
+	// the same answer for every taken email, registration must not tell
+	// anything about the password of an existing account
+	let mut account = match find_by_email(&db, email) {
+		Some(x) if Some(x.id) != owner || x.roles.contains_key(&role) => return Err(Status::Conflict),
+		Some(x) => x,
+		None => Account::create(NewAccount {
+			email: normalize_email(email),
+			pass: pass.to_string(),
+		}).1,
+	};
+
+	let record = create_record(account.id).ok_or(Status::InternalServerError)?;
+	account.roles.insert(role, record);
+	db.write()
+		.insert(account.id, &account)
+		.map_err(|_| Status::InternalServerError)?;
+
+	if !account.verified {
+		send_verification(&account);
+	}
+
+	Ok(account.id)
+}
+
+/// mails the account a link for verifying its email
+pub fn send_verification(account: &Account) -> Option<()> {
+	let token = onetime::issue(account.id, TokenPurpose::EmailVerification)?;
+
+	mail::send_later(Mail {
+		to: account.email.clone(),
+		subject: "Verify your email".to_string(),
+		body: format!(
+			"Open the following link to verify your email:\n\n{}/verify_email?token={}\n",
+			CONFIG.public_url, token,
+		),
+	});
+	Some(())
+}
+
+/// marks the email of the token's account as verified
+pub fn verify_email(token: &str) -> Option<()> {
+	let account = onetime::redeem(token, TokenPurpose::EmailVerification)?;
+
+	Database::<Account>::open()?
+		.write()
+		.update::<_, Account, _>(account, |c| c.map(|mut x| {
+			x.verified = true;
+			x
+		}))
+		.ok()?
+		.map(|_| ())
+}
+
+/// mails a reset link if there is an account with the email,
+/// says nothing about whether there is one
+pub fn request_password_reset(email: &str) {
+	let account = match Database::<Account>::open().and_then(|db| find_by_email(&db, email)) {
+		Some(account) => account,
+		None => return,
+	};
+	let token = match onetime::issue(account.id, TokenPurpose::PasswordReset) {
+		Some(token) => token,
+		None => return,
+	};
+
+	mail::send_later(Mail {
+		to: account.email,
+		subject: "Password reset".to_string(),
+		body: format!(
+			"Somebody asked to reset your password. If it was you, open the following link:\n\n\
+			{}/reset_password?token={}\n\nThe link expires in {} minutes, otherwise ignore this email.\n",
+			CONFIG.public_url, token, CONFIG.reset_token_lifetime / 60,
+		),
+	});
+}
+
+/// sets a new password, the token proves the owner has access to the email,
+/// so the email is verified as well and a login lockout is lifted
+pub fn reset_password(token: &str, pass: &str) -> Option<()> {
+	let account = onetime::redeem(token, TokenPurpose::PasswordReset)?;
+
+	let updated = Database::<Account>::open()?
+		.write()
+		.update::<_, Account, _>(account, |c| c.map(|mut x| {
+			x.pass = pass.to_string();
+			x.verified = true;
+			x
+		}))
+		.ok()??;
+	let account = serde_cbor::from_slice::<Account>(&updated).ok()?;
+
+	ratelimit::unlock(Some(&account.email), None)
+}
+
+/// whether tokens of the account must be refused
+pub fn is_disabled(account: Uuid) -> bool {
+	DISABLED.read().expect("the disabled accounts rwlock has been poisoned").contains(&account)
+}
+
+/// disables or enables an account, takes effect on the next request
+pub fn set_disabled(account: Uuid, disabled: bool) -> Result<(), Status> {
+	Database::<Account>::open()
+		.ok_or(Status::InternalServerError)?
+		.write()
+		.update::<_, Account, _>(account, |c| c.map(|mut x| {
+			x.disabled = disabled;
+			x
+		}))
+		.map_err(|_| Status::InternalServerError)?
+		.ok_or(Status::NotFound)?;
+
+	let mut set = DISABLED.write().expect("the disabled accounts rwlock has been poisoned");
+	if disabled {
+		set.insert(account);
+	} else {
+		set.remove(&account);
+	}
+	Ok(())
+}
+
+/// helps somebody who is locked out: lifts the login lockout and optionally
+/// removes their TOTP and/or mails them a password reset link
+pub fn admin_reset(account: Uuid, password: bool, totp: bool) -> Result<(), Status> {
+	let mut db = Database::<Account>::open().ok_or(Status::InternalServerError)?;
+	let mut account = db.read().get(account).ok_or(Status::NotFound)?;
+
+	if totp {
+		account.totp = None;
+		db.write().insert(account.id, &account).map_err(|_| Status::InternalServerError)?;
+	}
+	if password {
+		request_password_reset(&account.email);
+	}
+
+	ratelimit::unlock(Some(&account.email), None).ok_or(Status::InternalServerError)
+}
+
+/// moves the record of a role to another account,
+/// e.g. when somebody registered twice
+pub fn assign_role(account: Uuid, role: Role, record: Uuid) -> Result<(), Status> {
+	let mut db = Database::<Account>::open().ok_or(Status::InternalServerError)?;
+	let mut target = db.read().get(account).ok_or(Status::NotFound)?;
+
+	match target.roles.get(&role) {
+		Some(x) if *x == record => return Ok(()),
+		Some(_) => return Err(Status::Conflict),
+		None => (),
+	}
+
+	let previous = set_record_account(role, record, account).ok_or(Status::NotFound)?;
+	if let Some(mut previous) = db.read().get(previous).filter(|_| previous != account) {
+		previous.roles.retain(|r, id| !(*r == role && *id == record));
+		db.write().insert(previous.id, &previous).map_err(|_| Status::InternalServerError)?;
+	}
+
+	target.roles.insert(role, record);
+	db.write().insert(target.id, &target).map_err(|_| Status::InternalServerError)?;
+	Ok(())
+}
+
+/// points the record to another account, returns the account it pointed to
+fn set_record_account(role: Role, record: Uuid, account: Uuid) -> Option<Uuid> {
+	fn swap<T, F>(record: Uuid, account: Uuid, field: F) -> Option<Uuid>
+	where
+		T: Table<Key = Uuid, Value = T> + Serialize + for<'a> Deserialize<'a>,
+		F: Fn(&mut T) -> &mut Uuid,
+	{
+		let mut db = Database::<T>::open()?;
+		let mut value = db.read().get(record)?;
+		let previous = std::mem::replace(field(&mut value), account);
+		db.write().insert(record, &value).ok()?;
+		Some(previous)
+	}
+
+	match role {
+		Role::Student => swap::<Student, _>(record, account, |x| &mut x.account),
+		Role::Teacher => swap::<Teacher, _>(record, account, |x| &mut x.account),
+		Role::Admin => swap::<Admin, _>(record, account, |x| &mut x.account),
+		Role::Guardian => swap::<Guardian, _>(record, account, |x| &mut x.account),
+	}
+}
+
+/// creates an admin with a new account,
+/// existing accounts are made admins with [`assign_role`]
+pub fn create_admin(input: NewAdmin) -> Result<Uuid, Status> {
+	register(&input.email, &input.pass, Role::Admin, None, |account| {
+		let (id, admin) = Admin::create(input.clone());
+		(id, admin)
+			.and_modify(|x| x.account = account)
+			.save()
+			.ok()
+			.map(|_| id)
+	})
+}
+
+/// creates the first admin from the configuration if there is no admin yet
+pub fn bootstrap_admin() {
+	let (email, pass) = match (&CONFIG.admin_email, &CONFIG.admin_pass) {
+		(Some(email), Some(pass)) => (email.clone(), pass.clone()),
+		_ => return,
+	};
+	if Database::<Admin>::open().is_none_or(|db| db.read().iter().next().is_some()) {
+		return;
+	}
+
+	match create_admin(NewAdmin { name: "Administrator".to_string(), email, pass }) {
+		Ok(_) => eprintln!("created the first admin"),
+		Err(e) => eprintln!("failed to create the first admin: {}", e),
+	}
+}
+
+/// moves credentials of students and teachers created before accounts
+/// existed into accounts, people with the same email share one account
+pub fn migrate() {
+	let (mut accounts, mut students, mut teachers) = match (
+		Database::<Account>::open(),
+		Database::<Student>::open(),
+		Database::<Teacher>::open(),
+	) {
+		(Some(a), Some(s), Some(t)) => (a, s, t),
+		_ => return,
+	};
+
+	let legacy = students
+		.read()
+		.iter()
+		.filter(|(_, x)| x.account.is_nil())
+		.map(|(id, x)| (id, x.email, x.pass))
+		.collect::<Vec<_>>();
+	for (id, email, pass) in legacy {
+		if let Some(account) = attach(&mut accounts, &email, pass, Role::Student, id) {
+			let _ = students.write().update::<_, Student, _>(id, |c| c.map(|mut x| {
+				x.account = account;
+				x.pass = None;
+				x
+			}));
+		}
+	}
+
+	let legacy = teachers
+		.read()
+		.iter()
+		.filter(|(_, x)| x.account.is_nil())
+		.map(|(id, x)| (id, x.email, x.pass))
+		.collect::<Vec<_>>();
+	for (id, email, pass) in legacy {
+		if let Some(account) = attach(&mut accounts, &email, pass, Role::Teacher, id) {
+			let _ = teachers.write().update::<_, Teacher, _>(id, |c| c.map(|mut x| {
+				x.account = account;
+				x.pass = None;
+				x
+			}));
+		}
+	}
+}
+
+fn attach(db: &mut Database<Account>, email: &str, pass: Option<String>, role: Role, record: Uuid) -> Option<Uuid> {
+	let mut account = find_by_email(db, email).unwrap_or_else(|| Account::create(NewAccount {
+		email: normalize_email(email),
+		pass: pass.unwrap_or_default(),
+	}).1);
+
+	account.roles.insert(role, record);
+	db.write().insert(account.id, &account).ok()?;
+	Some(account.id)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	fn record(_: Uuid) -> Option<Uuid> {
+		Some(Uuid::new_v4())
+	}
+
+	#[test]
+	fn registration_does_not_touch_existing_accounts() {
+		testing::setup();
+		let email = format!("{}@example.com", Uuid::new_v4());
+		let id = register(&email, "heslo", Role::Student, None, record).unwrap();
+
+		// the right and a wrong password look the same and neither adds a role
+		assert_eq!(register(&email, "heslo", Role::Guardian, None, record), Err(Status::Conflict));
+		assert_eq!(register(&email, "spatne", Role::Guardian, None, record), Err(Status::Conflict));
+		assert_eq!(register(&email, "heslo", Role::Guardian, Some(Uuid::new_v4()), record), Err(Status::Conflict));
+
+		// only the account itself can add a role, once
+		assert_eq!(register(&email, "jine", Role::Guardian, Some(id), record), Ok(id));
+		assert_eq!(register(&email, "jine", Role::Guardian, Some(id), record), Err(Status::Conflict));
+
+		let account = find_by_email(&Database::<Account>::open().unwrap(), &email).unwrap();
+		assert_eq!(account.roles.len(), 2);
+		assert_eq!(account.pass, "heslo");
+	}
+}
@@ -1,9 +1,8 @@
 //! Modul obsahující věci týkající se autentifikace
-use crate::db::Database;
-use crate::models::{Teacher, Student};
-
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use rocket_contrib::json::Json;
 
 use rocket::Outcome;
 use rocket::Request;
@@ -12,6 +11,12 @@ use rocket::request::FromRequest;
 
 use chrono::Utc;
 
+use crate::policy;
+use crate::accounts;
+
+/// Doba platnosti tokenu v sekundách
+pub const TOKEN_LIFETIME: i64 = 69 * 60;
+
 /// JWT pro autorizaci atd.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AuthToken {
@@ -23,21 +28,45 @@ pub struct AuthToken {
 	pub id: Uuid,
 	/// typ uživatele
 	pub typ: String,
+	/// účet, ke kterému role patří
+	#[serde(default)]
+	pub account: Uuid,
+	/// jestli bylo při přihlášení použito druhé ověření
+	#[serde(default)]
+	pub mfa: bool,
 }
 
 impl AuthToken {
 	/// Vytvoří nový authtoken z usera
 	pub fn new(id: Uuid, typ: String) -> AuthToken {
-		let now = Utc::now().timestamp() + (69 * 60);
+		let now = Utc::now().timestamp() + TOKEN_LIFETIME;
 
 		AuthToken {
 			iss:       "Znamky".to_string(),
 			exp:       now,
 			id:        id,
 			typ:       typ,
+			account:   Uuid::nil(),
+			mfa:       false,
 		}
 	}
 
+	/// Označí token jako vydaný po dvoufázovém ověření
+	pub fn mfa(mut self, mfa: bool) -> AuthToken {
+		self.mfa = mfa;
+		self
+	}
+
+	/// jestli musí uživatel ještě nastavit druhé ověření, než smí cokoliv dělat
+	pub fn needs_mfa(&self) -> bool {
+		self.typ == "teacher" && !self.mfa && policy::current().require_teacher_2fa
+	}
+
+	/// Nastaví účet, ke kterému token patří
+	pub fn account(mut self, account: Uuid) -> AuthToken {
+		self.account = account;
+		self
+	}
 
 	/// Nastaví timestamp na vypršení tokenu
 	pub fn exp(mut self, exp: i64) -> AuthToken {
@@ -51,95 +80,365 @@ impl AuthToken {
 	}
 }
 
+/// Všechny důvody, proč může autentifikace selhat
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthError {
+	/// chybí hlavička `Authorization`
+	MissingHeader,
+	/// hlavička není ve tvaru `Bearer <token>`
+	InvalidScheme,
+	/// token nejde rozebrat na hlavičku, obsah a podpis
+	MalformedToken,
+	/// v hlavičce tokenu chybí `kid`
+	MissingKid,
+	/// `kid` nepatří žádnému platnému klíči
+	UnknownKey,
+	/// podpis nesedí nebo je použit nepovolený algoritmus
+	InvalidSignature,
+	/// obsah tokenu neodpovídá [`AuthToken`]
+	InvalidClaims,
+	/// token vypršel
+	Expired,
+	/// token je podepsaný klíčem jiného uživatele
+	KeyOwnerMismatch,
+	/// účet musí mít nastavené dvoufázové ověření
+	MfaRequired,
+	/// účet byl zablokován
+	AccountDisabled,
+	/// na tohle nemá uživatel právo
+	InsufficientRole,
+}
+
+impl AuthError {
+	/// HTTP status odpovídající chybě
+	pub fn status(&self) -> Status {
+		match self {
+			AuthError::InvalidScheme
+			| AuthError::MalformedToken
+			| AuthError::MissingKid
+			| AuthError::InvalidClaims => Status::BadRequest,
+			AuthError::MissingHeader
+			| AuthError::UnknownKey
+			| AuthError::InvalidSignature
+			| AuthError::Expired => Status::Unauthorized,
+			AuthError::KeyOwnerMismatch
+			| AuthError::MfaRequired
+			| AuthError::AccountDisabled
+			| AuthError::InsufficientRole => Status::Forbidden,
+		}
+	}
+
+	/// strojově čitelný kód chyby
+	pub fn code(&self) -> &'static str {
+		match self {
+			AuthError::MissingHeader => "missing_authorization",
+			AuthError::InvalidScheme => "invalid_authorization_scheme",
+			AuthError::MalformedToken => "malformed_token",
+			AuthError::MissingKid => "missing_kid",
+			AuthError::UnknownKey => "unknown_key",
+			AuthError::InvalidSignature => "invalid_signature",
+			AuthError::InvalidClaims => "invalid_claims",
+			AuthError::Expired => "token_expired",
+			AuthError::KeyOwnerMismatch => "key_owner_mismatch",
+			AuthError::MfaRequired => "mfa_required",
+			AuthError::AccountDisabled => "account_disabled",
+			AuthError::InsufficientRole => "insufficient_role",
+		}
+	}
+
+	/// popis chyby pro lidi
+	pub fn message(&self) -> &'static str {
+		match self {
+			AuthError::MissingHeader => "the Authorization header is missing",
+			AuthError::InvalidScheme => "the Authorization header must be `Bearer <token>`",
+			AuthError::MalformedToken => "the token is not a valid JWT",
+			AuthError::MissingKid => "the token header has no kid",
+			AuthError::UnknownKey => "the token was signed by an unknown key",
+			AuthError::InvalidSignature => "couldn't verify the token",
+			AuthError::InvalidClaims => "the token contains invalid data",
+			AuthError::Expired => "the token has expired",
+			AuthError::KeyOwnerMismatch => "the token was signed by another user's key",
+			AuthError::MfaRequired => "two-factor authentication has to be set up first",
+			AuthError::AccountDisabled => "the account has been disabled",
+			AuthError::InsufficientRole => "this is not allowed for your role",
+		}
+	}
+
+	/// JSON tělo chybové odpovědi
+	pub fn to_json(&self) -> Value {
+		json!({ "error": self.code(), "message": self.message() })
+	}
+}
+
+/// Ověří obsah hlavičky `Authorization` a vrátí token v ní
+pub fn verify(authorization: Option<&str>) -> Result<AuthToken, AuthError> {
+	let authorization = authorization.ok_or(AuthError::MissingHeader)?;
+
+	let mut parts = authorization.trim().splitn(2, ' ');
+	let token = match (parts.next(), parts.next()) {
+		(Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("Bearer") => token.trim().replace('"', ""),
+		_ => return Err(AuthError::InvalidScheme),
+	};
+
+	let (header, ..) = rejwt::decode_segments(&token).map_err(|_| AuthError::MalformedToken)?;
+	let kid = header
+		.get("kid")
+		.and_then(|k| k.as_str())
+		.ok_or(AuthError::MissingKid)?;
+	let key = crate::keys::lookup(kid).ok_or(AuthError::UnknownKey)?;
+
+	let (_, payload) = rejwt::decode(&token, &key.key, &[key.algorithm])
+		.map_err(|_| AuthError::InvalidSignature)?;
+	let tok = serde_json::from_value::<AuthToken>(payload).map_err(|_| AuthError::InvalidClaims)?;
+
+	if Utc::now().timestamp() > tok.exp {
+		return Err(AuthError::Expired);
+	}
+
+	if key.owner.is_some_and(|o| o != tok.id) || key.typ.is_some_and(|t| t != tok.typ) {
+		return Err(AuthError::KeyOwnerMismatch);
+	}
+
+	if accounts::is_disabled(tok.account) {
+		return Err(AuthError::AccountDisabled);
+	}
+
+	Ok(tok)
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for AuthToken {
-	type Error = String;
+	type Error = AuthError;
 
 	fn from_request(
 		request: &'a Request<'r>,
 	) -> rocket::request::Outcome<Self, Self::Error> {
-		let keys: Vec<_> = request.headers().get("Authorization").collect();
-		println!("lel");
-		match keys.get(0).unwrap_or(&"").split(' ').nth(1) {
-			Some(ref token) => {
-				println!("{}", token);
-				let header = rejwt::decode_segments(&token.replace('"', "")).unwrap().0;
-				println!("{}", header);
-
-				if let Some(key_id) = header.get("kid") {
-					let user_id = Uuid::parse_str(key_id.as_str().unwrap()).ok().unwrap();
-					let teachers = Database::<Teacher>::open().unwrap();
-					let students = Database::<Student>::open().unwrap();
-					
-					let key = match teachers.read().get(&user_id) {
-						Some(u) => u.pub_key.clone(),
-						None => match students.read().get(&user_id) {
-							Some(u) => u.pub_key.clone(),
-							None => unreachable!(),
-						}
-					};
-
-					match rejwt::decode(&token.replace('"', ""), &key, rejwt::Algorithm::RS256) {
-						Ok(t) => match serde_json::from_value::<AuthToken>(t.1) {
-							Ok(tok) => {
-								let now = Utc::now().timestamp();
-
-								println!("lel 2");
-								if now > tok.exp {
-									Outcome::Failure((
-										Status::ImATeapot,
-										"token has expired".to_string(),
-									))
-								} else if global_auth(&tok).is_some() {
-									Outcome::Success(tok)
-								} else {
-									Outcome::Failure((
-										Status::Forbidden,
-										"invalid token"
-											.to_string(),
-									))
-								}
-							}
-							Err(_) => Outcome::Failure((
-								Status::UnprocessableEntity,
-								"token contains invalid data".to_string(),
-							)),
-						},
-						Err(e) => {
-							println!("{}", e);
-							Outcome::Failure((
-								Status::Unauthorized,
-								"couldn't verify token".to_string(),
-							))
-						},
-					}
-				} else {
-					Outcome::Failure((
-						Status::BadRequest,
-						"invalid JWT header - missing kid".to_string(),
-					))
-				}
-			}
-			x => {
-				println!("{:?}", x);
-				Outcome::Failure((
-					Status::BadRequest,
-					"invalid authorization header".to_string(),
-				))
-			}
-		}
+		fail_with(request, authenticate(request))
 	}
 }
 
+/// Token administrátora
+pub struct AdminAuth(pub AuthToken);
 
-/// Autentifikace
-pub fn global_auth(info: &AuthToken) -> Option<(Uuid, String)> {
-	let teachers  = Database::<Teacher>::open()?;
-	let students  = Database::<Student>::open()?;
+impl<'a, 'r> FromRequest<'a, 'r> for AdminAuth {
+	type Error = AuthError;
 
-	if let Some(t) = teachers.read().get(&info.id) {
-		Some((t.id, "teacher".into()))
-	} else {
-		Some((students.read().get(&info.id)?.id, "student".into()))
+	fn from_request(
+		request: &'a Request<'r>,
+	) -> rocket::request::Outcome<Self, Self::Error> {
+		let admin = authenticate(request)
+			.and_then(|tok| if tok.typ == "admin" { Ok(AdminAuth(tok)) } else { Err(AuthError::InsufficientRole) });
+
+		fail_with(request, admin)
 	}
 }
 
+/// Token rodiče nebo zákonného zástupce
+pub struct GuardianAuth(pub AuthToken);
+
+impl<'a, 'r> FromRequest<'a, 'r> for GuardianAuth {
+	type Error = AuthError;
+
+	fn from_request(
+		request: &'a Request<'r>,
+	) -> rocket::request::Outcome<Self, Self::Error> {
+		let guardian = authenticate(request)
+			.and_then(|tok| if tok.typ == "guardian" { Ok(GuardianAuth(tok)) } else { Err(AuthError::InsufficientRole) });
+
+		fail_with(request, guardian)
+	}
+}
+
+/// Token kohokoli, kdo smí něco měnit, zákonní zástupci mohou jen číst
+pub struct WriteAuth(pub AuthToken);
+
+impl<'a, 'r> FromRequest<'a, 'r> for WriteAuth {
+	type Error = AuthError;
+
+	fn from_request(
+		request: &'a Request<'r>,
+	) -> rocket::request::Outcome<Self, Self::Error> {
+		let writer = authenticate(request)
+			.and_then(|tok| if tok.typ != "guardian" { Ok(WriteAuth(tok)) } else { Err(AuthError::InsufficientRole) });
+
+		fail_with(request, writer)
+	}
+}
+
+fn authenticate(request: &Request) -> Result<AuthToken, AuthError> {
+	verify(request.headers().get_one("Authorization"))
+		.and_then(|tok| if tok.needs_mfa() { Err(AuthError::MfaRequired) } else { Ok(tok) })
+}
+
+/// Token, který ještě nemusí mít druhé ověření, jen pro jeho nastavení
+pub struct MfaSetup(pub AuthToken);
+
+impl<'a, 'r> FromRequest<'a, 'r> for MfaSetup {
+	type Error = AuthError;
+
+	fn from_request(
+		request: &'a Request<'r>,
+	) -> rocket::request::Outcome<Self, Self::Error> {
+		fail_with(request, verify(request.headers().get_one("Authorization")).map(MfaSetup))
+	}
+}
+
+fn fail_with<T>(request: &Request, result: Result<T, AuthError>) -> rocket::request::Outcome<T, AuthError> {
+	match result {
+		Ok(x) => Outcome::Success(x),
+		Err(e) => {
+			// catchers can't see the guard's error, leave it for them here
+			request.local_cache(|| Some(e.clone()));
+			Outcome::Failure((e.status(), e))
+		}
+	}
+}
+
+fn error_body(req: &Request, status: Status) -> Json<Value> {
+	Json(match req.local_cache(|| None::<AuthError>) {
+		Some(e) => e.to_json(),
+		None => json!({ "error": status.reason.to_lowercase().replace(' ', "_"), "message": status.reason }),
+	})
+}
+
+/// 400 s JSON tělem
+#[catch(400)]
+pub fn bad_request(req: &Request) -> Json<Value> {
+	error_body(req, Status::BadRequest)
+}
+
+/// 401 s JSON tělem
+#[catch(401)]
+pub fn unauthorized(req: &Request) -> Json<Value> {
+	error_body(req, Status::Unauthorized)
+}
+
+/// 403 s JSON tělem
+#[catch(403)]
+pub fn forbidden(req: &Request) -> Json<Value> {
+	error_body(req, Status::Forbidden)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	/// xorshift, good enough for generating garbage
+	struct Rng(u64);
+
+	impl Rng {
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
+		}
+
+		fn below(&mut self, n: usize) -> usize {
+			(self.next() % n as u64) as usize
+		}
+
+		fn string(&mut self, alphabet: &[char], max_len: usize) -> String {
+			(0..self.below(max_len + 1))
+				.map(|_| alphabet[self.below(alphabet.len())])
+				.collect()
+		}
+	}
+
+	fn b64(s: &str) -> String {
+		base64::encode_config(s.as_bytes(), base64::URL_SAFE_NO_PAD)
+	}
+
+	fn assert_rejected(header: &str) {
+		match verify(Some(header)) {
+			Ok(tok) => panic!("{:?} was accepted as {:?}", header, tok),
+			Err(e) => assert!(
+				[Status::BadRequest, Status::Unauthorized, Status::Forbidden].contains(&e.status()),
+				"{:?} gave {:?}",
+				header,
+				e,
+			),
+		}
+	}
+
+	#[test]
+	fn known_failures_map_to_errors() {
+		// unknown uuids end up looking into the key table
+		testing::setup();
+		let header = b64(r#"{"alg":"RS256"}"#);
+		let with_kid = b64(r#"{"alg":"RS256","kid":"no-such-key"}"#);
+		let bad_kid = b64(r#"{"alg":"RS256","kid":"00000000-0000-0000-0000-000000000000"}"#);
+		let payload = b64("{}");
+
+		assert_eq!(verify(None).unwrap_err(), AuthError::MissingHeader);
+		assert_eq!(verify(Some("")).unwrap_err(), AuthError::InvalidScheme);
+		assert_eq!(verify(Some("Bearer")).unwrap_err(), AuthError::InvalidScheme);
+		assert_eq!(verify(Some("Basic dXNlcjpwYXNz")).unwrap_err(), AuthError::InvalidScheme);
+		assert_eq!(verify(Some("Bearer a.b")).unwrap_err(), AuthError::MalformedToken);
+		assert_eq!(verify(Some("Bearer !!.??.**")).unwrap_err(), AuthError::MalformedToken);
+		assert_eq!(
+			verify(Some(&format!("Bearer {}.{}.", header, payload))).unwrap_err(),
+			AuthError::MissingKid,
+		);
+		assert_eq!(
+			verify(Some(&format!("Bearer {}.{}.", with_kid, payload))).unwrap_err(),
+			AuthError::UnknownKey,
+		);
+		assert_eq!(
+			verify(Some(&format!("bearer \"{}.{}.\"", bad_kid, payload))).unwrap_err(),
+			AuthError::UnknownKey,
+		);
+	}
+
+	#[test]
+	fn statuses_and_bodies() {
+		assert_eq!(AuthError::MalformedToken.status(), Status::BadRequest);
+		assert_eq!(AuthError::Expired.status(), Status::Unauthorized);
+		assert_eq!(AuthError::KeyOwnerMismatch.status(), Status::Forbidden);
+		assert_eq!(AuthError::UnknownKey.to_json()["error"], "unknown_key");
+	}
+
+	#[test]
+	fn arbitrary_headers_are_rejected() {
+		testing::setup();
+		let alphabet = "abcXYZ019 .-_+/=\"{}:,\u{0}\u{7f}ž\u{1F600}".chars().collect::<Vec<_>>();
+		let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+
+		for _ in 0..5000 {
+			let garbage = rng.string(&alphabet, 64);
+			assert_rejected(&garbage);
+			assert_rejected(&format!("Bearer {}", garbage));
+		}
+	}
+
+	#[test]
+	fn arbitrary_tokens_are_rejected() {
+		testing::setup();
+		let b64_chars = "ABCxyz0189-_".chars().collect::<Vec<_>>();
+		let json_chars = "\"{}[]:,kidalgtypnoe0123 \\".chars().collect::<Vec<_>>();
+		let headers = [
+			r#"{"alg":"RS256","kid":5}"#,
+			r#"{"alg":"none","kid":"x"}"#,
+			r#"{"kid":null}"#,
+			r#"[1,2,3]"#,
+			r#""kid""#,
+			r#"{"alg":"HS256","kid":"00000000-0000-0000-0000-000000000000"}"#,
+		];
+		let mut rng = Rng(0xDEAD_BEEF_CAFE_F00D);
+
+		for _ in 0..5000 {
+			let header = match rng.below(3) {
+				0 => b64(headers[rng.below(headers.len())]),
+				1 => b64(&rng.string(&json_chars, 32)),
+				_ => rng.string(&b64_chars, 32),
+			};
+			let payload = match rng.below(2) {
+				0 => b64(&rng.string(&json_chars, 32)),
+				_ => rng.string(&b64_chars, 32),
+			};
+			let signature = rng.string(&b64_chars, 128);
+
+			assert_rejected(&format!("Bearer {}.{}.{}", header, payload, signature));
+		}
+	}
+}
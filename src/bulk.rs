@@ -0,0 +1,164 @@
+//! Hromadné zadávání známek
+//!
+//! A teacher enters one grade event (e.g. a test) for the whole class in
+//! one request. The rows are validated first and if any of them is wrong,
+//! nothing is written and the errors of all the rows are returned. Valid
+//! requests are written in one batch.
+use crate::stats;
+use crate::terms;
+use crate::scales;
+use crate::auth::AuthToken;
+use crate::db::{Database, NewEntry, NewEntryPartial};
+use crate::models::{Enrollment, EnrollmentStatus, Grade, NewGrade, Subject};
+
+use uuid::Uuid;
+use serde_json::json;
+use rocket::Request;
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::Json;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+
+/// one grade event for many students
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BulkGradeForm {
+	pub name: String,
+	/// `YYYY-MM-DD`
+	pub date: String,
+	/// `Regular`, `Bonus` or `Penalisation`, regular if not given
+	#[serde(default)]
+	pub typ: String,
+	#[serde(default)]
+	pub category: Option<String>,
+	#[serde(default)]
+	pub description: Option<String>,
+	#[serde(default)]
+	pub weight: Option<f32>,
+	pub values: Vec<BulkValue>,
+}
+
+/// a grade of one student, rows with an empty `number` are skipped
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BulkValue {
+	pub student: Uuid,
+	pub number: String,
+}
+
+/// what is wrong with a row
+#[derive(Clone, Debug, Serialize)]
+pub struct RowError {
+	/// index in `values`
+	pub row: usize,
+	pub student: Uuid,
+	pub error: &'static str,
+}
+
+#[derive(Debug)]
+pub enum BulkError {
+	/// the whole request is wrong
+	Status(Status),
+	/// some of the rows are wrong, nothing was written
+	Rows(Vec<RowError>),
+}
+
+impl From<Status> for BulkError {
+	fn from(status: Status) -> Self {
+		BulkError::Status(status)
+	}
+}
+
+impl<'r> Responder<'r> for BulkError {
+	fn respond_to(self, req: &Request) -> response::Result<'r> {
+		match self {
+			BulkError::Status(status) => Err(status),
+			BulkError::Rows(errors) => Response::build_from(Json(json!({ "errors": errors })).respond_to(req)?)
+				.status(Status::UnprocessableEntity)
+				.ok(),
+		}
+	}
+}
+
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+	let date = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()?;
+	Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?))
+}
+
+/// admins and the subject's teacher can grade its students
+pub fn can_grade(subject: &Subject, info: &AuthToken) -> bool {
+	info.typ == "admin" || (info.typ == "teacher" && subject.teacher == info.id)
+}
+
+/// validates and writes the grades, returns their ids in the order of the rows
+pub fn enter(subject: Uuid, input: BulkGradeForm, info: &AuthToken) -> Result<Vec<Uuid>, BulkError> {
+	let subject = Database::<Subject>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(subject)
+		.ok_or(Status::NotFound)?;
+	if !can_grade(&subject, info) {
+		return Err(Status::NotFound.into());
+	}
+
+	let date = parse_date(&input.date).ok_or(Status::BadRequest)?;
+	let term = terms::term_of(date.naive_utc().date()).map(|x| x.id);
+	if input.category.as_ref().is_some_and(|c| !subject.categories.iter().any(|x| x.name == *c))
+		|| input.weight.is_some_and(|w| !(w >= 0.0 && w.is_finite()))
+	{
+		return Err(Status::BadRequest.into());
+	}
+
+	let enrolled = Database::<Enrollment>::open()
+		.ok_or(Status::InternalServerError)?
+		.find("subject", &subject.id)
+		.into_iter()
+		.filter(|x| x.status == EnrollmentStatus::Approved)
+		.map(|x| x.student)
+		.collect::<HashSet<_>>();
+	let scale = scales::for_subject(&subject);
+
+	let mut seen = HashSet::new();
+	let mut errors = vec![];
+	let mut grades = vec![];
+
+	for (row, value) in input.values.iter().enumerate() {
+		if value.number.trim().is_empty() {
+			continue;
+		}
+		let error = |error| RowError { row, student: value.student, error };
+
+		if !seen.insert(value.student) {
+			errors.push(error("duplicate_student"));
+		} else if !enrolled.contains(&value.student) {
+			errors.push(error("not_enrolled"));
+		} else {
+			match scales::parse_value(scale.as_ref().map(|x| &x.kind), &input.typ, &value.number) {
+				Some(val) => grades.push(Grade::create(NewGrade {
+					name: input.name.clone(),
+					val,
+					description: input.description.clone(),
+					date,
+					subject: subject.id,
+					student: value.student,
+					category: input.category.clone(),
+					weight: input.weight,
+				}).and_modify(|x| x.term = term)),
+				None => errors.push(error("invalid_value")),
+			}
+		}
+	}
+
+	if !errors.is_empty() {
+		return Err(BulkError::Rows(errors));
+	}
+
+	Database::<Grade>::open()
+		.ok_or(Status::InternalServerError)?
+		.insert_indexed_batch(&grades)
+		.map_err(|_| Status::InternalServerError)?;
+	stats::invalidate(subject.id);
+
+	Ok(grades.into_iter().map(|(id, _)| id).collect())
+}
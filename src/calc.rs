@@ -0,0 +1,350 @@
+//! Výpočet známek
+//!
+//! Turns a student's grades in a subject into the variables of
+//! [`formula`](crate::formula) and evaluates the subject's formula.
+//! A grade weighs what it says, or what its category says, or 1.
+//!
+//! Bonuses and penalisations don't enter averages. Their points are summed
+//! and turned into a shift of the final mark by the subject's
+//! [`BonusRules`], each side capped separately, and the result is kept
+//! within the subject's scale. A formula that uses the `bonus` or `penalty`
+//! variables handles them itself and the rules are not applied.
+use crate::scales;
+use crate::formula::{self, Env};
+use crate::models::{BonusRules, Category, Grade, GradeVal, PointRule, ScaleKind, Subject};
+
+use serde::Serialize;
+
+/// whether the categories can be used in formulas, names have to be
+/// identifiers and unique
+pub fn valid_categories(categories: &[Category]) -> bool {
+	categories.iter().enumerate().all(|(i, x)| {
+		!x.name.is_empty()
+			&& x.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+			&& !x.name.starts_with(|c: char| c.is_ascii_digit())
+			&& x.weight >= 0.0
+			&& categories[..i].iter().all(|y| y.name != x.name)
+	})
+}
+
+/// the weight a grade counts with
+pub fn weight(subject: &Subject, grade: &Grade) -> f64 {
+	let category = || subject.categories
+		.iter()
+		.find(|c| Some(&c.name) == grade.category.as_ref())
+		.map(|c| c.weight);
+
+	f64::from(grade.weight.or_else(category).unwrap_or(1.0))
+}
+
+/// regular grades with their weights
+fn regular<'a>(subject: &'a Subject, grades: &'a [Grade]) -> impl Iterator<Item = (&'a Grade, f64, f64)> + 'a {
+	grades.iter().filter_map(move |x| match x.val {
+		GradeVal::Regular(v) => Some((x, f64::from(v), weight(subject, x))),
+		_ => None,
+	})
+}
+
+fn insert_stats(env: &mut Env, suffix: &str, values: &[(f64, f64)]) {
+	let sum = values.iter().map(|(v, _)| v).sum::<f64>();
+	let weights = values.iter().map(|(_, w)| w).sum::<f64>();
+
+	env.insert(format!("sum{}", suffix), sum);
+	env.insert(format!("count{}", suffix), values.len() as f64);
+	if !values.is_empty() {
+		env.insert(format!("avg{}", suffix), sum / values.len() as f64);
+		env.insert(format!("lowest{}", suffix), values.iter().map(|(v, _)| *v).fold(f64::INFINITY, f64::min));
+		env.insert(format!("highest{}", suffix), values.iter().map(|(v, _)| *v).fold(f64::NEG_INFINITY, f64::max));
+	}
+	if weights > 0.0 {
+		env.insert(format!("wavg{}", suffix), values.iter().map(|(v, w)| v * w).sum::<f64>() / weights);
+	}
+}
+
+/// variables for the formula: `avg`, `wavg` (weighted), `sum`, `count`,
+/// `lowest` and `highest` of the regular grades, and the same per category
+/// with its name as a suffix, e.g. `wavg_test`
+pub fn env(subject: &Subject, grades: &[Grade]) -> Env {
+	let mut env = Env::new();
+
+	let all = regular(subject, grades).map(|(_, v, w)| (v, w)).collect::<Vec<_>>();
+	insert_stats(&mut env, "", &all);
+
+	for category in &subject.categories {
+		let values = regular(subject, grades)
+			.filter(|(x, _, _)| x.category.as_ref() == Some(&category.name))
+			.map(|(_, v, w)| (v, w))
+			.collect::<Vec<_>>();
+		insert_stats(&mut env, &format!("_{}", category.name), &values);
+	}
+
+	env
+}
+
+/// total points of bonuses and of penalisations
+pub fn points(grades: &[Grade]) -> (i32, i32) {
+	grades.iter().fold((0, 0), |(b, p), x| match x.val {
+		GradeVal::Bonus(v) => (b.saturating_add(v), p),
+		GradeVal::Penalisation(v) => (b, p.saturating_add(v)),
+		GradeVal::Regular(_) => (b, p),
+	})
+}
+
+impl PointRule {
+	/// steps and shifts can't be negative, a threshold needs some points
+	pub fn is_valid(self) -> bool {
+		match self {
+			PointRule::Additive { step } => step >= 0.0 && step.is_finite(),
+			PointRule::Threshold { points, shift } => points > 0 && shift >= 0.0 && shift.is_finite(),
+		}
+	}
+
+	/// how far the points move the mark, never negative
+	pub fn shift(self, points: i32) -> f64 {
+		let points = points.max(0);
+
+		match self {
+			PointRule::Additive { step } => f64::from(points) * f64::from(step),
+			PointRule::Threshold { points: 0, .. } => 0.0,
+			PointRule::Threshold { points: per, shift } => f64::from(points as u32 / per) * f64::from(shift),
+		}
+	}
+}
+
+impl BonusRules {
+	/// whether the rules can be set on a subject
+	pub fn is_valid(&self) -> bool {
+		let cap = |cap: Option<f32>| cap.is_none_or(|c| c >= 0.0 && c.is_finite());
+
+		self.bonus.is_valid() && self.penalisation.is_valid() && cap(self.bonus_cap) && cap(self.penalisation_cap)
+	}
+}
+
+/// moves the mark by the points according to the rules
+pub fn apply_points(rules: &BonusRules, mark: f64, bonus: i32, penalisation: i32) -> f64 {
+	let cap = |shift: f64, cap: Option<f32>| cap.map_or(shift, |c| shift.min(f64::from(c).max(0.0)));
+
+	let up = cap(rules.bonus.shift(bonus), rules.bonus_cap);
+	let down = cap(rules.penalisation.shift(penalisation), rules.penalisation_cap);
+	let better = if rules.lower_is_better { -1.0 } else { 1.0 };
+
+	mark + better * (up - down)
+}
+
+/// everything shown about a student's grades in a subject
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Summary {
+	/// weighted average of the regular grades
+	pub average: Option<f64>,
+	/// total points of bonuses
+	pub bonus: i32,
+	/// total points of penalisations
+	pub penalisation: i32,
+	/// the final mark, `None` without regular grades or with a broken formula
+	pub mark: Option<f64>,
+	/// the mark on the subject's scale
+	pub display: Option<String>,
+}
+
+/// computes the summary, the scale keeps the mark in its range
+pub fn summarize_with(subject: &Subject, scale: Option<&ScaleKind>, grades: &[Grade]) -> Summary {
+	let mut env = env(subject, grades);
+	let (bonus, penalisation) = points(grades);
+	env.insert("bonus".to_string(), f64::from(bonus));
+	env.insert("penalty".to_string(), f64::from(penalisation));
+
+	let mark = formula::parse(&subject.grade_formula)
+		.ok()
+		.filter(|_| env["count"] > 0.0)
+		.and_then(|expr| {
+			let base = expr.eval(&env).ok()?;
+			let vars = expr.variables();
+
+			Some(if vars.contains(&"bonus") || vars.contains(&"penalty") {
+				base
+			} else {
+				apply_points(&subject.bonus_rules, base, bonus, penalisation)
+			})
+		})
+		.map(|mark| match scale.map(ScaleKind::range) {
+			Some((lo, hi)) => mark.max(lo).min(hi),
+			None => mark,
+		});
+
+	Summary {
+		average: env.get("wavg").cloned(),
+		bonus,
+		penalisation,
+		mark,
+		display: scale.zip(mark).map(|(s, m)| s.display(m)),
+	}
+}
+
+/// computes the summary on the subject's scale
+pub fn summarize(subject: &Subject, grades: &[Grade]) -> Summary {
+	let scale = scales::for_subject(subject);
+	summarize_with(subject, scale.as_ref().map(|x| &x.kind), grades)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use uuid::Uuid;
+	use chrono::Utc;
+	use crate::models::Kind;
+
+	fn subject(formula: &str, categories: &[(&str, f32)]) -> Subject {
+		Subject {
+			id: Uuid::nil(),
+			name: String::new(),
+			description: String::new(),
+			year: String::new(),
+			grade_formula: formula.to_string(),
+			kind: Kind::Other,
+			teacher: Uuid::nil(),
+			capacity: None,
+			term: None,
+			scale: None,
+			bonus_rules: BonusRules::default(),
+			categories: categories
+				.iter()
+				.map(|(name, weight)| Category { name: name.to_string(), weight: *weight })
+				.collect(),
+		}
+	}
+
+	fn grade(val: GradeVal, category: Option<&str>, weight: Option<f32>) -> Grade {
+		Grade {
+			id: Uuid::new_v4(),
+			name: String::new(),
+			val,
+			description: None,
+			date: Utc::now(),
+			subject: Uuid::nil(),
+			student: Uuid::nil(),
+			category: category.map(str::to_string),
+			weight,
+			term: None,
+		}
+	}
+
+	#[test]
+	fn grades_are_weighted_by_category_or_override() {
+		let subject = subject("wavg", &[("test", 3.0), ("homework", 1.0)]);
+		let grades = vec![
+			grade(GradeVal::Regular(1.0), Some("test"), None),
+			grade(GradeVal::Regular(3.0), Some("homework"), None),
+			grade(GradeVal::Regular(5.0), Some("homework"), Some(0.0)),
+			grade(GradeVal::Regular(2.0), None, None),
+		];
+		let env = env(&subject, &grades);
+
+		assert_eq!(env["wavg"], (3.0 + 3.0 + 2.0) / 5.0);
+		assert_eq!(env["avg"], 11.0 / 4.0);
+		assert_eq!(env["avg_homework"], 4.0);
+		assert_eq!(env["count_test"], 1.0);
+		assert_eq!(summarize_with(&subject, None, &grades).mark, Some(1.6));
+		assert_eq!(summarize_with(&subject, None, &[]).mark, None);
+	}
+
+	#[test]
+	fn category_names_must_be_identifiers() {
+		let valid = |names: &[&str]| valid_categories(
+			&names.iter().map(|n| Category { name: n.to_string(), weight: 1.0 }).collect::<Vec<_>>()
+		);
+
+		assert!(valid(&["test", "home_work2"]));
+		assert!(!valid(&["home work"]));
+		assert!(!valid(&["2nd"]));
+		assert!(!valid(&["test", "test"]));
+	}
+
+	#[test]
+	fn points_are_additive_and_capped() {
+		let mut subject = subject("avg", &[]);
+		let mut grades = vec![
+			grade(GradeVal::Regular(3.0), None, None),
+			grade(GradeVal::Bonus(3), None, None),
+			grade(GradeVal::Penalisation(1), None, None),
+		];
+		let mark = |s: &Subject, g: &[Grade]| summarize_with(s, None, g).mark.map(|m| (m * 100.0).round() / 100.0);
+
+		// three bonus points up, one down, lower is better
+		assert_eq!(mark(&subject, &grades), Some(2.8));
+
+		grades.push(grade(GradeVal::Bonus(20), None, None));
+		assert_eq!(mark(&subject, &grades), Some(2.1));
+
+		subject.bonus_rules.lower_is_better = false;
+		assert_eq!(mark(&subject, &grades), Some(3.9));
+	}
+
+	#[test]
+	fn points_saturate() {
+		let grades = vec![grade(GradeVal::Bonus(i32::MAX), None, None), grade(GradeVal::Bonus(i32::MAX), None, None)];
+
+		assert_eq!(points(&grades), (i32::MAX, 0));
+	}
+
+	#[test]
+	fn threshold_rules_shift_by_whole_steps() {
+		let rules = BonusRules {
+			bonus: PointRule::Threshold { points: 5, shift: 1.0 },
+			penalisation: PointRule::Threshold { points: 3, shift: 0.5 },
+			bonus_cap: None,
+			penalisation_cap: Some(0.5),
+			lower_is_better: true,
+		};
+
+		assert_eq!(apply_points(&rules, 3.0, 4, 0), 3.0);
+		assert_eq!(apply_points(&rules, 3.0, 10, 0), 1.0);
+		assert_eq!(apply_points(&rules, 3.0, 0, 9), 3.5);
+		assert_eq!(apply_points(&rules, 3.0, -5, 2), 3.0);
+		assert_eq!(PointRule::Threshold { points: 0, shift: 1.0 }.shift(10), 0.0);
+	}
+
+	#[test]
+	fn rules_are_validated() {
+		let with = |f: fn(&mut BonusRules)| {
+			let mut rules = BonusRules::default();
+			f(&mut rules);
+			rules.is_valid()
+		};
+
+		assert!(with(|_| ()));
+		assert!(!with(|r| r.bonus = PointRule::Additive { step: -0.1 }));
+		assert!(!with(|r| r.penalisation = PointRule::Threshold { points: 0, shift: 1.0 }));
+		assert!(!with(|r| r.penalisation = PointRule::Threshold { points: 3, shift: -1.0 }));
+		assert!(!with(|r| r.bonus = PointRule::Additive { step: f32::NAN }));
+		assert!(!with(|r| r.bonus_cap = Some(-1.0)));
+		assert!(with(|r| r.penalisation_cap = None));
+	}
+
+	#[test]
+	fn formulas_using_points_skip_the_rules() {
+		let subject = subject("avg - bonus + 2 * penalty", &[]);
+		let grades = vec![
+			grade(GradeVal::Regular(3.0), None, None),
+			grade(GradeVal::Bonus(1), None, None),
+			grade(GradeVal::Penalisation(1), None, None),
+		];
+
+		assert_eq!(summarize_with(&subject, None, &grades).mark, Some(4.0));
+	}
+
+	#[test]
+	fn marks_stay_on_the_scale() {
+		let subject = subject("avg", &[]);
+		let grades = vec![
+			grade(GradeVal::Regular(1.0), None, None),
+			grade(GradeVal::Bonus(5), None, None),
+		];
+		let scale = ScaleKind::Numeric { min: 1.0, max: 5.0, step: 1.0 };
+		let summary = summarize_with(&subject, Some(&scale), &grades);
+
+		assert_eq!(summary.mark, Some(1.0));
+		assert_eq!(summary.display, Some("1".to_string()));
+		assert_eq!((summary.bonus, summary.penalisation), (5, 0));
+	}
+}
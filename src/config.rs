@@ -0,0 +1,175 @@
+//! Konfigurace serveru
+//!
+//! Everything is read from environment variables (and `.env`),
+//! missing or unparsable values fall back to the defaults below.
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+lazy_static! {
+	/// global server configuration, read on first access
+	pub static ref CONFIG: Config = Config::from_env();
+}
+
+/// who signs the issued tokens
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigningMode {
+	/// a small set of server keys, see [`Config::server_key_dir`]
+	Server,
+	/// every user has their own key, only kept for migrating old databases
+	PerUser,
+}
+
+impl FromStr for SigningMode {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"server" => Ok(SigningMode::Server),
+			"user" | "per_user" => Ok(SigningMode::PerUser),
+			_ => Err(()),
+		}
+	}
+}
+
+/// how emails are sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MailTransport {
+	/// through an SMTP relay
+	Smtp,
+	/// appended to [`Config::mail_file`]
+	File,
+	/// printed, for development
+	Stdout,
+}
+
+impl FromStr for MailTransport {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"smtp" => Ok(MailTransport::Smtp),
+			"file" => Ok(MailTransport::File),
+			"stdout" => Ok(MailTransport::Stdout),
+			_ => Err(()),
+		}
+	}
+}
+
+/// server configuration
+pub struct Config {
+	/// who signs the issued tokens
+	pub signing_mode: SigningMode,
+	/// directory with the server's private keys, one PEM per file,
+	/// the file name without extension is the `kid`
+	pub server_key_dir: String,
+	/// passphrase of the keys in [`Config::server_key_dir`], if they are encrypted
+	pub server_key_passphrase: Option<String>,
+	/// `kid` of the server key used for signing,
+	/// the last one by name is used if not set
+	pub server_signing_kid: Option<String>,
+	/// how many parsed verification keys to keep in memory
+	pub key_cache_size: usize,
+	/// age after which a user's signing key is rotated
+	pub key_rotation_days: i64,
+	/// how often the rotation task wakes up
+	pub key_rotation_interval_secs: u64,
+	/// failed logins allowed before the backoff kicks in
+	pub login_free_attempts: u32,
+	/// first backoff delay in seconds, doubled with every further failure
+	pub login_backoff_secs: i64,
+	/// upper bound of the backoff delay in seconds
+	pub login_backoff_max_secs: i64,
+	/// failed logins after which the email or address is locked out
+	pub login_lockout_threshold: u32,
+	/// how long a lockout lasts, failures older than this are forgotten
+	pub login_lockout_secs: i64,
+	/// reverse proxies whose `X-Real-IP` header is believed,
+	/// the peer address is used for everybody else
+	pub trusted_proxies: Vec<IpAddr>,
+	/// address of the server as seen by users, used in links in emails
+	pub public_url: String,
+	/// secret for signing password reset and verification tokens,
+	/// a random one is generated on startup if not set
+	pub action_token_secret: Option<String>,
+	/// how long a password reset token is valid, in seconds
+	pub reset_token_lifetime: i64,
+	/// how long an email verification token is valid, in seconds
+	pub verify_token_lifetime: i64,
+	/// how long one has to enter the second factor after the password, in seconds
+	pub login_challenge_lifetime: i64,
+	/// whether teachers must use TOTP until an admin changes the policy
+	pub require_teacher_2fa: bool,
+	/// email of the first admin, created on startup if there is no admin yet
+	pub admin_email: Option<String>,
+	/// password of the first admin
+	pub admin_pass: Option<String>,
+	/// how to send emails
+	pub mail_transport: MailTransport,
+	/// sender of all emails
+	pub mail_from: String,
+	/// file the `file` transport appends to
+	pub mail_file: String,
+	/// SMTP relay
+	pub smtp_host: String,
+	/// SMTP port
+	pub smtp_port: u16,
+	/// SMTP user, authentication is skipped if not set
+	pub smtp_user: Option<String>,
+	/// SMTP password
+	pub smtp_pass: Option<String>,
+	/// whether to upgrade the SMTP connection with `STARTTLS`,
+	/// without it the relay can't be authenticated to
+	pub smtp_starttls: bool,
+}
+
+impl Config {
+	/// reads the configuration from the environment
+	pub fn from_env() -> Config {
+		Config {
+			signing_mode:               env_or("SIGNING_MODE", SigningMode::Server),
+			server_key_dir:             env_or("SERVER_KEY_DIR", "keys".to_string()),
+			server_key_passphrase:      env::var("SERVER_KEY_PASSPHRASE").ok(),
+			server_signing_kid:         env::var("SERVER_SIGNING_KID").ok(),
+			key_cache_size:             env_or("KEY_CACHE_SIZE", 1024),
+			key_rotation_days:          env_or("KEY_ROTATION_DAYS", 30),
+			key_rotation_interval_secs: env_or("KEY_ROTATION_INTERVAL", 60 * 60),
+			login_free_attempts:        env_or("LOGIN_FREE_ATTEMPTS", 3),
+			login_backoff_secs:         env_or("LOGIN_BACKOFF", 1),
+			login_backoff_max_secs:     env_or("LOGIN_BACKOFF_MAX", 5 * 60),
+			login_lockout_threshold:    env_or("LOGIN_LOCKOUT_THRESHOLD", 10),
+			login_lockout_secs:         env_or("LOGIN_LOCKOUT", 15 * 60),
+			trusted_proxies:            env_list("TRUSTED_PROXIES"),
+			public_url:                 env_or("PUBLIC_URL", "http://localhost:8000".to_string()),
+			action_token_secret:        env::var("ACTION_TOKEN_SECRET").ok(),
+			reset_token_lifetime:       env_or("RESET_TOKEN_LIFETIME", 60 * 60),
+			verify_token_lifetime:      env_or("VERIFY_TOKEN_LIFETIME", 2 * 24 * 60 * 60),
+			login_challenge_lifetime:   env_or("LOGIN_CHALLENGE_LIFETIME", 5 * 60),
+			require_teacher_2fa:        env_or("REQUIRE_TEACHER_2FA", false),
+			admin_email:                env::var("ADMIN_EMAIL").ok(),
+			admin_pass:                 env::var("ADMIN_PASSWORD").ok(),
+			mail_transport:             env_or("MAIL_TRANSPORT", MailTransport::Stdout),
+			mail_from:                  env_or("MAIL_FROM", "znamky@localhost".to_string()),
+			mail_file:                  env_or("MAIL_FILE", "mail.log".to_string()),
+			smtp_host:                  env_or("SMTP_HOST", "localhost".to_string()),
+			smtp_port:                  env_or("SMTP_PORT", 25),
+			smtp_user:                  env::var("SMTP_USER").ok(),
+			smtp_pass:                  env::var("SMTP_PASS").ok(),
+			smtp_starttls:              env_or("SMTP_STARTTLS", true),
+		}
+	}
+}
+
+/// a comma separated list, unparseable items are skipped
+fn env_list<T: FromStr>(name: &str) -> Vec<T> {
+	env::var(name)
+		.map(|x| x.split(',').filter_map(|x| x.trim().parse().ok()).collect())
+		.unwrap_or_default()
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+	env::var(name)
+		.ok()
+		.and_then(|x| x.parse().ok())
+		.unwrap_or(default)
+}
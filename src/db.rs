@@ -108,6 +108,21 @@ where
 		})
 	}
 
+	/// inserts all the pairs at once, either all of them are written or none
+	pub fn insert_batch<Key: Borrow<K>, Value: Borrow<V>>(
+		&mut self,
+		pairs: impl IntoIterator<Item = (Key, Value)>,
+	) -> sled::Result<()> {
+		let mut batch = sled::Batch::default();
+		for (k, v) in pairs {
+			batch.insert(
+				serde_cbor::to_vec(k.borrow()).unwrap(),
+				serde_cbor::to_vec(v.borrow()).unwrap(),
+			);
+		}
+		self.tree.apply_batch(batch)
+	}
+
 	/// remove a value
 	pub fn delete<Key: Borrow<K>>(&mut self, k: Key) -> sled::Result<Option<sled::IVec>> {
 		self.tree.remove(serde_cbor::to_vec(k.borrow()).unwrap())
@@ -154,6 +169,91 @@ impl<T: Table> Database<T> {
 	}
 }
 
+impl<T: Indexed> Database<T> {
+	fn index_tree(index: &str) -> sled::Result<Tree> {
+		let lock = DB.read().expect("the database rwlock has been poisoned");
+		lock.open_tree(format!("{}_by_{}", T::name(), index))
+	}
+
+	/// the key under which an entry is stored in an index,
+	/// CBOR items are self-delimiting so fields can't be confused
+	fn index_key(field: &[u8], key: &T::Key) -> Vec<u8> {
+		let mut index_key = field.to_vec();
+		index_key.extend(serde_cbor::to_vec(key).unwrap()); // can't fail
+		index_key
+	}
+
+	fn unindex(key: &T::Key, value: &T::Value) -> sled::Result<()> {
+		for (index, field) in T::index_fields(value) {
+			Self::index_tree(index)?.remove(Self::index_key(&field, key))?;
+		}
+		Ok(())
+	}
+
+	fn index(key: &T::Key, value: &T::Value) -> sled::Result<()> {
+		for (index, field) in T::index_fields(value) {
+			Self::index_tree(index)?.insert(Self::index_key(&field, key), serde_cbor::to_vec(key).unwrap())?;
+		}
+		Ok(())
+	}
+
+	/// inserts a value and updates the indexes
+	pub fn insert_indexed(&mut self, k: &T::Key, v: &T::Value) -> sled::Result<()> {
+		let old = self.0.insert(k, v)?;
+		if let Some(old) = old.and_then(|x| serde_cbor::from_slice::<T::Value>(&x).ok()) {
+			Self::unindex(k, &old)?;
+		}
+		Self::index(k, v)
+	}
+
+	/// inserts new values at once and indexes them,
+	/// the values are written atomically, the indexes after them
+	pub fn insert_indexed_batch(&mut self, pairs: &[(T::Key, T::Value)]) -> sled::Result<()> {
+		self.0.insert_batch(pairs.iter().map(|(k, v)| (k, v)))?;
+		for (k, v) in pairs {
+			Self::index(k, v)?;
+		}
+		Ok(())
+	}
+
+	/// removes a value and its index entries
+	pub fn delete_indexed(&mut self, k: &T::Key) -> sled::Result<Option<T::Value>> {
+		let old = self.0.delete(k)?.and_then(|x| serde_cbor::from_slice::<T::Value>(&x).ok());
+		if let Some(ref old) = old {
+			Self::unindex(k, old)?;
+		}
+		Ok(old)
+	}
+
+	/// values whose field in the index equals `field`, without scanning the table
+	pub fn find<F: Serialize>(&self, index: &str, field: &F) -> Vec<T::Value> {
+		let tree = match Self::index_tree(index) {
+			Ok(t) => t,
+			Err(_) => return vec![],
+		};
+
+		tree.scan_prefix(serde_cbor::to_vec(field).unwrap())
+			.filter_map(|res| res.ok())
+			.filter_map(|(_, key)| serde_cbor::from_slice::<T::Key>(&key).ok())
+			.filter_map(|key| self.0.get(key))
+			.collect()
+	}
+
+	/// builds the indexes from scratch, for data written before they existed
+	pub fn rebuild_indexes() -> sled::Result<()> {
+		for index in T::indexes() {
+			Self::index_tree(index)?.clear()?;
+		}
+
+		if let Some(db) = Self::open() {
+			for (key, value) in db.0.iter() {
+				Self::index(&key, &value)?;
+			}
+		}
+		Ok(())
+	}
+}
+
 /// trait for the Table marker types
 pub trait Table {
 	/// opening a table might not always work,
@@ -208,6 +308,17 @@ impl<T: Table> Drop for Database<T> {
 	}
 }
 
+/// tables with secondary indexes, each index is a tree named
+/// `<table>_by_<index>` mapping a field to the keys of the entries,
+/// it's only kept up to date by [`Database::insert_indexed`]
+/// and [`Database::delete_indexed`]
+pub trait Indexed: Table {
+	/// names of the indexes
+	fn indexes() -> &'static [&'static str];
+	/// the CBOR-encoded field of the value in each index
+	fn index_fields(value: &Self::Value) -> Vec<(&'static str, Vec<u8>)>;
+}
+
 /// trait for manupulating a newy created entry
 pub trait NewEntry {
 	/// table
@@ -258,3 +369,65 @@ where
 	}
 }
 
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+	struct Item {
+		owner: u32,
+		name: String,
+	}
+
+	impl Table for Item {
+		type Key = u32;
+		type Value = Self;
+
+		fn name() -> &'static str {
+			"test_item"
+		}
+	}
+
+	impl Indexed for Item {
+		fn indexes() -> &'static [&'static str] {
+			&["owner"]
+		}
+
+		fn index_fields(value: &Self) -> Vec<(&'static str, Vec<u8>)> {
+			vec![("owner", serde_cbor::to_vec(&value.owner).unwrap())]
+		}
+	}
+
+	fn names(db: &Database<Item>, owner: u32) -> Vec<String> {
+		let mut names = db.find("owner", &owner).into_iter().map(|x| x.name).collect::<Vec<_>>();
+		names.sort();
+		names
+	}
+
+	#[test]
+	fn indexes_follow_writes() {
+		testing::setup();
+		let mut db = Database::<Item>::open().unwrap();
+		let item = |owner, name: &str| Item { owner, name: name.to_string() };
+
+		db.insert_indexed(&1, &item(1, "a")).unwrap();
+		db.insert_indexed(&2, &item(1, "b")).unwrap();
+		db.insert_indexed(&3, &item(10, "c")).unwrap();
+		assert_eq!(names(&db, 1), ["a", "b"]);
+
+		db.insert_indexed(&2, &item(10, "b")).unwrap();
+		assert_eq!(names(&db, 1), ["a"]);
+		assert_eq!(names(&db, 10), ["b", "c"]);
+
+		db.delete_indexed(&3).unwrap();
+		assert_eq!(names(&db, 10), ["b"]);
+
+		// written without the index, found after a rebuild
+		db.write().insert(4, item(1, "d")).unwrap();
+		assert_eq!(names(&db, 1), ["a"]);
+		Database::<Item>::rebuild_indexes().unwrap();
+		assert_eq!(names(&db, 1), ["a", "d"]);
+	}
+}
@@ -1,34 +1,138 @@
 use uuid::Uuid;
+use rocket::http::{RawStr, Status};
+use rocket::request::FromParam;
+use rocket::response::content::Content;
 use rocket_contrib::json::Json;
 use serde_json::{json, Value, value};
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc, NaiveDateTime};
 
-use std::str::FromStr;
+use std::ops::Deref;
+use std::net::IpAddr;
 
-use crate::auth::AuthToken;
+use crate::keys;
+use crate::ratelimit::{self, ClientIp};
+use crate::accounts::{self, AccountView, LoginError};
+use crate::policy;
+use crate::invites;
+use crate::enrollment;
+use crate::projection::{self, Projection, ProjectionForm};
+use crate::stats::{self, SubjectStats, TeacherStats, TermStats};
+use crate::bulk::{self, BulkError, BulkGradeForm};
+use crate::gradebook::{self, Gradebook};
+use crate::calc;
+use crate::formula;
+use crate::scales;
+use crate::terms::{self, TermMark};
+use crate::reports;
+use crate::guardians;
+use crate::overview::{self, SubjectOverview};
+use crate::auth::{AdminAuth, AuthToken, GuardianAuth, MfaSetup, WriteAuth};
 use crate::db::{Database, NewEntry, NewEntryPartial};
 use crate::models::{
+	Role,
+	Admin,
+	NewAdmin,
+	Guardian,
+	NewGuardian,
+	GuardianLink,
+	Policy,
+	Invite,
+	NewInvite,
+	Account,
+	UserKey,
 	Student,
 	NewStudent,
 	Teacher,
 	NewTeacher,
 	Grade,
 	NewGrade,
-	GradeVal,
 	Subject,
 	NewSubject,
+	Category,
+	Enrollment,
+	GradingScale,
+	NewGradingScale,
+	SchoolYear,
+	NewSchoolYear,
+	Term,
+	NewTerm,
+	ReportNote,
+	NewReportNote,
+	ReportTemplate,
+	NewReportTemplate,
 };
 
 use rejwt::{
-	encode,
-	Algorithm,
+	Jwk,
+	JwkSet,
 };
 
+/// a uuid in the path, rocket_contrib's one is for an older `uuid`
+#[derive(Clone, Copy, Debug)]
+pub struct UuidParam(Uuid);
+
+impl<'a> FromParam<'a> for UuidParam {
+	type Error = &'a RawStr;
+
+	fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+		Uuid::parse_str(param).map(UuidParam).map_err(|_| param)
+	}
+}
+
+impl Deref for UuidParam {
+	type Target = Uuid;
+
+	fn deref(&self) -> &Uuid {
+		&self.0
+	}
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LoginForm {
 	pub email: String,
 	pub pass: String,
+	#[serde(default)]
+	pub role: Option<Role>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TotpLoginForm {
+	pub challenge: String,
+	pub code: String,
+	#[serde(default)]
+	pub role: Option<Role>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResetRequestForm {
+	pub email: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResetForm {
+	pub token: String,
+	pub pass: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminResetForm {
+	#[serde(default)]
+	pub password: bool,
+	#[serde(default)]
+	pub totp: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AssignRoleForm {
+	pub role: Role,
+	pub record: Uuid,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UnlockForm {
+	pub email: Option<String>,
+	pub ip: Option<IpAddr>,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -40,6 +144,10 @@ pub struct NewGradeForm {
 	pub number: String,
 	pub student: Uuid,
 	pub description: String,
+	#[serde(default)]
+	pub category: Option<String>,
+	#[serde(default)]
+	pub weight: Option<f32>,
 }
 
 #[get("/subjects")]
@@ -56,7 +164,6 @@ pub(crate) fn teachers(db: Database<Teacher>) -> Json<Vec<Teacher>> {
 	Json(db
 		.read()
 		.iter()
-		.inspect(|(_, x)| println!("{:?}", x))
 		.map(|(_, x)| x)
 		.collect::<Vec<_>>())
 }
@@ -66,7 +173,6 @@ pub(crate) fn students(db: Database<Student>) -> Json<Vec<Student>> {
 	Json(db
 		.read()
 		.iter()
-		.inspect(|(_, x)| println!("{:?}", x))
 		.map(|(_, x)| x)
 		.collect::<Vec<_>>())
 }
@@ -80,129 +186,610 @@ pub(crate) fn grades(db: Database<Grade>) -> Json<Vec<Grade>> {
 		.collect::<Vec<_>>())
 }
 
+/// public keys of all users, so that other services can verify our tokens
+#[get("/.well-known/jwks.json")]
+pub(crate) fn jwks(db: Database<UserKey>) -> Json<JwkSet> {
+	let user_keys = db
+		.read()
+		.iter()
+		.filter(|(_, x)| keys::is_valid(x))
+		.filter_map(|(kid, x)| Some(
+			Jwk::from_pem(x.pub_key.as_bytes())
+				.ok()?
+				.kid(kid.to_hyphenated().to_string())
+		))
+		.collect::<Vec<_>>();
+
+	Json(JwkSet::new(keys::server_jwks()
+		.into_iter()
+		.chain(user_keys)
+		.collect()))
+}
+
+/// rotates the signing key of the current user,
+/// tokens signed by the old key stay valid until they expire
+#[post("/keys/rotate")]
+pub(crate) fn rotate_key(info: AuthToken) -> Option<Json<String>> {
+	keys::rotate(info.id, &info.typ).map(|kid| Json(kid.to_hyphenated().to_string()))
+}
+
 #[get("/me")]
 pub(crate) fn me(teachers: Database<Teacher>, students: Database<Student>, info: AuthToken) -> Option<Json<Value>> {
 	match info.typ.as_ref() {
 		"teacher" => Some(Json(value::to_value(teachers.read().get(&info.id)?).unwrap())),
 		"student" => Some(Json(value::to_value(students.read().get(&info.id)?).unwrap())),
+		"admin" => Some(Json(value::to_value(Database::<Admin>::open()?.read().get(info.id)?).unwrap())),
+		"guardian" => Some(Json(value::to_value(Database::<Guardian>::open()?.read().get(info.id)?).unwrap())),
 		_ => None
 	}
 }
 
+/// the current student's subjects with grades, averages and marks
+#[get("/me/overview")]
+pub(crate) fn my_overview(info: AuthToken) -> Result<Json<Vec<SubjectOverview>>, Status> {
+	if info.typ != "student" {
+		return Err(Status::NotFound);
+	}
+	overview::for_student(info.id).map(Json)
+}
+
 #[post("/my_description", format = "application/json", data = "<input>")]
-pub(crate) fn my_description(mut db: Database<Teacher>, input: Json<String>, info: AuthToken) -> Option<()> {
-	println!("{}", *input);
+pub(crate) fn my_description(mut db: Database<Teacher>, input: Json<String>, info: WriteAuth) -> Option<()> {
 	let _ = db
 		.write()
-		.update::<_, Teacher, _>(info.id, |c| {
-			println!("{:?}", c);
-			c.map(|mut x| {
-				x.info = input.clone();
-				x
-			})
-		});
+		.update::<_, Teacher, _>(info.0.id, |c| c.map(|mut x| {
+			x.info = input.clone();
+			x
+		}));
 
 	Some(())
 }
 
+/// students register with an invite, which also signs them up to its subjects;
+/// with a token of an existing account, the role is added to that account
 #[post("/register_student", format = "application/json", data = "<input>")]
-pub(crate) fn register_student(input: Json<NewStudent>, mut _db: Database<Student>) -> Option<()> {
-	Student::create(input.clone())
-		.save()
-		.map(|_| ())
-		.ok()
+pub(crate) fn register_student(input: Json<NewStudent>, owner: Option<AuthToken>) -> Result<(), Status> {
+	let code = input.invite.as_ref().ok_or(Status::Forbidden)?;
+	let invite = invites::redeem(code, Role::Student).ok_or(Status::Forbidden)?;
+
+	let (id, student) = Student::create(input.clone());
+	accounts::register(&input.email, &input.pass, Role::Student, owner.map(|x| x.account), |account| {
+		(id, student)
+			.and_modify(|x| {
+				x.account = account;
+				x.class = invite.class.clone();
+			})
+			.save()
+			.ok()
+			.map(|_| id)
+	})
+	.inspect_err(|_| invites::refund(code))?;
+
+	invite.subjects
+		.iter()
+		.try_for_each(|subject| enrollment::enroll_approved(id, *subject))
 }
 
+/// teachers are created by admins, or register with an invite from one
 #[post("/register_teacher", format = "application/json", data = "<input>")]
-pub(crate) fn register_teacher(input: Json<NewTeacher>, mut _db: Database<Teacher>) -> Option<()> {
-	Teacher::create(input.clone())
-		.save()
-		.map(|_| ())
-		.ok()
+pub(crate) fn register_teacher(input: Json<NewTeacher>, admin: Option<AdminAuth>, owner: Option<AuthToken>) -> Result<(), Status> {
+	let code = match (admin, input.invite.as_ref()) {
+		(Some(_), _) => None,
+		(None, Some(code)) => {
+			invites::redeem(code, Role::Teacher).ok_or(Status::Forbidden)?;
+			Some(code)
+		}
+		(None, None) => return Err(Status::Forbidden),
+	};
+
+	accounts::register(&input.email, &input.pass, Role::Teacher, owner.map(|x| x.account), |account| {
+		let (id, teacher) = Teacher::create(input.clone());
+		(id, teacher)
+			.and_modify(|x| x.account = account)
+			.save()
+			.ok()
+			.map(|_| id)
+	})
+	.map(|_| ())
+	.inspect_err(|_| if let Some(code) = code {
+		invites::refund(code);
+	})
 }
 
-#[post("/login_student", format = "application/json", data = "<input>")]
-pub(crate) fn login_student(input: Json<LoginForm>, db: Database<Student>) -> Option<Json<String>> {
-	let u = db
+/// anybody can register as a guardian, students have to approve the links
+#[post("/register_guardian", format = "application/json", data = "<input>")]
+pub(crate) fn register_guardian(input: Json<NewGuardian>, owner: Option<AuthToken>) -> Result<(), Status> {
+	accounts::register(&input.email, &input.pass, Role::Guardian, owner.map(|x| x.account), |account| {
+		let (id, guardian) = Guardian::create(input.clone());
+		(id, guardian)
+			.and_modify(|x| x.account = account)
+			.save()
+			.ok()
+			.map(|_| id)
+	}).map(|_| ())
+}
+
+/// asks to be linked to the student with the given email, the link shows
+/// up in the guardian's links only if the student exists
+#[post("/guardian/links", format = "application/json", data = "<input>")]
+pub(crate) fn request_guardian_link(input: Json<String>, info: GuardianAuth) -> Result<(), Status> {
+	guardians::request_link(info.0.id, &input)
+}
+
+/// links of the current guardian or student, all of them for admins
+#[get("/guardian/links")]
+pub(crate) fn guardian_links(info: AuthToken) -> Option<Json<Vec<GuardianLink>>> {
+	guardians::links(&info).map(Json)
+}
+
+#[post("/guardian/links/<id>/approve")]
+pub(crate) fn approve_guardian_link(id: UuidParam, info: AuthToken) -> Result<(), Status> {
+	guardians::approve(*id, &info)
+}
+
+#[delete("/guardian/links/<id>")]
+pub(crate) fn remove_guardian_link(id: UuidParam, info: AuthToken) -> Result<(), Status> {
+	guardians::remove(*id, &info)
+}
+
+#[get("/guardian/students")]
+pub(crate) fn guardian_students(info: GuardianAuth) -> Option<Json<Vec<Student>>> {
+	guardians::students(info.0.id).map(Json)
+}
+
+/// subjects, grades and marks of a linked student
+#[get("/guardian/students/<id>/grades")]
+pub(crate) fn guardian_student_grades(id: UuidParam, info: GuardianAuth) -> Result<Json<Vec<SubjectOverview>>, Status> {
+	guardians::grades(info.0.id, *id).map(Json)
+}
+
+#[get("/school_years")]
+pub(crate) fn school_years(db: Database<SchoolYear>) -> Json<Vec<SchoolYear>> {
+	Json(db
 		.read()
 		.iter()
-		.find_map(|(_, x)| if x.email == input.email && x.pass == input.pass {
-			Some(x)
-		} else { None })?;
+		.map(|(_, x)| x)
+		.collect::<Vec<_>>())
+}
+
+#[post("/school_years", format = "application/json", data = "<input>")]
+pub(crate) fn new_school_year(input: Json<NewSchoolYear>, _admin: AdminAuth) -> Result<Json<SchoolYear>, Status> {
+	terms::create_year(input.into_inner()).map(Json)
+}
 
-	let header = json!({ "kid": u.id.to_hyphenated().to_string() });
-	let body = AuthToken::new(u.id, "student".to_string()).make();
+#[get("/terms")]
+pub(crate) fn terms(db: Database<Term>) -> Json<Vec<Term>> {
+	Json(db
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.collect::<Vec<_>>())
+}
 
-	Some(Json(encode(header, &u.priv_key, &body, Algorithm::RS256).unwrap()))
+#[post("/terms", format = "application/json", data = "<input>")]
+pub(crate) fn new_term(input: Json<NewTerm>, _admin: AdminAuth) -> Result<Json<Term>, Status> {
+	terms::create_term(input.into_inner()).map(Json)
+}
+
+#[get("/scales")]
+pub(crate) fn scales(db: Database<GradingScale>) -> Json<Vec<GradingScale>> {
+	Json(db
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.collect::<Vec<_>>())
+}
+
+#[post("/scales", format = "application/json", data = "<input>")]
+pub(crate) fn new_scale(input: Json<NewGradingScale>, _admin: AdminAuth) -> Result<Json<GradingScale>, Status> {
+	scales::create(input.into_inner()).map(Json)
+}
+
+/// final marks of a student in a term
+#[get("/terms/<id>/marks/<student>")]
+pub(crate) fn term_marks(id: UuidParam, student: UuidParam, info: AuthToken) -> Result<Json<Vec<TermMark>>, Status> {
+	if !terms::can_see(*student, &info) {
+		return Err(Status::NotFound);
+	}
+	terms::marks(*id, *student).map(Json)
+}
+
+/// sets a teacher's comment and the absences of a student for the report card
+#[put("/terms/<id>/notes/<subject>/<student>", format = "application/json", data = "<input>")]
+pub(crate) fn set_report_note(
+	id: UuidParam,
+	subject: UuidParam,
+	student: UuidParam,
+	input: Json<NewReportNote>,
+	info: WriteAuth,
+) -> Result<Json<ReportNote>, Status> {
+	reports::set_note(*id, *subject, *student, input.into_inner(), &info.0).map(Json)
+}
+
+/// report card notes of a subject in a term
+#[get("/terms/<id>/notes/<subject>")]
+pub(crate) fn report_notes(id: UuidParam, subject: UuidParam, info: AuthToken) -> Result<Json<Vec<ReportNote>>, Status> {
+	reports::notes(*id, *subject, &info).map(Json)
+}
+
+/// the report card of a student, `format` is `html` (default) or `pdf`
+#[get("/terms/<id>/report_cards/<student>?<format>&<template>")]
+pub(crate) fn report_card(
+	id: UuidParam,
+	student: UuidParam,
+	format: Option<String>,
+	template: Option<String>,
+	info: AuthToken,
+) -> Result<Content<Vec<u8>>, Status> {
+	if !terms::can_see(*student, &info) {
+		return Err(Status::NotFound);
+	}
+	let (format, template) = reports::options(format.as_deref(), template.as_deref())?;
+	reports::render(&[reports::card(*id, *student)?], template, format)
+}
+
+/// report cards of a whole class in one document
+#[get("/terms/<id>/classes/<class>/report_cards?<format>&<template>")]
+pub(crate) fn class_report_cards(
+	id: UuidParam,
+	class: String,
+	format: Option<String>,
+	template: Option<String>,
+	info: AuthToken,
+) -> Result<Content<Vec<u8>>, Status> {
+	if info.typ != "teacher" && info.typ != "admin" {
+		return Err(Status::Forbidden);
+	}
+	let (format, template) = reports::options(format.as_deref(), template.as_deref())?;
+	reports::render(&reports::class(*id, &class)?, template, format)
+}
+
+#[get("/report_templates")]
+pub(crate) fn report_templates(db: Database<ReportTemplate>, _info: AuthToken) -> Json<Vec<ReportTemplate>> {
+	Json(db
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.collect::<Vec<_>>())
+}
+
+#[post("/report_templates", format = "application/json", data = "<input>")]
+pub(crate) fn new_report_template(input: Json<NewReportTemplate>, _admin: AdminAuth) -> Result<Json<ReportTemplate>, Status> {
+	reports::create_template(input.into_inner()).map(Json)
+}
+
+/// creates an invite, returns it with a registration link
+#[post("/invites", format = "application/json", data = "<input>")]
+pub(crate) fn create_invite(input: Json<NewInvite>, info: AuthToken) -> Result<Json<Value>, Status> {
+	let invite = invites::create(input.into_inner(), &info)?;
+	let link = invites::link(&invite);
+
+	Ok(Json(json!({ "invite": invite, "link": link })))
+}
+
+/// invites created by the user, admins get all of them
+#[get("/invites")]
+pub(crate) fn list_invites(db: Database<Invite>, info: AuthToken) -> Json<Vec<Invite>> {
+	Json(
+		db.read()
+			.iter()
+			.map(|(_, x)| x)
+			.filter(|x| invites::visible_to(x, &info))
+			.collect()
+	)
+}
+
+#[delete("/invites/<code>")]
+pub(crate) fn revoke_invite(code: String, info: AuthToken) -> Result<(), Status> {
+	invites::revoke(&code, &info)
+}
+
+/// logs in with any role, `role` only has to be given
+/// if the account has more than one
+#[post("/login", format = "application/json", data = "<input>")]
+pub(crate) fn login(input: Json<LoginForm>, ip: ClientIp) -> Result<Json<String>, LoginError> {
+	accounts::login(&input.email, &input.pass, input.role, ip).map(Json)
+}
+
+/// second step of logging in for accounts with TOTP
+#[post("/login/totp", format = "application/json", data = "<input>")]
+pub(crate) fn login_totp(input: Json<TotpLoginForm>, ip: ClientIp) -> Result<Json<String>, LoginError> {
+	accounts::login_totp(&input.challenge, &input.code, input.role, ip).map(Json)
+}
+
+/// generates a TOTP secret, it has to be confirmed with a code before it's used
+#[post("/2fa/enroll")]
+pub(crate) fn enroll_totp(info: MfaSetup) -> Result<Json<Value>, Status> {
+	let (secret, uri) = accounts::enroll_totp(info.0.account)?;
+	Ok(Json(json!({ "secret": secret, "uri": uri })))
+}
+
+/// turns TOTP on, returns recovery codes
+#[post("/2fa/confirm", format = "application/json", data = "<input>")]
+pub(crate) fn confirm_totp(input: Json<String>, info: MfaSetup) -> Result<Json<Vec<String>>, Status> {
+	accounts::confirm_totp(info.0.account, &input).map(Json)
+}
+
+#[post("/2fa/disable", format = "application/json", data = "<input>")]
+pub(crate) fn disable_totp(input: Json<String>, info: AuthToken) -> Result<(), Status> {
+	accounts::disable_totp(info.account, &input)
+}
+
+#[post("/login_student", format = "application/json", data = "<input>")]
+pub(crate) fn login_student(input: Json<LoginForm>, ip: ClientIp) -> Result<Json<String>, LoginError> {
+	accounts::login(&input.email, &input.pass, Some(Role::Student), ip).map(Json)
 }
 
 #[post("/login_teacher", format = "application/json", data = "<input>")]
-pub(crate) fn login_teacher(input: Json<LoginForm>, db: Database<Teacher>) -> Option<Json<String>> {
-	println!("{:?}", input);
-	println!("{}", db.read().iter().count());
+pub(crate) fn login_teacher(input: Json<LoginForm>, ip: ClientIp) -> Result<Json<String>, LoginError> {
+	accounts::login(&input.email, &input.pass, Some(Role::Teacher), ip).map(Json)
+}
+
+/// sends a password reset link, answers the same whether the account exists or not
+#[post("/password/reset", format = "application/json", data = "<input>")]
+pub(crate) fn request_password_reset(input: Json<ResetRequestForm>) -> Status {
+	accounts::request_password_reset(&input.email);
+	Status::Accepted
+}
+
+#[post("/password/reset/confirm", format = "application/json", data = "<input>")]
+pub(crate) fn reset_password(input: Json<ResetForm>) -> Result<(), Status> {
+	accounts::reset_password(&input.token, &input.pass).ok_or(Status::BadRequest)
+}
+
+/// sends the verification link again
+#[post("/email/verify")]
+pub(crate) fn resend_verification(db: Database<Account>, info: AuthToken) -> Result<(), Status> {
+	let account = db.read().get(info.account).ok_or(Status::NotFound)?;
+	if account.verified {
+		return Err(Status::Conflict);
+	}
+
+	accounts::send_verification(&account).ok_or(Status::InternalServerError)
+}
+
+#[post("/email/verify/confirm", format = "application/json", data = "<input>")]
+pub(crate) fn verify_email(input: Json<String>) -> Result<(), Status> {
+	accounts::verify_email(&input).ok_or(Status::BadRequest)
+}
+
+/// lifts a login lockout of an email or a client address
+#[post("/admin/unlock", format = "application/json", data = "<input>")]
+pub(crate) fn unlock_login(input: Json<UnlockForm>, _admin: AdminAuth) -> Option<()> {
+	ratelimit::unlock(input.email.as_deref(), input.ip)
+}
 
-	let u = db
+#[post("/admin/register_admin", format = "application/json", data = "<input>")]
+pub(crate) fn register_admin(input: Json<NewAdmin>, _admin: AdminAuth) -> Result<(), Status> {
+	accounts::create_admin(input.into_inner()).map(|_| ())
+}
+
+#[get("/admin/accounts")]
+pub(crate) fn list_accounts(db: Database<Account>, _admin: AdminAuth) -> Json<Vec<AccountView>> {
+	Json(db
 		.read()
 		.iter()
-		.inspect(|e| println!("{:?}", e))
-		.find_map(|(_, x)| if x.email == input.email && x.pass == input.pass {
-			Some(x)
-		} else { None })?;
+		.map(|(_, x)| AccountView::from(x))
+		.collect::<Vec<_>>())
+}
+
+/// disabled accounts can't log in and their tokens stop working immediately
+#[post("/admin/accounts/<id>/disable")]
+pub(crate) fn disable_account(id: UuidParam, admin: AdminAuth) -> Result<(), Status> {
+	if *id == admin.0.account {
+		return Err(Status::Conflict);
+	}
+
+	accounts::set_disabled(*id, true)
+}
 
-	let header = json!({ "kid": u.id.to_hyphenated().to_string() });
-	let body = AuthToken::new(u.id, "teacher".to_string()).make();
+#[post("/admin/accounts/<id>/enable")]
+pub(crate) fn enable_account(id: UuidParam, _admin: AdminAuth) -> Result<(), Status> {
+	accounts::set_disabled(*id, false)
+}
+
+/// lifts a lockout, `password` also mails a reset link, `totp` removes TOTP
+#[post("/admin/accounts/<id>/reset", format = "application/json", data = "<input>")]
+pub(crate) fn reset_account(id: UuidParam, input: Json<AdminResetForm>, _admin: AdminAuth) -> Result<(), Status> {
+	accounts::admin_reset(*id, input.password, input.totp)
+}
 
-	Some(Json(encode(header, &u.priv_key, &body, Algorithm::RS256).unwrap()))
+/// moves a student/teacher/admin record to this account
+#[post("/admin/accounts/<id>/roles", format = "application/json", data = "<input>")]
+pub(crate) fn assign_role(id: UuidParam, input: Json<AssignRoleForm>, _admin: AdminAuth) -> Result<(), Status> {
+	accounts::assign_role(*id, input.role, input.record)
 }
 
+#[get("/admin/policy")]
+pub(crate) fn get_policy(_admin: AdminAuth) -> Json<Policy> {
+	Json(policy::current())
+}
+
+#[post("/admin/policy", format = "application/json", data = "<input>")]
+pub(crate) fn set_policy(input: Json<Policy>, _admin: AdminAuth) -> Result<(), Status> {
+	policy::set(input.into_inner()).ok_or(Status::InternalServerError)
+}
+
+/// only teachers and admins create subjects, the formula and the categories
+/// have to be valid, the term and the scale have to exist
 #[post("/subject", format = "application/json", data = "<input>")]
-pub(crate) fn new_subject(input: Json<NewSubject>, mut _db: Database<Subject>, info: AuthToken) -> Option<()> {
+pub(crate) fn new_subject(input: Json<NewSubject>, mut _db: Database<Subject>, info: WriteAuth) -> Result<(), Status> {
+	if info.0.typ != "teacher" && info.0.typ != "admin" {
+		return Err(Status::Forbidden);
+	}
+	formula::parse(&input.grade_formula).map_err(|_| Status::BadRequest)?;
+	if !calc::valid_categories(&input.categories) || !input.bonus_rules.is_valid() {
+		return Err(Status::BadRequest);
+	}
+	let year = match input.term {
+		Some(term) => {
+			let term = Database::<Term>::open()
+				.ok_or(Status::InternalServerError)?
+				.read()
+				.get(term)
+				.ok_or(Status::NotFound)?;
+			Database::<SchoolYear>::open()
+				.ok_or(Status::InternalServerError)?
+				.read()
+				.get(term.year)
+				.map(|x| x.name)
+		}
+		None => None,
+	};
+	if let Some(scale) = input.scale {
+		Database::<GradingScale>::open()
+			.ok_or(Status::InternalServerError)?
+			.read()
+			.get(scale)
+			.ok_or(Status::NotFound)?;
+	}
+
 	Subject::create(input.clone())
-		.and_modify(|mut x| x.teacher = info.id)
+		.and_modify(|mut x| x.teacher = info.0.id)
+		.and_modify(|x| if let Some(year) = &year { x.year = year.clone() })
 		.save()
 		.map(|_| ())
-		.ok()
+		.map_err(|_| Status::InternalServerError)
 }
 
+/// replaces the grade categories of a subject, grades of removed
+/// categories count with the default weight
+#[post("/subject/<id>/categories", format = "application/json", data = "<input>")]
+pub(crate) fn set_categories(id: UuidParam, input: Json<Vec<Category>>, mut db: Database<Subject>, info: WriteAuth) -> Result<(), Status> {
+	let mut subject = db.read().get(*id).ok_or(Status::NotFound)?;
+	if info.0.typ != "admin" && subject.teacher != info.0.id {
+		return Err(Status::NotFound);
+	}
+	if !calc::valid_categories(&input) {
+		return Err(Status::BadRequest);
+	}
+
+	subject.categories = input.into_inner();
+	db.write()
+		.insert(subject.id, &subject)
+		.map(|_| ())
+		.map_err(|_| Status::InternalServerError)
+}
+
+/// regular grades have to be on the subject's scale, only the subject's
+/// teacher and admins can grade and only students attending the subject
 #[post("/grade", format = "application/json", data = "<input>")]
-pub(crate) fn new_grade(input: Json<NewGradeForm>, mut _db: Database<Grade>, _info: AuthToken) -> Option<()> {
-	println!("{:?}", input);
+pub(crate) fn new_grade(input: Json<NewGradeForm>, mut db: Database<Grade>, info: WriteAuth) -> Result<(), Status> {
+	let subject = Database::<Subject>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(input.subject)
+		.filter(|x| bulk::can_grade(x, &info.0))
+		.ok_or(Status::NotFound)?;
+	if !enrollment::is_approved(input.student, subject.id) {
+		return Err(Status::UnprocessableEntity);
+	}
+	if input.category.as_ref().is_some_and(|c| !subject.categories.iter().any(|x| x.name == *c))
+		|| input.weight.is_some_and(|w| !(w >= 0.0 && w.is_finite()))
+	{
+		return Err(Status::BadRequest);
+	}
+	let scale = scales::for_subject(&subject);
+	let val = scales::parse_value(scale.as_ref().map(|x| &x.kind), &input.typ, &input.number).ok_or(Status::BadRequest)?;
+
 	let new_grade = NewGrade {
 		name: input.name.clone(),
-		val: match input.typ.as_str() {
-			"Regular" => GradeVal::Regular(f32::from_str(&input.number).unwrap()),
-			"Bonus" => GradeVal::Bonus(i32::from_str(&input.number).unwrap()),
-			"Penalisation" => GradeVal::Penalisation(i32::from_str(&input.number).unwrap()),
-			_ => GradeVal::Regular(f32::from_str(&input.number).unwrap()),
-		},
+		val,
 		description: Some(input.description.clone()),
 		date: DateTime::<Utc>::from_utc(
 			NaiveDateTime::parse_from_str(
 					&format!("{} 0:0:0", input.date), 
 					"%Y-%m-%d %H:%M:%S")
-				.unwrap(),
+				.map_err(|_| Status::BadRequest)?,
 			Utc),
 		student: input.student.clone(),
 		subject: input.subject.clone(),
+		category: input.category.clone(),
+		weight: input.weight,
 	};
-	Grade::create(new_grade)
-		.save()
-		.map(|_| ())
-		.ok()
+	let term = terms::term_of(new_grade.date.naive_utc().date()).map(|x| x.id);
+	let (id, grade) = Grade::create(new_grade).and_modify(|x| x.term = term);
+	db.insert_indexed(&id, &grade)
+		.map_err(|_| Status::InternalServerError)?;
+	stats::invalidate(grade.subject);
+	Ok(())
+}
+
+/// one grade event for the whole class, nothing is written if any row is wrong
+#[post("/subject/<id>/grades", format = "application/json", data = "<input>")]
+pub(crate) fn bulk_grades(id: UuidParam, input: Json<BulkGradeForm>, info: WriteAuth) -> Result<Json<Vec<Uuid>>, BulkError> {
+	bulk::enter(*id, input.into_inner(), &info.0).map(Json)
 }
 
+/// asks to attend a subject, its teacher has to approve it
 #[post("/subject/sign_up", format = "application/json", data = "<input>")]
-pub(crate) fn sign_up(input: Json<Uuid>,  mut db: Database<Student>, info: AuthToken) -> Option<()> {
-	println!("{:?} {:?}", input, info);
-	let _ = db
-		.write()
-		.update::<_, Student, _>(info.id, |c| {
-			println!("{:?}", c);
-			c.map(|mut x| {
-				x.subjects.push(*input);
-				x
-			})
-		});
+pub(crate) fn sign_up(input: Json<Uuid>, info: WriteAuth) -> Result<Json<Enrollment>, Status> {
+	if info.0.typ != "student" {
+		return Err(Status::Forbidden);
+	}
+	enrollment::request(info.0.id, *input).map(Json)
+}
 
-	Some(())
+/// leaves a subject or cancels the request
+#[post("/subject/<id>/unenroll")]
+pub(crate) fn unenroll(id: UuidParam, info: WriteAuth) -> Result<Json<Enrollment>, Status> {
+	if info.0.typ != "student" {
+		return Err(Status::Forbidden);
+	}
+	enrollment::withdraw(info.0.id, *id).map(Json)
+}
+
+#[get("/subject/<id>/enrollments")]
+pub(crate) fn subject_enrollments(id: UuidParam, info: AuthToken) -> Result<Json<Vec<Enrollment>>, Status> {
+	enrollment::of_subject(*id, &info).map(Json)
+}
+
+/// enrollments of the current student
+#[get("/enrollments")]
+pub(crate) fn my_enrollments(info: AuthToken) -> Option<Json<Vec<Enrollment>>> {
+	if info.typ != "student" {
+		return None;
+	}
+	enrollment::of_student(info.id).map(Json)
+}
+
+/// enrolled students and grade events of a subject as a matrix
+#[get("/subject/<id>/gradebook")]
+pub(crate) fn gradebook(id: UuidParam, info: AuthToken) -> Result<Json<Gradebook>, Status> {
+	gradebook::for_subject(*id, &info).map(Json)
+}
+
+/// statistics of a subject overall, per grade event and per term
+#[get("/subject/<id>/stats")]
+pub(crate) fn subject_stats(id: UuidParam, info: AuthToken) -> Result<Json<SubjectStats>, Status> {
+	stats::for_subject(*id, &info).map(Json)
+}
+
+#[get("/stats/terms")]
+pub(crate) fn term_stats(_admin: AdminAuth) -> Option<Json<Vec<TermStats>>> {
+	stats::by_term().map(Json)
+}
+
+#[get("/stats/teachers")]
+pub(crate) fn teacher_stats(_admin: AdminAuth) -> Option<Json<Vec<TeacherStats>>> {
+	stats::by_teacher().map(Json)
+}
+
+/// what the current student needs on the planned grades to reach a mark
+#[post("/subject/<id>/projection", format = "application/json", data = "<input>")]
+pub(crate) fn projection(id: UuidParam, input: Json<ProjectionForm>, info: AuthToken) -> Result<Json<Projection>, Status> {
+	if info.typ != "student" {
+		return Err(Status::NotFound);
+	}
+	projection::project(*id, info.id, &input).map(Json)
+}
+
+#[post("/enrollments/<id>/approve")]
+pub(crate) fn approve_enrollment(id: UuidParam, info: WriteAuth) -> Result<Json<Enrollment>, Status> {
+	enrollment::approve(*id, &info.0).map(Json)
+}
+
+#[post("/enrollments/<id>/reject")]
+pub(crate) fn reject_enrollment(id: UuidParam, info: WriteAuth) -> Result<Json<Enrollment>, Status> {
+	enrollment::reject(*id, &info.0).map(Json)
 }
@@ -0,0 +1,352 @@
+//! Zápis do předmětů
+//!
+//! Students ask to attend a subject and its teacher approves or rejects
+//! them. Subjects can have a capacity, requests to a full subject go to
+//! a waitlist and the oldest one is moved back to the teacher when
+//! somebody leaves. `Student::subjects` lists the approved subjects.
+use crate::auth::AuthToken;
+use crate::db::Database;
+use crate::models::{Enrollment, EnrollmentStatus, Student, Subject};
+
+use uuid::Uuid;
+use chrono::Utc;
+use rocket::http::Status;
+
+use std::sync::Mutex;
+use std::collections::HashSet;
+
+lazy_static! {
+	/// capacity checks and status changes must not interleave
+	static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn open() -> Result<Database<Enrollment>, Status> {
+	Database::<Enrollment>::open().ok_or(Status::InternalServerError)
+}
+
+fn approved_count(db: &Database<Enrollment>, subject: Uuid) -> usize {
+	db.find("subject", &subject)
+		.into_iter()
+		.filter(|x| x.status == EnrollmentStatus::Approved)
+		.count()
+}
+
+/// the enrollment of the student that is still in progress
+fn active(db: &Database<Enrollment>, student: Uuid, subject: Uuid) -> Option<Enrollment> {
+	db.find("student", &student)
+		.into_iter()
+		.find(|x| x.subject == subject && x.status.is_active())
+}
+
+fn is_full(db: &Database<Enrollment>, subject: &Subject) -> bool {
+	subject.capacity.is_some_and(|cap| approved_count(db, subject.id) >= cap as usize)
+}
+
+fn subject(id: Uuid) -> Result<Subject, Status> {
+	Database::<Subject>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(id)
+		.ok_or(Status::NotFound)
+}
+
+/// keeps `Student::subjects` in line with approved enrollments
+fn set_subject(student: Uuid, subject: Uuid, attends: bool) -> Result<(), Status> {
+	Database::<Student>::open()
+		.ok_or(Status::InternalServerError)?
+		.write()
+		.update::<_, Student, _>(student, |c| c.map(|mut x| {
+			x.subjects.retain(|s| *s != subject);
+			if attends {
+				x.subjects.push(subject);
+			}
+			x
+		}))
+		.map(|_| ())
+		.map_err(|_| Status::InternalServerError)
+}
+
+fn save(db: &mut Database<Enrollment>, mut enrollment: Enrollment, status: EnrollmentStatus) -> Result<Enrollment, Status> {
+	enrollment.status = status;
+	enrollment.updated = Utc::now();
+	db.insert_indexed(&enrollment.id, &enrollment)
+		.map_err(|_| Status::InternalServerError)?;
+	Ok(enrollment)
+}
+
+/// asks for a subject, goes to the waitlist if the subject is full
+pub fn request(student: Uuid, subject_id: Uuid) -> Result<Enrollment, Status> {
+	let subject = subject(subject_id)?;
+	let _lock = LOCK.lock().unwrap();
+	let mut db = open()?;
+
+	if active(&db, student, subject.id).is_some() {
+		return Err(Status::Conflict);
+	}
+
+	let now = Utc::now();
+	let enrollment = Enrollment {
+		id: Uuid::new_v4(),
+		student,
+		subject: subject.id,
+		status: EnrollmentStatus::Requested,
+		requested: now,
+		updated: now,
+	};
+	let status = if is_full(&db, &subject) { EnrollmentStatus::Waitlisted } else { EnrollmentStatus::Requested };
+	save(&mut db, enrollment, status)
+}
+
+/// enrolls the student right away, for invitations made by teachers;
+/// a full subject puts them on the waitlist like any other request
+pub fn enroll_approved(student: Uuid, subject_id: Uuid) -> Result<(), Status> {
+	let subject = subject(subject_id)?;
+	let _lock = LOCK.lock().unwrap();
+	let mut db = open()?;
+	let now = Utc::now();
+
+	let enrollment = active(&db, student, subject.id).unwrap_or(Enrollment {
+		id: Uuid::new_v4(),
+		student,
+		subject: subject.id,
+		status: EnrollmentStatus::Requested,
+		requested: now,
+		updated: now,
+	});
+	if enrollment.status == EnrollmentStatus::Approved {
+		return Ok(());
+	}
+	if is_full(&db, &subject) {
+		return save(&mut db, enrollment, EnrollmentStatus::Waitlisted).map(|_| ());
+	}
+
+	save(&mut db, enrollment, EnrollmentStatus::Approved)?;
+	set_subject(student, subject.id, true)
+}
+
+/// only the subject's teacher and admins decide about enrollments
+fn can_decide(subject: &Subject, info: &AuthToken) -> bool {
+	info.typ == "admin" || (info.typ == "teacher" && subject.teacher == info.id)
+}
+
+/// approves a requested or waitlisted enrollment, fails if the subject is full
+pub fn approve(id: Uuid, info: &AuthToken) -> Result<Enrollment, Status> {
+	let _lock = LOCK.lock().unwrap();
+	let mut db = open()?;
+	let enrollment = db.read().get(id).ok_or(Status::NotFound)?;
+	let subject = subject(enrollment.subject)?;
+
+	if !can_decide(&subject, info) {
+		return Err(Status::NotFound);
+	}
+	match enrollment.status {
+		EnrollmentStatus::Requested | EnrollmentStatus::Waitlisted => (),
+		_ => return Err(Status::Conflict),
+	}
+	if is_full(&db, &subject) {
+		return Err(Status::Conflict);
+	}
+
+	let enrollment = save(&mut db, enrollment, EnrollmentStatus::Approved)?;
+	set_subject(enrollment.student, enrollment.subject, true)?;
+	Ok(enrollment)
+}
+
+/// rejects a pending enrollment or removes an approved student
+pub fn reject(id: Uuid, info: &AuthToken) -> Result<Enrollment, Status> {
+	let _lock = LOCK.lock().unwrap();
+	let mut db = open()?;
+	let enrollment = db.read().get(id).ok_or(Status::NotFound)?;
+	let subject = subject(enrollment.subject)?;
+
+	if !can_decide(&subject, info) {
+		return Err(Status::NotFound);
+	}
+	if !enrollment.status.is_active() {
+		return Err(Status::Conflict);
+	}
+
+	leave(&mut db, enrollment, &subject, EnrollmentStatus::Rejected)
+}
+
+/// the student leaves the subject or cancels the request
+pub fn withdraw(student: Uuid, subject_id: Uuid) -> Result<Enrollment, Status> {
+	let subject = subject(subject_id)?;
+	let _lock = LOCK.lock().unwrap();
+	let mut db = open()?;
+
+	let enrollment = active(&db, student, subject.id).ok_or(Status::NotFound)?;
+
+	leave(&mut db, enrollment, &subject, EnrollmentStatus::Withdrawn)
+}
+
+/// ends an enrollment, a freed place goes to the oldest waitlisted request
+fn leave(db: &mut Database<Enrollment>, enrollment: Enrollment, subject: &Subject, status: EnrollmentStatus) -> Result<Enrollment, Status> {
+	let was_approved = enrollment.status == EnrollmentStatus::Approved;
+	let enrollment = save(db, enrollment, status)?;
+
+	if was_approved {
+		set_subject(enrollment.student, subject.id, false)?;
+
+		let next = db
+			.find("subject", &subject.id)
+			.into_iter()
+			.filter(|x| x.status == EnrollmentStatus::Waitlisted)
+			.min_by_key(|x| x.requested);
+		if let Some(next) = next.filter(|_| !is_full(db, subject)) {
+			save(db, next, EnrollmentStatus::Requested)?;
+		}
+	}
+
+	Ok(enrollment)
+}
+
+/// enrollments of a subject, for its teacher and admins
+pub fn of_subject(subject_id: Uuid, info: &AuthToken) -> Result<Vec<Enrollment>, Status> {
+	let subject = subject(subject_id)?;
+	if !can_decide(&subject, info) {
+		return Err(Status::NotFound);
+	}
+
+	Ok(open()?.find("subject", &subject.id))
+}
+
+/// enrollments of a student
+pub fn of_student(student: Uuid) -> Option<Vec<Enrollment>> {
+	Some(Database::<Enrollment>::open()?.find("student", &student))
+}
+
+/// whether the student was approved to attend the subject
+pub fn is_approved(student: Uuid, subject: Uuid) -> bool {
+	Database::<Enrollment>::open().is_some_and(|db| db
+		.find("student", &student)
+		.into_iter()
+		.any(|x| x.subject == subject && x.status == EnrollmentStatus::Approved))
+}
+
+/// subjects students signed up to before enrollments existed become approved
+/// enrollments, unknown subjects and duplicates are dropped; only records
+/// that need it are written, so running it again changes nothing
+pub fn migrate() {
+	let (mut enrollments, mut students, subjects) = match (
+		Database::<Enrollment>::open(),
+		Database::<Student>::open(),
+		Database::<Subject>::open(),
+	) {
+		(Some(e), Some(st), Some(su)) => (e, st, su),
+		_ => return,
+	};
+
+	let known = enrollments
+		.read()
+		.iter()
+		.map(|(_, x)| (x.student, x.subject))
+		.collect::<HashSet<_>>();
+	let all = students
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.collect::<Vec<_>>();
+
+	for mut student in all {
+		let mut subjects_of = student.subjects.clone();
+		subjects_of.sort();
+		subjects_of.dedup();
+		subjects_of.retain(|s| subjects.read().get(s).is_some());
+
+		for subject in subjects_of.iter().filter(|s| !known.contains(&(student.id, **s))) {
+			let now = Utc::now();
+			let enrollment = Enrollment {
+				id: Uuid::new_v4(),
+				student: student.id,
+				subject: *subject,
+				status: EnrollmentStatus::Approved,
+				requested: now,
+				updated: now,
+			};
+			let _ = enrollments.insert_indexed(&enrollment.id, &enrollment);
+		}
+
+		let mut before = student.subjects.clone();
+		before.sort();
+		if before != subjects_of {
+			student.subjects = subjects_of;
+			let _ = students.write().insert(student.id, &student);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+	use crate::models::{BonusRules, Kind};
+
+	fn subject(capacity: Option<u32>) -> Subject {
+		let subject = Subject {
+			id: Uuid::new_v4(),
+			name: String::new(),
+			description: String::new(),
+			year: String::new(),
+			grade_formula: String::new(),
+			kind: Kind::Other,
+			teacher: Uuid::nil(),
+			capacity,
+			term: None,
+			scale: None,
+			categories: vec![],
+			bonus_rules: BonusRules::default(),
+		};
+		Database::<Subject>::open().unwrap().write().insert(subject.id, &subject).unwrap();
+		subject
+	}
+
+	fn student(subjects: Vec<Uuid>) -> Student {
+		let student = Student {
+			id: Uuid::new_v4(),
+			account: Uuid::nil(),
+			name: String::new(),
+			email: String::new(),
+			subjects,
+			class: None,
+			pass: None,
+			pub_key: None,
+			priv_key: None,
+		};
+		Database::<Student>::open().unwrap().write().insert(student.id, &student).unwrap();
+		student
+	}
+
+	#[test]
+	fn invites_respect_capacity() {
+		testing::setup();
+		let subject = subject(Some(1));
+		let (first, second) = (student(vec![]), student(vec![]));
+
+		enroll_approved(first.id, subject.id).unwrap();
+		enroll_approved(second.id, subject.id).unwrap();
+		enroll_approved(first.id, subject.id).unwrap();
+
+		let db = open().unwrap();
+		assert_eq!(approved_count(&db, subject.id), 1);
+		assert!(is_approved(first.id, subject.id));
+		assert_eq!(active(&db, second.id, subject.id).map(|x| x.status), Some(EnrollmentStatus::Waitlisted));
+		assert!(Database::<Student>::open().unwrap().read().get(second.id).unwrap().subjects.is_empty());
+	}
+
+	#[test]
+	fn migration_runs_once() {
+		testing::setup();
+		let subject = subject(None);
+		let student = student(vec![subject.id, Uuid::new_v4(), subject.id]);
+
+		migrate();
+		let enrollments = of_student(student.id).unwrap();
+		assert_eq!(enrollments.len(), 1);
+		assert_eq!(Database::<Student>::open().unwrap().read().get(student.id).unwrap().subjects, vec![subject.id]);
+
+		migrate();
+		let again = of_student(student.id).unwrap();
+		assert_eq!(again.iter().map(|x| x.id).collect::<Vec<_>>(), enrollments.iter().map(|x| x.id).collect::<Vec<_>>());
+	}
+}
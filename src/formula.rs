@@ -0,0 +1,338 @@
+//! Vzorec pro výpočet známky
+//!
+//! `Subject::grade_formula` is an arithmetic expression over variables that
+//! describe the student's grades, e.g. `round(avg)` or
+//! `min(5, avg + 0.5 * penalty)`. It supports numbers, `+ - * /`,
+//! parentheses, unary minus and the functions `min`, `max`, `round`,
+//! `floor`, `ceil`, `abs` and `clamp(x, lo, hi)`. An empty formula is `avg`.
+//!
+//! ```text
+//! expr   = term (("+" | "-") term)*
+//! term   = factor (("*" | "/") factor)*
+//! factor = "-" factor | "(" expr ")" | number | name | name "(" [expr ("," expr)*] ")"
+//! ```
+//!
+//! Operators are left associative, `*` and `/` bind tighter than `+` and
+//! `-`. The variables are filled in by [`calc::env`](crate::calc::env), an
+//! unknown one is an error rather than zero, and so is a result that is not
+//! a finite number. Formulas can nest at most [`MAX_DEPTH`] levels deep.
+use std::fmt;
+use std::collections::HashMap;
+
+/// a parsed formula
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+	Num(f64),
+	Var(String),
+	Neg(Box<Expr>),
+	Bin(Op, Box<Expr>, Box<Expr>),
+	Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+	Add,
+	Sub,
+	Mul,
+	Div,
+}
+
+/// why a formula can't be parsed or evaluated
+#[derive(Clone, Debug, PartialEq)]
+pub enum FormulaError {
+	/// unexpected character or token at the byte offset
+	Syntax(usize),
+	UnknownVariable(String),
+	UnknownFunction(String),
+	/// the function got a wrong number of arguments
+	Arity(String),
+	/// e.g. division by zero
+	NotANumber,
+	/// parentheses, unary minus, calls or operators nested deeper than [`MAX_DEPTH`]
+	TooDeep,
+}
+
+impl fmt::Display for FormulaError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			FormulaError::Syntax(at) => write!(f, "syntax error at {}", at),
+			FormulaError::UnknownVariable(v) => write!(f, "unknown variable {}", v),
+			FormulaError::UnknownFunction(v) => write!(f, "unknown function {}", v),
+			FormulaError::Arity(v) => write!(f, "wrong number of arguments for {}", v),
+			FormulaError::NotANumber => write!(f, "the result is not a number"),
+			FormulaError::TooDeep => write!(f, "the formula is nested too deep"),
+		}
+	}
+}
+
+/// how deep a formula can nest, parsing and evaluation recurse over it
+pub const MAX_DEPTH: usize = 64;
+
+/// values of the variables a formula can use
+pub type Env = HashMap<String, f64>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+	Num(f64),
+	Ident(String),
+	Sym(char),
+}
+
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, FormulaError> {
+	let mut tokens = vec![];
+	let mut chars = src.char_indices().peekable();
+
+	while let Some(&(at, c)) = chars.peek() {
+		if c.is_whitespace() {
+			chars.next();
+		} else if c.is_ascii_digit() || c == '.' {
+			let mut num = String::new();
+			while let Some(&(_, c)) = chars.peek().filter(|(_, c)| c.is_ascii_digit() || *c == '.') {
+				num.push(c);
+				chars.next();
+			}
+			tokens.push((at, Token::Num(num.parse().map_err(|_| FormulaError::Syntax(at))?)));
+		} else if c.is_alphabetic() || c == '_' {
+			let mut ident = String::new();
+			while let Some(&(_, c)) = chars.peek().filter(|(_, c)| c.is_alphanumeric() || *c == '_') {
+				ident.push(c);
+				chars.next();
+			}
+			tokens.push((at, Token::Ident(ident)));
+		} else if "+-*/(),".contains(c) {
+			tokens.push((at, Token::Sym(c)));
+			chars.next();
+		} else {
+			return Err(FormulaError::Syntax(at));
+		}
+	}
+
+	Ok(tokens)
+}
+
+struct Parser {
+	tokens: Vec<(usize, Token)>,
+	pos: usize,
+	len: usize,
+	depth: usize,
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos).map(|(_, t)| t)
+	}
+
+	fn at(&self) -> usize {
+		self.tokens.get(self.pos).map_or(self.len, |(at, _)| *at)
+	}
+
+	fn eat(&mut self, sym: char) -> bool {
+		if self.peek() == Some(&Token::Sym(sym)) {
+			self.pos += 1;
+			true
+		} else {
+			false
+		}
+	}
+
+	fn expect(&mut self, sym: char) -> Result<(), FormulaError> {
+		if self.eat(sym) { Ok(()) } else { Err(FormulaError::Syntax(self.at())) }
+	}
+
+	/// goes one level deeper into the tree, a chain of operators nests too
+	fn deeper(&mut self) -> Result<(), FormulaError> {
+		self.depth += 1;
+		if self.depth > MAX_DEPTH { Err(FormulaError::TooDeep) } else { Ok(()) }
+	}
+
+	fn expr(&mut self) -> Result<Expr, FormulaError> {
+		let depth = self.depth;
+		self.deeper()?;
+		let mut left = self.term()?;
+		loop {
+			let op = if self.eat('+') { Op::Add } else if self.eat('-') { Op::Sub } else { break };
+			self.deeper()?;
+			left = Expr::Bin(op, Box::new(left), Box::new(self.term()?));
+		}
+
+		self.depth = depth;
+		Ok(left)
+	}
+
+	fn term(&mut self) -> Result<Expr, FormulaError> {
+		let depth = self.depth;
+		let mut left = self.factor()?;
+		loop {
+			let op = if self.eat('*') { Op::Mul } else if self.eat('/') { Op::Div } else { break };
+			self.deeper()?;
+			left = Expr::Bin(op, Box::new(left), Box::new(self.factor()?));
+		}
+
+		self.depth = depth;
+		Ok(left)
+	}
+
+	fn factor(&mut self) -> Result<Expr, FormulaError> {
+		if self.eat('-') {
+			let depth = self.depth;
+			self.deeper()?;
+			let inner = self.factor()?;
+			self.depth = depth;
+			return Ok(Expr::Neg(Box::new(inner)));
+		}
+		if self.eat('(') {
+			let inner = self.expr()?;
+			self.expect(')')?;
+			return Ok(inner);
+		}
+
+		let at = self.at();
+		match self.tokens.get(self.pos).map(|(_, t)| t.clone()) {
+			Some(Token::Num(n)) => {
+				self.pos += 1;
+				Ok(Expr::Num(n))
+			}
+			Some(Token::Ident(name)) => {
+				self.pos += 1;
+				if !self.eat('(') {
+					return Ok(Expr::Var(name));
+				}
+
+				let mut args = vec![];
+				if !self.eat(')') {
+					loop {
+						args.push(self.expr()?);
+						if self.eat(')') {
+							break;
+						}
+						self.expect(',')?;
+					}
+				}
+				Ok(Expr::Call(name, args))
+			}
+			_ => Err(FormulaError::Syntax(at)),
+		}
+	}
+}
+
+/// parses a formula, an empty one means `avg`
+pub fn parse(src: &str) -> Result<Expr, FormulaError> {
+	if src.trim().is_empty() {
+		return Ok(Expr::Var("avg".to_string()));
+	}
+
+	let mut parser = Parser { tokens: tokenize(src)?, pos: 0, len: src.len(), depth: 0 };
+	let expr = parser.expr()?;
+
+	match parser.peek() {
+		None => Ok(expr),
+		Some(_) => Err(FormulaError::Syntax(parser.at())),
+	}
+}
+
+impl Expr {
+	/// evaluates the formula with the given variables
+	pub fn eval(&self, env: &Env) -> Result<f64, FormulaError> {
+		let value = match self {
+			Expr::Num(n) => *n,
+			Expr::Var(name) => *env.get(name).ok_or_else(|| FormulaError::UnknownVariable(name.clone()))?,
+			Expr::Neg(e) => -e.eval(env)?,
+			Expr::Bin(op, a, b) => {
+				let (a, b) = (a.eval(env)?, b.eval(env)?);
+				match op {
+					Op::Add => a + b,
+					Op::Sub => a - b,
+					Op::Mul => a * b,
+					Op::Div => a / b,
+				}
+			}
+			Expr::Call(name, args) => {
+				let args = args.iter().map(|a| a.eval(env)).collect::<Result<Vec<_>, _>>()?;
+				call(name, &args)?
+			}
+		};
+
+		if value.is_finite() { Ok(value) } else { Err(FormulaError::NotANumber) }
+	}
+
+	/// names of the variables the formula uses
+	pub fn variables(&self) -> Vec<&str> {
+		match self {
+			Expr::Num(_) => vec![],
+			Expr::Var(name) => vec![name.as_str()],
+			Expr::Neg(e) => e.variables(),
+			Expr::Bin(_, a, b) => a.variables().into_iter().chain(b.variables()).collect(),
+			Expr::Call(_, args) => args.iter().flat_map(|a| a.variables()).collect(),
+		}
+	}
+}
+
+fn call(name: &str, args: &[f64]) -> Result<f64, FormulaError> {
+	let arity = || FormulaError::Arity(name.to_string());
+
+	match (name, args) {
+		("min", [_, ..]) => Ok(args.iter().cloned().fold(f64::INFINITY, f64::min)),
+		("max", [_, ..]) => Ok(args.iter().cloned().fold(f64::NEG_INFINITY, f64::max)),
+		("round", [x]) => Ok(x.round()),
+		("floor", [x]) => Ok(x.floor()),
+		("ceil", [x]) => Ok(x.ceil()),
+		("abs", [x]) => Ok(x.abs()),
+		("clamp", [x, lo, hi]) => Ok(x.max(*lo).min(*hi)),
+		("min", _) | ("max", _) | ("round", _) | ("floor", _) | ("ceil", _) | ("abs", _) | ("clamp", _) => Err(arity()),
+		_ => Err(FormulaError::UnknownFunction(name.to_string())),
+	}
+}
+
+/// parses and evaluates a formula at once
+pub fn eval(src: &str, env: &Env) -> Result<f64, FormulaError> {
+	parse(src)?.eval(env)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn env(vars: &[(&str, f64)]) -> Env {
+		vars.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+	}
+
+	#[test]
+	fn precedence_and_functions() {
+		let e = env(&[("avg", 2.4), ("count", 5.0)]);
+
+		assert_eq!(eval("", &e), Ok(2.4));
+		assert_eq!(eval("1 + 2 * 3", &e), Ok(7.0));
+		assert_eq!(eval("(1 + 2) * 3", &e), Ok(9.0));
+		assert_eq!(eval("-avg + 10 / 4", &e), Ok(-2.4 + 2.5));
+		assert_eq!(eval("round(avg)", &e), Ok(2.0));
+		assert_eq!(eval("clamp(avg - 2, 1, 5)", &e), Ok(1.0));
+		assert_eq!(eval("max(1, count, 3)", &e), Ok(5.0));
+	}
+
+	#[test]
+	fn errors_are_reported() {
+		let e = env(&[("avg", 1.0)]);
+
+		assert_eq!(eval("1 +", &e), Err(FormulaError::Syntax(3)));
+		assert_eq!(eval("1 $ 2", &e), Err(FormulaError::Syntax(2)));
+		assert_eq!(eval("(1", &e), Err(FormulaError::Syntax(2)));
+		assert_eq!(eval("nope", &e), Err(FormulaError::UnknownVariable("nope".to_string())));
+		assert_eq!(eval("nope(1)", &e), Err(FormulaError::UnknownFunction("nope".to_string())));
+		assert_eq!(eval("round(1, 2)", &e), Err(FormulaError::Arity("round".to_string())));
+		assert_eq!(eval("avg / 0", &e), Err(FormulaError::NotANumber));
+	}
+
+	#[test]
+	fn nesting_is_limited() {
+		let e = env(&[("avg", 1.0)]);
+		let nested = |open: &str, close: &str, n: usize| format!("{}avg{}", open.repeat(n), close.repeat(n));
+
+		assert_eq!(eval(&nested("(", ")", 10), &e), Ok(1.0));
+		assert_eq!(eval(&nested("-", "", 10), &e), Ok(1.0));
+		assert_eq!(parse(&nested("(", ")", 100_000)), Err(FormulaError::TooDeep));
+		assert_eq!(parse(&nested("-", "", 100_000)), Err(FormulaError::TooDeep));
+		assert_eq!(parse(&nested("abs(", ")", 100_000)), Err(FormulaError::TooDeep));
+		assert_eq!(parse(&"avg + ".repeat(100_000)), Err(FormulaError::TooDeep));
+		assert_eq!(parse(&"avg * ".repeat(100_000)), Err(FormulaError::TooDeep));
+	}
+}
@@ -0,0 +1,138 @@
+//! Třídní kniha
+//!
+//! The teacher's view of a subject: enrolled students as rows and grade
+//! events as columns. Grades with the same name and date form a column,
+//! a cell can hold several grades (e.g. a grade and a bonus for the same
+//! test). Students and grades are found through the indexes of the
+//! subject, so the size of the school doesn't matter.
+use crate::calc::{self, Summary};
+use crate::auth::AuthToken;
+use crate::db::Database;
+use crate::models::{Enrollment, EnrollmentStatus, Grade, GradeVal, Student, Subject};
+
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use rocket::http::Status;
+
+use std::collections::BTreeMap;
+
+/// statistics of the regular grades in a column
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ColumnStats {
+	pub count: usize,
+	pub average: Option<f64>,
+	pub lowest: Option<f64>,
+	pub highest: Option<f64>,
+}
+
+/// a grade event, e.g. one test
+#[derive(Clone, Debug, Serialize)]
+pub struct Column {
+	pub name: String,
+	pub date: DateTime<Utc>,
+	pub category: Option<String>,
+	pub stats: ColumnStats,
+}
+
+/// a student with their grades in every column
+#[derive(Clone, Debug, Serialize)]
+pub struct Row {
+	pub student: Uuid,
+	pub name: String,
+	/// grades in the same order as the columns
+	pub cells: Vec<Vec<Grade>>,
+	#[serde(flatten)]
+	pub summary: Summary,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Gradebook {
+	pub subject: Subject,
+	pub columns: Vec<Column>,
+	pub rows: Vec<Row>,
+}
+
+fn stats<'a>(grades: impl Iterator<Item = &'a Grade>) -> ColumnStats {
+	let values = grades
+		.filter_map(|x| match x.val {
+			GradeVal::Regular(v) => Some(f64::from(v)),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+
+	if values.is_empty() {
+		return ColumnStats::default();
+	}
+	ColumnStats {
+		count: values.len(),
+		average: Some(values.iter().sum::<f64>() / values.len() as f64),
+		lowest: values.iter().cloned().reduce(f64::min),
+		highest: values.iter().cloned().reduce(f64::max),
+	}
+}
+
+/// the gradebook of a subject, for its teacher and admins
+pub fn for_subject(subject: Uuid, info: &AuthToken) -> Result<Gradebook, Status> {
+	let subject = Database::<Subject>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(subject)
+		.ok_or(Status::NotFound)?;
+	if info.typ != "admin" && !(info.typ == "teacher" && subject.teacher == info.id) {
+		return Err(Status::NotFound);
+	}
+
+	let students_db = Database::<Student>::open().ok_or(Status::InternalServerError)?;
+	let mut students = Database::<Enrollment>::open()
+		.ok_or(Status::InternalServerError)?
+		.find("subject", &subject.id)
+		.into_iter()
+		.filter(|x| x.status == EnrollmentStatus::Approved)
+		.filter_map(|x| students_db.read().get(x.student))
+		.collect::<Vec<_>>();
+	students.sort_by(|a, b| a.name.cmp(&b.name));
+
+	// only grades of enrolled students, grades of those who left stay hidden
+	let grades = Database::<Grade>::open()
+		.ok_or(Status::InternalServerError)?
+		.find("subject", &subject.id)
+		.into_iter()
+		.filter(|x| students.iter().any(|s| s.id == x.student))
+		.collect::<Vec<_>>();
+
+	let mut events = BTreeMap::<(DateTime<Utc>, String), Vec<&Grade>>::new();
+	for grade in &grades {
+		events.entry((grade.date, grade.name.clone())).or_default().push(grade);
+	}
+
+	let columns = events
+		.iter()
+		.map(|((date, name), grades)| Column {
+			name: name.clone(),
+			date: *date,
+			category: grades.iter().find_map(|x| x.category.clone()),
+			stats: stats(grades.iter().cloned()),
+		})
+		.collect();
+
+	let rows = students
+		.into_iter()
+		.map(|student| {
+			let own = grades.iter().filter(|x| x.student == student.id).cloned().collect::<Vec<_>>();
+			let cells = events
+				.values()
+				.map(|column| column.iter().filter(|x| x.student == student.id).map(|x| (*x).clone()).collect())
+				.collect();
+
+			Row {
+				student: student.id,
+				name: student.name,
+				cells,
+				summary: calc::summarize(&subject, &own),
+			}
+		})
+		.collect();
+
+	Ok(Gradebook { subject, columns, rows })
+}
@@ -0,0 +1,132 @@
+//! Zákonní zástupci
+//!
+//! Guardians ask to be linked to a student by the student's email. The link
+//! only starts working after the student or an admin approves it, from then
+//! on the guardian can read the student's subjects and grades. A student can
+//! have several guardians and a guardian several students.
+use crate::overview::{self, SubjectOverview};
+use crate::accounts;
+use crate::auth::AuthToken;
+use crate::db::Database;
+use crate::models::{Account, GuardianLink, LinkStatus, Role, Student};
+
+use uuid::Uuid;
+use chrono::Utc;
+use rocket::http::Status;
+
+/// asks for a link to the student with the given email; the answer is the
+/// same whether the student exists or not, so that guardians can't probe
+/// for accounts, only the student's approval tells that the link works
+pub fn request_link(guardian: Uuid, student_email: &str) -> Result<(), Status> {
+	let accounts = Database::<Account>::open().ok_or(Status::InternalServerError)?;
+	let student = match accounts::find_by_email(&accounts, student_email)
+		.and_then(|x| x.roles.get(&Role::Student).cloned())
+	{
+		Some(student) => student,
+		None => return Ok(()),
+	};
+
+	let mut db = Database::<GuardianLink>::open().ok_or(Status::InternalServerError)?;
+	if db.read().iter().any(|(_, x)| x.guardian == guardian && x.student == student) {
+		return Ok(());
+	}
+
+	let link = GuardianLink {
+		id: Uuid::new_v4(),
+		guardian,
+		student,
+		status: LinkStatus::Pending,
+		requested: Utc::now(),
+		approved_by: None,
+	};
+	db.write().insert(link.id, &link).map(|_| ()).map_err(|_| Status::InternalServerError)
+}
+
+/// whether the user is the guardian, the student, or an admin
+fn party_to(link: &GuardianLink, info: &AuthToken) -> bool {
+	match info.typ.as_str() {
+		"admin" => true,
+		"guardian" => link.guardian == info.id,
+		"student" => link.student == info.id,
+		_ => false,
+	}
+}
+
+/// links the user is a party to, admins see all of them
+pub fn links(info: &AuthToken) -> Option<Vec<GuardianLink>> {
+	Some(Database::<GuardianLink>::open()?
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.filter(|x| party_to(x, info))
+		.collect())
+}
+
+/// approves a link, only the student or an admin can do it
+pub fn approve(id: Uuid, info: &AuthToken) -> Result<(), Status> {
+	let mut db = Database::<GuardianLink>::open().ok_or(Status::InternalServerError)?;
+	let mut link = db.read().get(id).filter(|x| party_to(x, info)).ok_or(Status::NotFound)?;
+
+	if info.typ == "guardian" {
+		return Err(Status::Forbidden);
+	}
+
+	link.status = LinkStatus::Approved;
+	link.approved_by = Some(info.account);
+	db.write().insert(link.id, &link).map(|_| ()).map_err(|_| Status::InternalServerError)
+}
+
+/// rejects a pending link or removes an approved one, any party can do it
+pub fn remove(id: Uuid, info: &AuthToken) -> Result<(), Status> {
+	let mut db = Database::<GuardianLink>::open().ok_or(Status::InternalServerError)?;
+	db.read().get(id).filter(|x| party_to(x, info)).ok_or(Status::NotFound)?;
+
+	db.write().delete(id).map(|_| ()).map_err(|_| Status::InternalServerError)
+}
+
+/// whether the guardian can see the student
+pub fn is_linked(guardian: Uuid, student: Uuid) -> bool {
+	Database::<GuardianLink>::open().is_some_and(|db| db
+		.read()
+		.iter()
+		.any(|(_, x)| x.guardian == guardian && x.student == student && x.status == LinkStatus::Approved))
+}
+
+/// students with an approved link to the guardian
+pub fn students(guardian: Uuid) -> Option<Vec<Student>> {
+	let students = Database::<Student>::open()?;
+
+	Some(Database::<GuardianLink>::open()?
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.filter(|x| x.guardian == guardian && x.status == LinkStatus::Approved)
+		.filter_map(|x| students.read().get(x.student))
+		.collect())
+}
+
+/// overview of a linked student's subjects and grades
+pub fn grades(guardian: Uuid, student: Uuid) -> Result<Vec<SubjectOverview>, Status> {
+	if !is_linked(guardian, student) {
+		return Err(Status::NotFound);
+	}
+
+	overview::for_student(student)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn unknown_students_look_like_known_ones() {
+		testing::setup();
+		let guardian = Uuid::new_v4();
+
+		assert_eq!(request_link(guardian, "nobody@example.com"), Ok(()));
+		assert_eq!(request_link(guardian, "nobody@example.com"), Ok(()));
+		let links = Database::<GuardianLink>::open().unwrap();
+		assert!(!links.read().iter().any(|(_, x)| x.guardian == guardian));
+	}
+}
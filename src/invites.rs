@@ -0,0 +1,156 @@
+//! Pozvánky
+//!
+//! Students (and teachers) can only register with an invitation code.
+//! Teachers invite students into the subjects they teach, admins can invite
+//! anyone anywhere. A code can be limited in time and in the number of uses,
+//! and signs the new student up to its subjects.
+use crate::totp;
+use crate::config::CONFIG;
+use crate::auth::AuthToken;
+use crate::db::Database;
+use crate::models::{Invite, NewInvite, Role, Subject};
+
+use chrono::Utc;
+use rocket::http::Status;
+use openssl::rand::rand_bytes;
+
+use std::cell::Cell;
+
+/// creates an invite, teachers can only invite students into their own subjects
+/// and have to name at least one, so that the invite is tied to what they teach
+pub fn create(input: NewInvite, info: &AuthToken) -> Result<Invite, Status> {
+	match (info.typ.as_str(), input.role) {
+		("admin", Role::Student) | ("admin", Role::Teacher) => (),
+		("teacher", Role::Student) => {
+			if input.subjects.is_empty() {
+				return Err(Status::BadRequest);
+			}
+
+			let subjects = Database::<Subject>::open().ok_or(Status::InternalServerError)?;
+			let own = input.subjects
+				.iter()
+				.all(|id| subjects.read().get(id).is_some_and(|s| s.teacher == info.id));
+
+			if !own {
+				return Err(Status::Forbidden);
+			}
+		}
+		("admin", Role::Admin) => return Err(Status::BadRequest),
+		_ => return Err(Status::Forbidden),
+	}
+
+	let invite = Invite {
+		code: generate_code(),
+		role: input.role,
+		subjects: input.subjects,
+		class: input.class,
+		created_by: info.account,
+		created: Utc::now(),
+		expires: input.expires,
+		max_uses: input.max_uses,
+		uses: 0,
+	};
+	Database::<Invite>::open()
+		.ok_or(Status::InternalServerError)?
+		.write()
+		.insert(invite.code.clone(), &invite)
+		.map_err(|_| Status::InternalServerError)?;
+
+	Ok(invite)
+}
+
+/// link to the registration page with the code filled in
+pub fn link(invite: &Invite) -> String {
+	format!("{}/register?invite={}", CONFIG.public_url, invite.code)
+}
+
+fn generate_code() -> String {
+	let mut bytes = vec![0; 10];
+	rand_bytes(&mut bytes).expect("failed to generate an invite code");
+	totp::base32(&bytes)
+}
+
+fn normalize(code: &str) -> String {
+	code.trim().to_uppercase().replace('-', "")
+}
+
+fn usable(invite: &Invite, role: Role) -> bool {
+	invite.role == role
+		&& invite.expires.is_none_or(|e| e > Utc::now())
+		&& invite.max_uses.is_none_or(|max| invite.uses < max)
+}
+
+/// uses up one use of the code, returns the invite if it was valid for the role
+pub fn redeem(code: &str, role: Role) -> Option<Invite> {
+	let mut db = Database::<Invite>::open()?;
+	let redeemed = Cell::new(false);
+
+	// the closure may run several times if somebody else uses the code
+	// at the same time, only the last run counts
+	let updated = db
+		.write()
+		.update::<_, Invite, _>(normalize(code), |c| c.map(|mut x| {
+			redeemed.set(usable(&x, role));
+			if redeemed.get() {
+				x.uses += 1;
+			}
+			x
+		}))
+		.ok()??;
+
+	if !redeemed.get() {
+		return None;
+	}
+	serde_cbor::from_slice(&updated).ok()
+}
+
+/// gives the use back when registration failed after redeeming
+pub fn refund(code: &str) {
+	if let Some(mut db) = Database::<Invite>::open() {
+		let _ = db.write().update::<_, Invite, _>(normalize(code), |c| c.map(|mut x| {
+			x.uses = x.uses.saturating_sub(1);
+			x
+		}));
+	}
+}
+
+/// invites the user can see and revoke, admins see all of them
+pub fn visible_to(invite: &Invite, info: &AuthToken) -> bool {
+	info.typ == "admin" || invite.created_by == info.account
+}
+
+/// deletes an invite
+pub fn revoke(code: &str, info: &AuthToken) -> Result<(), Status> {
+	let mut db = Database::<Invite>::open().ok_or(Status::InternalServerError)?;
+	let invite = db.read().get(normalize(code)).ok_or(Status::NotFound)?;
+
+	if !visible_to(&invite, info) {
+		return Err(Status::NotFound);
+	}
+
+	db.write()
+		.delete(invite.code)
+		.map(|_| ())
+		.map_err(|_| Status::InternalServerError)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	use uuid::Uuid;
+
+	fn student_invite(subjects: Vec<Uuid>) -> NewInvite {
+		NewInvite { role: Role::Student, subjects, class: Some("4.A".to_string()), expires: None, max_uses: None }
+	}
+
+	#[test]
+	fn teachers_invite_into_their_own_subjects() {
+		testing::setup();
+		let teacher = AuthToken::new(Uuid::new_v4(), "teacher".to_string());
+
+		assert_eq!(create(student_invite(vec![]), &teacher).err(), Some(Status::BadRequest));
+		assert_eq!(create(student_invite(vec![Uuid::new_v4()]), &teacher).err(), Some(Status::Forbidden));
+	}
+}
@@ -0,0 +1,514 @@
+//! Modul pro správu podpisových klíčů
+//!
+//! By default all tokens are signed by one of a few server keys loaded
+//! from [`Config::server_key_dir`](crate::config::Config). The older mode,
+//! where every user signs their tokens with their own key, is kept around
+//! for migration: in it, keys are rotated periodically (and on demand)
+//! and a retired key stays valid for verification until the last token
+//! it could have signed expires. Switching to server keys retires
+//! all per-user keys the same way.
+//!
+//! Verifying a token used to mean opening both user tables and parsing
+//! a PEM key on every request. Parsed keys are now kept in a small
+//! LRU cache keyed by `kid`, which is invalidated whenever a key is
+//! retired or removed.
+use crate::auth::TOKEN_LIFETIME;
+use crate::config::{CONFIG, SigningMode};
+use crate::db::{Database, NewEntry};
+use crate::models::{NewUserKey, Student, Teacher, UserKey};
+
+use uuid::Uuid;
+use chrono::{Duration, Utc};
+use rejwt::{Algorithm, DecodingKey, EncodingKey, Jwk};
+
+use std::fs;
+use std::thread;
+use std::path::Path;
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+lazy_static! {
+	/// global cache of parsed verification keys
+	static ref KEY_CACHE: Mutex<KeyCache> = Mutex::new(KeyCache::new(CONFIG.key_cache_size));
+	/// server signing keys, sorted by `kid`
+	static ref SERVER_KEYS: ServerKeys = load_server_keys(&CONFIG.server_key_dir);
+	/// finding and creating the current key of a user must not interleave,
+	/// or two first logins at once would both create one
+	static ref USER_KEYS: Mutex<()> = Mutex::new(());
+}
+
+/// a parsed verification key together with its owner
+#[derive(Clone)]
+pub struct CachedKey {
+	/// the key itself
+	pub key: DecodingKey,
+	/// the only algorithm the key may be used with
+	pub algorithm: Algorithm,
+	/// user the key belongs to, `None` for server keys
+	pub owner: Option<Uuid>,
+	/// type of the user, "teacher" or "student"
+	pub typ: Option<String>,
+	/// timestamp after which the key must not be used anymore
+	pub valid_until: Option<i64>,
+}
+
+/// a key of the server itself
+struct ServerKey {
+	kid: String,
+	algorithm: Algorithm,
+	encoding: EncodingKey,
+	decoding: DecodingKey,
+	jwk: Jwk,
+}
+
+/// the server keys that could be loaded and why the others couldn't
+struct ServerKeys {
+	keys: Vec<ServerKey>,
+	errors: Vec<String>,
+}
+
+/// least recently used cache, evicts the entry
+/// that has not been touched for the longest time
+struct KeyCache {
+	capacity: usize,
+	tick: u64,
+	entries: HashMap<String, (u64, CachedKey)>,
+}
+
+impl KeyCache {
+	fn new(capacity: usize) -> Self {
+		Self { capacity: capacity.max(1), tick: 0, entries: HashMap::new() }
+	}
+
+	fn get(&mut self, kid: &str) -> Option<CachedKey> {
+		self.tick += 1;
+		let tick = self.tick;
+
+		self.entries.get_mut(kid).map(|entry| {
+			entry.0 = tick;
+			entry.1.clone()
+		})
+	}
+
+	fn insert(&mut self, kid: String, key: CachedKey) {
+		if self.entries.len() >= self.capacity && !self.entries.contains_key(&kid) {
+			let oldest = self.entries
+				.iter()
+				.min_by_key(|(_, (used, _))| *used)
+				.map(|(k, _)| k.clone());
+
+			if let Some(oldest) = oldest {
+				self.entries.remove(&oldest);
+			}
+		}
+
+		self.tick += 1;
+		self.entries.insert(kid, (self.tick, key));
+	}
+}
+
+/// finds the verification key for a `kid`, going to the database
+/// only if the key is not cached yet
+pub fn lookup(kid: &str) -> Option<CachedKey> {
+	if let Some(key) = SERVER_KEYS.keys.iter().find(|k| k.kid == kid) {
+		return Some(CachedKey {
+			key: key.decoding.clone(),
+			algorithm: key.algorithm,
+			owner: None,
+			typ: None,
+			valid_until: None,
+		});
+	}
+
+	let cached = KEY_CACHE.lock().expect("the key cache mutex has been poisoned").get(kid);
+
+	let key = match cached {
+		Some(key) => key,
+		None => {
+			let key = load(kid)?;
+			KEY_CACHE
+				.lock()
+				.expect("the key cache mutex has been poisoned")
+				.insert(kid.to_string(), key.clone());
+			key
+		}
+	};
+
+	match key.valid_until {
+		Some(until) if Utc::now().timestamp() > until => None,
+		_ => Some(key),
+	}
+}
+
+/// drops a key from the cache, must be called whenever a key changes
+pub fn invalidate(kid: Uuid) {
+	KEY_CACHE
+		.lock()
+		.expect("the key cache mutex has been poisoned")
+		.entries
+		.remove(&kid.to_hyphenated().to_string());
+}
+
+/// loads the server keys, panics if server signing is enabled
+/// and there is no usable key
+pub fn init() {
+	if CONFIG.signing_mode == SigningMode::Server && server_signing_key().is_none() {
+		let errors = SERVER_KEYS.errors.iter().map(|e| format!("\n  {}", e)).collect::<String>();
+		panic!(
+			"no usable signing key in {}, add a PEM private key or set SIGNING_MODE=user{}",
+			CONFIG.server_key_dir,
+			errors,
+		);
+	}
+}
+
+/// returns the key that should sign a token for the given user
+/// together with its `kid` and algorithm
+pub fn signing_key(owner: Uuid, typ: &str) -> Option<(String, EncodingKey, Algorithm)> {
+	match CONFIG.signing_mode {
+		SigningMode::Server => server_signing_key()
+			.map(|k| (k.kid.clone(), k.encoding.clone(), k.algorithm)),
+		SigningMode::PerUser => user_signing_key(owner, typ)
+			.map(|(kid, key)| (kid.to_hyphenated().to_string(), key, Algorithm::RS256)),
+	}
+}
+
+/// public parts of the server keys
+pub fn server_jwks() -> Vec<Jwk> {
+	SERVER_KEYS.keys.iter().map(|k| k.jwk.clone()).collect()
+}
+
+fn server_signing_key() -> Option<&'static ServerKey> {
+	match CONFIG.server_signing_kid {
+		Some(ref kid) => SERVER_KEYS.keys.iter().find(|k| &k.kid == kid),
+		None => SERVER_KEYS.keys.last(),
+	}
+}
+
+/// the keys of a user that haven't been retired, there should be just one
+fn current_keys(db: &Database<UserKey>, owner: Uuid) -> Vec<UserKey> {
+	db.find("owner", &owner)
+		.into_iter()
+		.filter(|k| k.retired.is_none())
+		.collect()
+}
+
+fn user_signing_key(owner: Uuid, typ: &str) -> Option<(Uuid, EncodingKey)> {
+	let _lock = USER_KEYS.lock().expect("the user key mutex has been poisoned");
+	let mut db = Database::<UserKey>::open()?;
+
+	let key = match current_keys(&db, owner).into_iter().max_by_key(|k| k.created) {
+		Some(k) => k,
+		None => {
+			let (kid, key) = UserKey::create(NewUserKey { owner, typ: typ.to_string() });
+			db.insert_indexed(&kid, &key).ok()?;
+			key
+		}
+	};
+
+	Some((key.kid, EncodingKey::from_rsa_pem(key.priv_key.as_bytes()).ok()?))
+}
+
+/// retires all current keys of a user and creates a new one,
+/// returns the new `kid`; there is nothing to rotate with server keys
+pub fn rotate(owner: Uuid, typ: &str) -> Option<Uuid> {
+	if CONFIG.signing_mode == SigningMode::Server {
+		return None;
+	}
+
+	let _lock = USER_KEYS.lock().expect("the user key mutex has been poisoned");
+	let mut db = Database::<UserKey>::open()?;
+	let (kid, key) = UserKey::create(NewUserKey { owner, typ: typ.to_string() });
+	db.insert_indexed(&kid, &key).ok()?;
+
+	for old in current_keys(&db, owner).into_iter().filter(|k| k.kid != kid) {
+		retire(&mut db, old.kid);
+	}
+
+	Some(kid)
+}
+
+fn retire(db: &mut Database<UserKey>, kid: Uuid) {
+	let now = Utc::now();
+	// the owner stays the same, so the index doesn't have to change
+	let _ = db
+		.write()
+		.update::<_, UserKey, _>(kid, |c| c.map(|mut x| {
+			x.retired = Some(now);
+			x
+		}));
+	invalidate(kid);
+}
+
+/// copies the key pairs users had before rotation into the key table under
+/// their user id, which is the `kid` of the tokens they signed; the keys are
+/// retired right away, so they verify those tokens until they expire
+pub fn migrate() {
+	let (mut keys, mut students, mut teachers) = match (
+		Database::<UserKey>::open(),
+		Database::<Student>::open(),
+		Database::<Teacher>::open(),
+	) {
+		(Some(k), Some(s), Some(t)) => (k, s, t),
+		_ => return,
+	};
+	let now = Utc::now();
+
+	let mut legacy = |owner: Uuid, typ: &str, pub_key: Option<String>, priv_key: Option<String>| {
+		let key = UserKey {
+			kid: owner,
+			owner,
+			typ: typ.to_string(),
+			pub_key: pub_key?,
+			priv_key: priv_key?,
+			created: now,
+			retired: Some(now),
+		};
+		if keys.read().get(owner).is_none() {
+			keys.insert_indexed(&owner, &key).ok()?;
+			invalidate(owner);
+		}
+		Some(())
+	};
+
+	let found = students
+		.read()
+		.iter()
+		.filter(|(_, x)| x.pub_key.is_some() || x.priv_key.is_some())
+		.map(|(id, x)| (id, legacy(id, "student", x.pub_key, x.priv_key)))
+		.collect::<Vec<_>>();
+	for (id, copied) in found {
+		// rewriting the record drops the keys from it
+		if copied.is_some() {
+			let _ = students.write().update::<_, Student, _>(id, |c| c);
+		}
+	}
+
+	let found = teachers
+		.read()
+		.iter()
+		.filter(|(_, x)| x.pub_key.is_some() || x.priv_key.is_some())
+		.map(|(id, x)| (id, legacy(id, "teacher", x.pub_key, x.priv_key)))
+		.collect::<Vec<_>>();
+	for (id, copied) in found {
+		if copied.is_some() {
+			let _ = teachers.write().update::<_, Teacher, _>(id, |c| c);
+		}
+	}
+}
+
+/// whether the key can still be used to verify tokens
+pub fn is_valid(key: &UserKey) -> bool {
+	match key.retired {
+		Some(retired) => (Utc::now() - retired).num_seconds() <= TOKEN_LIFETIME,
+		None => true,
+	}
+}
+
+/// starts a background thread which rotates old keys (or retires them
+/// when signing with server keys) and removes retired keys
+/// that can't verify any token anymore
+pub fn spawn_rotation() {
+	thread::spawn(|| loop {
+		rotate_expired();
+		thread::sleep(std::time::Duration::from_secs(CONFIG.key_rotation_interval_secs));
+	});
+}
+
+fn rotate_expired() {
+	let mut db = match Database::<UserKey>::open() {
+		Some(db) => db,
+		None => return,
+	};
+	let max_age = Duration::days(CONFIG.key_rotation_days);
+	let now = Utc::now();
+
+	let keys = db.read().iter().map(|(_, k)| k).collect::<Vec<_>>();
+	for key in keys {
+		if key.retired.is_none() && CONFIG.signing_mode == SigningMode::Server {
+			retire(&mut db, key.kid);
+		} else if key.retired.is_none() && now - key.created > max_age {
+			rotate(key.owner, &key.typ);
+		} else if !is_valid(&key) {
+			let _ = db.delete_indexed(&key.kid);
+			invalidate(key.kid);
+		}
+	}
+}
+
+fn load(kid: &str) -> Option<CachedKey> {
+	let kid = Uuid::parse_str(kid).ok()?;
+	let key = Database::<UserKey>::open()?.read().get(kid)?;
+
+	Some(CachedKey {
+		key: DecodingKey::from_rsa_pem(key.pub_key.as_bytes()).ok()?,
+		algorithm: Algorithm::RS256,
+		owner: Some(key.owner),
+		typ: Some(key.typ),
+		valid_until: key.retired.map(|r| r.timestamp() + TOKEN_LIFETIME),
+	})
+}
+
+fn load_server_keys(dir: &str) -> ServerKeys {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) => return ServerKeys { keys: vec![], errors: vec![format!("{}: {}", dir, e)] },
+	};
+
+	let mut keys = vec![];
+	let mut errors = vec![];
+	for path in entries
+		.filter_map(|e| e.ok())
+		.map(|e| e.path())
+		.filter(|p| p.extension().is_some_and(|ext| ext == "pem"))
+	{
+		match load_server_key(&path) {
+			Ok(key) => keys.push(key),
+			Err(e) => {
+				let error = format!("{}: {}", path.display(), e);
+				eprintln!("failed to load server key {}", error);
+				errors.push(error);
+			}
+		}
+	}
+
+	keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+	ServerKeys { keys, errors }
+}
+
+fn load_server_key(path: &Path) -> Result<ServerKey, String> {
+	let kid = path
+		.file_stem()
+		.and_then(|x| x.to_str())
+		.ok_or("the file name is not valid UTF-8")?
+		.to_string();
+	let pem = fs::read(path).map_err(|e| e.to_string())?;
+
+	let encoding = match CONFIG.server_key_passphrase {
+		Some(ref pass) => EncodingKey::from_encrypted_pem(&pem, pass.as_bytes()),
+		None => EncodingKey::from_pem(&pem),
+	}.map_err(|e| e.to_string())?;
+	let decoding = encoding.decoding_key().map_err(|e| e.to_string())?;
+	let jwk = Jwk::from_decoding_key(&decoding).map_err(|e| e.to_string())?.kid(kid.clone());
+
+	Ok(ServerKey {
+		algorithm: jwk.algorithm().map_err(|e| e.to_string())?,
+		kid,
+		encoding,
+		decoding,
+		jwk,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	use crate::auth::{self, AuthError};
+	use crate::db::DB;
+
+	use serde::Serialize;
+	use serde_json::json;
+	use openssl::rsa::Rsa;
+
+	/// a student as stored before key rotation
+	#[derive(Serialize)]
+	struct LegacyStudent {
+		id: Uuid,
+		name: String,
+		email: String,
+		subjects: Vec<Uuid>,
+		pass: String,
+		pub_key: String,
+		priv_key: String,
+	}
+
+	#[test]
+	fn broken_server_keys_are_reported() {
+		let dir = std::env::temp_dir().join(format!("grades-keys-{}", Uuid::new_v4()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("broken.pem"), "not a key").unwrap();
+
+		let loaded = load_server_keys(dir.to_str().unwrap());
+		fs::remove_dir_all(&dir).unwrap();
+
+		assert!(loaded.keys.is_empty());
+		assert_eq!(loaded.errors.len(), 1);
+		assert!(loaded.errors[0].contains("broken.pem: "));
+	}
+
+	#[test]
+	fn tokens_signed_with_legacy_keys_still_verify() {
+		testing::setup();
+		let rsa = Rsa::generate(2048).unwrap();
+		let student = LegacyStudent {
+			id: Uuid::new_v4(),
+			name: "Old Student".to_string(),
+			email: "old@example.com".to_string(),
+			subjects: vec![],
+			pass: String::new(),
+			pub_key: String::from_utf8(rsa.public_key_to_pem().unwrap()).unwrap(),
+			priv_key: String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap(),
+		};
+		DB.read()
+			.unwrap()
+			.open_tree("student")
+			.unwrap()
+			.insert(serde_cbor::to_vec(&student.id).unwrap(), serde_cbor::to_vec(&student).unwrap())
+			.unwrap();
+
+		// the way tokens were signed before rotation, `kid` is the user id
+		let token = rejwt::encode(
+			json!({ "kid": student.id.to_hyphenated().to_string() }),
+			&EncodingKey::from_rsa_pem(student.priv_key.as_bytes()).unwrap(),
+			&json!({ "iss": "Znamky", "exp": Utc::now().timestamp() + 60, "id": student.id, "typ": "student" }),
+			Algorithm::RS256,
+		)
+		.unwrap();
+		let header = format!("Bearer {}", token);
+		assert_eq!(auth::verify(Some(&header)).unwrap_err(), AuthError::UnknownKey);
+
+		migrate();
+		assert_eq!(auth::verify(Some(&header)).unwrap().id, student.id);
+
+		let key = Database::<UserKey>::open().unwrap().read().get(student.id).unwrap();
+		assert_eq!((key.owner, key.typ.as_str()), (student.id, "student"));
+		assert!(key.retired.is_some() && is_valid(&key));
+		let record = Database::<Student>::open().unwrap().read().get(student.id).unwrap();
+		assert!(record.pub_key.is_none() && record.priv_key.is_none());
+
+		// running it again changes nothing
+		migrate();
+		assert_eq!(auth::verify(Some(&header)).unwrap().id, student.id);
+	}
+
+	#[test]
+	fn current_key_is_found_through_the_owner_index() {
+		testing::setup();
+		let rsa = Rsa::generate(2048).unwrap();
+		let key = |owner, retired| UserKey {
+			kid: Uuid::new_v4(),
+			owner,
+			typ: "teacher".to_string(),
+			pub_key: String::from_utf8(rsa.public_key_to_pem().unwrap()).unwrap(),
+			priv_key: String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap(),
+			created: Utc::now(),
+			retired,
+		};
+		let owner = Uuid::new_v4();
+		let keys = [key(owner, None), key(owner, Some(Utc::now())), key(Uuid::new_v4(), None)];
+		let mut db = Database::<UserKey>::open().unwrap();
+		for k in &keys {
+			db.insert_indexed(&k.kid, k).unwrap();
+		}
+
+		let threads = (0..8)
+			.map(|_| thread::spawn(move || user_signing_key(owner, "teacher").unwrap().0))
+			.collect::<Vec<_>>();
+		for t in threads {
+			assert_eq!(t.join().unwrap(), keys[0].kid);
+		}
+		assert_eq!(db.find("owner", &owner).len(), 2);
+	}
+}
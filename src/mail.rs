@@ -0,0 +1,397 @@
+//! Odesílání emailů
+//!
+//! Everything goes through the [`Mailer`] trait, the transport is picked
+//! by [`Config::mail_transport`](crate::config::Config). Besides SMTP
+//! there is a transport that appends mails to a file or prints them,
+//! so that the flows can be tried out without a mail server.
+use crate::config::{CONFIG, MailTransport};
+
+use chrono::Utc;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+
+use std::fmt;
+use std::thread;
+use std::fs::OpenOptions;
+use std::time::Duration;
+use std::net::TcpStream;
+use std::io::{self, Read, Write};
+
+lazy_static! {
+	/// the configured transport
+	pub static ref MAILER: Box<dyn Mailer> = match CONFIG.mail_transport {
+		MailTransport::Smtp => Box::new(SmtpMailer {
+			host: CONFIG.smtp_host.clone(),
+			port: CONFIG.smtp_port,
+			credentials: CONFIG.smtp_user.clone().zip(CONFIG.smtp_pass.clone()),
+			starttls: CONFIG.smtp_starttls,
+		}),
+		MailTransport::File => Box::new(FileMailer { path: Some(CONFIG.mail_file.clone()) }),
+		MailTransport::Stdout => Box::new(FileMailer { path: None }),
+	};
+}
+
+/// a plain text email
+#[derive(Clone, Debug)]
+pub struct Mail {
+	/// recipient
+	pub to: String,
+	/// subject
+	pub subject: String,
+	/// plain text body
+	pub body: String,
+}
+
+impl Mail {
+	/// the whole message including headers, with CRLF line endings
+	pub fn to_message(&self, from: &str) -> String {
+		let body = base64::encode(self.body.replace("\r\n", "\n").replace('\n', "\r\n").as_bytes())
+			.as_bytes()
+			.chunks(76)
+			.map(|line| String::from_utf8_lossy(line).into_owned())
+			.collect::<Vec<_>>()
+			.join("\r\n");
+
+		format!(
+			"From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+			Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+			header_value(from),
+			header_value(&self.to),
+			encode_word(&header_value(&self.subject)),
+			Utc::now().to_rfc2822(),
+			body,
+		)
+	}
+}
+
+/// header values must not contain line breaks, or one could inject headers
+fn header_value(s: &str) -> String {
+	s.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+/// RFC 2047 encoded word for non-ASCII header values
+fn encode_word(s: &str) -> String {
+	if s.is_ascii() {
+		s.to_string()
+	} else {
+		format!("=?UTF-8?B?{}?=", base64::encode(s.as_bytes()))
+	}
+}
+
+/// why sending failed
+#[derive(Debug)]
+pub enum MailError {
+	/// connection problems
+	Io(io::Error),
+	/// the TLS handshake failed
+	Tls(String),
+	/// the server replied with an unexpected code
+	Smtp(u16, String),
+	/// credentials would have been sent over an unencrypted connection
+	Insecure,
+}
+
+impl fmt::Display for MailError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MailError::Io(e) => write!(f, "io error: {}", e),
+			MailError::Tls(e) => write!(f, "tls error: {}", e),
+			MailError::Smtp(code, msg) => write!(f, "smtp error {}: {}", code, msg),
+			MailError::Insecure => write!(f, "refusing to authenticate without STARTTLS"),
+		}
+	}
+}
+
+impl From<io::Error> for MailError {
+	fn from(e: io::Error) -> Self {
+		MailError::Io(e)
+	}
+}
+
+/// a way of delivering emails
+pub trait Mailer: Send + Sync {
+	/// delivers the mail, blocking until it's done
+	fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+/// sends the mail in the background, so that a request doesn't have to wait
+/// for the mail server (and its timing doesn't tell whether a mail was sent)
+pub fn send_later(mail: Mail) {
+	thread::spawn(move || {
+		if let Err(e) = MAILER.send(&mail) {
+			eprintln!("failed to send mail to {}: {}", mail.to, e);
+		}
+	});
+}
+
+/// appends mails to a file, or prints them if there is no file
+pub struct FileMailer {
+	/// where to write, `None` means stdout
+	pub path: Option<String>,
+}
+
+impl Mailer for FileMailer {
+	fn send(&self, mail: &Mail) -> Result<(), MailError> {
+		let text = format!(
+			"From: {}\nTo: {}\nSubject: {}\n\n{}\n\n",
+			CONFIG.mail_from, mail.to, mail.subject, mail.body,
+		);
+
+		match self.path {
+			Some(ref path) => OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(path)?
+				.write_all(text.as_bytes())?,
+			None => print!("{}", text),
+		}
+
+		Ok(())
+	}
+}
+
+/// a minimal SMTP client, enough to hand mails over to a relay
+pub struct SmtpMailer {
+	/// relay host
+	pub host: String,
+	/// relay port
+	pub port: u16,
+	/// user and password for `AUTH PLAIN`, only sent after `STARTTLS`
+	pub credentials: Option<(String, String)>,
+	/// whether to upgrade the connection with `STARTTLS`
+	pub starttls: bool,
+}
+
+enum Stream {
+	Plain(TcpStream),
+	Tls(SslStream<TcpStream>),
+}
+
+impl Read for Stream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Stream::Plain(s) => s.read(buf),
+			Stream::Tls(s) => s.read(buf),
+		}
+	}
+}
+
+impl Write for Stream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			Stream::Plain(s) => s.write(buf),
+			Stream::Tls(s) => s.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Stream::Plain(s) => s.flush(),
+			Stream::Tls(s) => s.flush(),
+		}
+	}
+}
+
+struct Connection(Stream);
+
+impl Connection {
+	fn line(&mut self) -> Result<String, MailError> {
+		let mut line = Vec::new();
+		let mut byte = [0];
+
+		while !line.ends_with(b"\r\n") {
+			if self.0.read(&mut byte)? == 0 {
+				return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+			}
+			line.push(byte[0]);
+		}
+
+		Ok(String::from_utf8_lossy(&line[..line.len() - 2]).into_owned())
+	}
+
+	/// reads a (possibly multiline) reply and checks its code
+	fn expect(&mut self, code: u16) -> Result<(), MailError> {
+		loop {
+			let line = self.line()?;
+			let got = line.get(..3).and_then(|c| c.parse::<u16>().ok()).unwrap_or(0);
+
+			if got != code {
+				return Err(MailError::Smtp(got, line));
+			}
+			if line.as_bytes().get(3) != Some(&b'-') {
+				return Ok(());
+			}
+		}
+	}
+
+	fn command(&mut self, command: &str, code: u16) -> Result<(), MailError> {
+		self.0.write_all(command.as_bytes())?;
+		self.0.write_all(b"\r\n")?;
+		self.expect(code)
+	}
+}
+
+impl Mailer for SmtpMailer {
+	fn send(&self, mail: &Mail) -> Result<(), MailError> {
+		if self.credentials.is_some() && !self.starttls {
+			return Err(MailError::Insecure);
+		}
+
+		let tcp = TcpStream::connect((self.host.as_str(), self.port))?;
+		tcp.set_read_timeout(Some(Duration::from_secs(30)))?;
+		tcp.set_write_timeout(Some(Duration::from_secs(30)))?;
+
+		let mut conn = Connection(Stream::Plain(tcp));
+		conn.expect(220)?;
+		conn.command("EHLO localhost", 250)?;
+
+		if self.starttls {
+			conn.command("STARTTLS", 220)?;
+			let tcp = match conn.0 {
+				Stream::Plain(tcp) => tcp,
+				Stream::Tls(_) => unreachable!("the connection is upgraded only once"),
+			};
+			let tls = SslConnector::builder(SslMethod::tls())
+				.map_err(|e| MailError::Tls(e.to_string()))?
+				.build()
+				.connect(&self.host, tcp)
+				.map_err(|e| MailError::Tls(e.to_string()))?;
+
+			conn = Connection(Stream::Tls(tls));
+			conn.command("EHLO localhost", 250)?;
+		}
+
+		if let Some((ref user, ref pass)) = self.credentials {
+			let plain = base64::encode(format!("\0{}\0{}", user, pass).as_bytes());
+			conn.command(&format!("AUTH PLAIN {}", plain), 235)?;
+		}
+
+		conn.command(&format!("MAIL FROM:<{}>", header_value(&CONFIG.mail_from)), 250)?;
+		conn.command(&format!("RCPT TO:<{}>", header_value(&mail.to)), 250)?;
+		conn.command("DATA", 354)?;
+		// the body is base64, so no line can start with a dot
+		conn.command(&format!("{}.", mail.to_message(&CONFIG.mail_from)), 250)?;
+		conn.command("QUIT", 221)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::net::TcpListener;
+	use std::io::{BufRead, BufReader};
+
+	/// accepts one mail and returns everything the client sent
+	fn fake_smtp_server(listener: TcpListener) -> thread::JoinHandle<Vec<String>> {
+		thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut writer = stream.try_clone().unwrap();
+			let mut reader = BufReader::new(stream);
+			let mut received = vec![];
+			let mut in_data = false;
+
+			writer.write_all(b"220 fake ESMTP\r\n").unwrap();
+			loop {
+				let mut line = String::new();
+				if reader.read_line(&mut line).unwrap() == 0 {
+					break;
+				}
+				let line = line.trim_end_matches("\r\n").to_string();
+				received.push(line.clone());
+
+				let reply: &[u8] = if in_data {
+					if line != "." {
+						continue;
+					}
+					in_data = false;
+					b"250 queued\r\n"
+				} else if line.starts_with("EHLO") {
+					b"250-fake\r\n250 AUTH PLAIN\r\n"
+				} else if line.starts_with("AUTH") {
+					b"235 ok\r\n"
+				} else if line == "DATA" {
+					in_data = true;
+					b"354 go ahead\r\n"
+				} else if line == "QUIT" {
+					writer.write_all(b"221 bye\r\n").unwrap();
+					break;
+				} else {
+					b"250 ok\r\n"
+				};
+				writer.write_all(reply).unwrap();
+			}
+
+			received
+		})
+	}
+
+	#[test]
+	fn smtp_mailer_talks_to_a_server() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = fake_smtp_server(listener);
+
+		let mailer = SmtpMailer {
+			host: "127.0.0.1".to_string(),
+			port,
+			credentials: None,
+			starttls: false,
+		};
+		mailer.send(&Mail {
+			to: "student@example.com".to_string(),
+			subject: "Známky".to_string(),
+			body: "hello\n.\nworld".to_string(),
+		}).unwrap();
+
+		let received = server.join().unwrap();
+		assert_eq!(received[0], "EHLO localhost");
+		assert_eq!(received[2], "RCPT TO:<student@example.com>");
+		assert!(received.contains(&"Subject: =?UTF-8?B?Wm7DoW1reQ==?=".to_string()));
+		assert!(received.contains(&base64::encode(b"hello\r\n.\r\nworld")));
+		assert_eq!(received.last().unwrap(), "QUIT");
+	}
+
+	#[test]
+	fn smtp_errors_are_reported() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			stream.write_all(b"554 go away\r\n").unwrap();
+		});
+
+		let mailer = SmtpMailer { host: "127.0.0.1".to_string(), port, credentials: None, starttls: false };
+		let mail = Mail { to: "x@example.com".to_string(), subject: String::new(), body: String::new() };
+		match mailer.send(&mail) {
+			Err(MailError::Smtp(554, _)) => (),
+			other => panic!("unexpected {:?}", other),
+		}
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn credentials_are_not_sent_in_plain_text() {
+		let mailer = SmtpMailer {
+			host: "127.0.0.1".to_string(),
+			port: 0,
+			credentials: Some(("user".to_string(), "pass".to_string())),
+			starttls: false,
+		};
+		let mail = Mail { to: "x@example.com".to_string(), subject: String::new(), body: String::new() };
+		match mailer.send(&mail) {
+			Err(MailError::Insecure) => (),
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn headers_cannot_be_injected() {
+		let mail = Mail {
+			to: "a@example.com\r\nBcc: everyone@example.com".to_string(),
+			subject: "hi\nBcc: everyone@example.com".to_string(),
+			body: String::new(),
+		};
+
+		assert!(!mail.to_message("me@example.com").contains("\r\nBcc:"));
+	}
+}
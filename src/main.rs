@@ -15,14 +15,44 @@ extern crate dotenv;
 extern crate serde;
 extern crate uuid;
 extern crate sled;
+extern crate base64;
+extern crate openssl;
 
 extern crate rejwt;
 
 mod db;
 mod auth;
+mod keys;
+mod config;
+mod accounts;
+mod mail;
+mod onetime;
+mod policy;
+mod invites;
+mod enrollment;
+mod formula;
+mod calc;
+mod terms;
+mod scales;
+mod overview;
+mod gradebook;
+mod bulk;
+mod stats;
+mod projection;
+mod template;
+mod pdf;
+mod reports;
+mod guardians;
+mod totp;
+mod ratelimit;
 mod models;
 mod endpoints;
+#[cfg(test)]
+mod testing;
 
+use std::io;
+use std::env;
+use std::process;
 use std::path::{PathBuf, Path};
 use rocket::response::NamedFile;
 
@@ -37,26 +67,149 @@ fn frontend(name: PathBuf) -> Option<NamedFile> {
 	NamedFile::open(Path::new("pkg/").join(name)).ok()
 }
 
+/// `grades create-admin <email> [name]`, the password is read from stdin,
+/// so that it doesn't end up in the shell history or the process list
+fn create_admin(args: &[String]) {
+	let email = match args {
+		[email, ..] => email.clone(),
+		_ => {
+			eprintln!("usage: grades create-admin <email> [name] < password");
+			process::exit(2);
+		}
+	};
+	let name = args.get(1).cloned().unwrap_or_else(|| "Administrator".to_string());
+
+	eprint!("password: ");
+	let mut line = String::new();
+	let pass = io::stdin()
+		.read_line(&mut line)
+		.ok()
+		.map(|_| line.trim_end_matches(&['\r', '\n'][..]).to_string())
+		.filter(|x| !x.is_empty());
+	let pass = match pass {
+		Some(pass) => pass,
+		None => {
+			eprintln!("failed to read the password");
+			process::exit(2);
+		}
+	};
+
+	match accounts::create_admin(models::NewAdmin { name, email, pass }) {
+		Ok(id) => println!("created admin account {}", id),
+		Err(e) => {
+			eprintln!("failed to create admin: {}", e);
+			process::exit(1);
+		}
+	}
+}
+
+/// indexes of data from before they existed, or written by older versions
+fn rebuild_indexes() {
+	let result = db::Database::<models::Grade>::rebuild_indexes()
+		.and_then(|_| db::Database::<models::Enrollment>::rebuild_indexes())
+		.and_then(|_| db::Database::<models::ReportNote>::rebuild_indexes())
+		.and_then(|_| db::Database::<models::UserKey>::rebuild_indexes());
+
+	if let Err(e) = result {
+		eprintln!("failed to rebuild indexes: {}", e);
+	}
+}
+
 fn main() {
 	dotenv::dotenv().ok();
 
+	let args = env::args().collect::<Vec<_>>();
+	if args.get(1).map(|x| x.as_str()) == Some("create-admin") {
+		return create_admin(&args[2..]);
+	}
+
+	keys::init();
+	// before accounts::migrate rewrites the records without the old keys
+	keys::migrate();
+	accounts::migrate();
+	enrollment::migrate();
+	rebuild_indexes();
+	accounts::bootstrap_admin();
+	keys::spawn_rotation();
+
 	rocket::ignite()
 		.mount("/", routes![
 			index,
 			frontend,
 			endpoints::me,
+			endpoints::my_overview,
+			endpoints::jwks,
+			endpoints::rotate_key,
 			endpoints::grades,
 			endpoints::subjects,
 			endpoints::teachers,
 			endpoints::students,
+			endpoints::login,
+			endpoints::login_totp,
+			endpoints::enroll_totp,
+			endpoints::confirm_totp,
+			endpoints::disable_totp,
 			endpoints::login_student,
 			endpoints::login_teacher,
+			endpoints::unlock_login,
+			endpoints::register_admin,
+			endpoints::list_accounts,
+			endpoints::disable_account,
+			endpoints::enable_account,
+			endpoints::reset_account,
+			endpoints::assign_role,
+			endpoints::get_policy,
+			endpoints::set_policy,
+			endpoints::request_password_reset,
+			endpoints::reset_password,
+			endpoints::resend_verification,
+			endpoints::verify_email,
 			endpoints::my_description,
 			endpoints::register_student,
 			endpoints::register_teacher,
+			endpoints::register_guardian,
+			endpoints::request_guardian_link,
+			endpoints::guardian_links,
+			endpoints::approve_guardian_link,
+			endpoints::remove_guardian_link,
+			endpoints::guardian_students,
+			endpoints::guardian_student_grades,
+			endpoints::school_years,
+			endpoints::new_school_year,
+			endpoints::terms,
+			endpoints::new_term,
+			endpoints::term_marks,
+			endpoints::scales,
+			endpoints::new_scale,
+			endpoints::create_invite,
+			endpoints::list_invites,
+			endpoints::revoke_invite,
 			endpoints::new_subject,
 			endpoints::new_grade,
+			endpoints::bulk_grades,
+			endpoints::set_categories,
 			endpoints::sign_up,
+			endpoints::unenroll,
+			endpoints::subject_enrollments,
+			endpoints::gradebook,
+			endpoints::subject_stats,
+			endpoints::term_stats,
+			endpoints::teacher_stats,
+			endpoints::projection,
+			endpoints::set_report_note,
+			endpoints::report_notes,
+			endpoints::report_card,
+			endpoints::class_report_cards,
+			endpoints::report_templates,
+			endpoints::new_report_template,
+			endpoints::my_enrollments,
+			endpoints::approve_enrollment,
+			endpoints::reject_enrollment,
+		])
+		.register(catchers![
+			auth::bad_request,
+			auth::unauthorized,
+			auth::forbidden,
 		])
 		.launch();
 }
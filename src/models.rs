@@ -3,10 +3,14 @@ use uuid::Uuid;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use std::fmt;
+use std::str::FromStr;
 use std::process::Command;
+use std::collections::BTreeMap;
 
 use crate::db::{
 	Table,
+	Indexed,
 	NewEntry,
 };
 
@@ -26,6 +30,15 @@ pub struct Grade {
 	pub date: DateTime<Utc>,
 	pub subject: Uuid,
 	pub student: Uuid,
+	/// name of one of the subject's [`Category`]s
+	#[serde(default)]
+	pub category: Option<String>,
+	/// overrides the weight of the category
+	#[serde(default)]
+	pub weight: Option<f32>,
+	/// the [`Term`] the grade was given in, set from `date` when it's written
+	#[serde(default)]
+	pub term: Option<Uuid>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -36,6 +49,12 @@ pub struct NewGrade {
 	pub date: DateTime<Utc>,
 	pub subject: Uuid,
 	pub student: Uuid,
+	/// name of one of the subject's [`Category`]s
+	#[serde(default)]
+	pub category: Option<String>,
+	/// overrides the weight of the category
+	#[serde(default)]
+	pub weight: Option<f32>,
 }
 
 impl Table for Grade {
@@ -47,6 +66,19 @@ impl Table for Grade {
 	}
 }
 
+impl Indexed for Grade {
+	fn indexes() -> &'static [&'static str] {
+		&["subject", "student"]
+	}
+
+	fn index_fields(value: &Self) -> Vec<(&'static str, Vec<u8>)> {
+		vec![
+			("subject", serde_cbor::to_vec(&value.subject).unwrap()),
+			("student", serde_cbor::to_vec(&value.student).unwrap()),
+		]
+	}
+}
+
 impl NewEntry for Grade {
 	type Input = NewGrade;
 	type Key = <Self as Table>::Key;
@@ -63,6 +95,9 @@ impl NewEntry for Grade {
 			date: src.date,
 			subject: src.subject,
 			student: src.student,
+			category: src.category,
+			weight: src.weight,
+			term: None,
 		})
 	}
 }
@@ -80,19 +115,91 @@ pub struct Subject {
 	pub id: Uuid,
 	pub name: String,
 	pub description: String,
+	/// name of the school year, only kept for older clients, use `term`;
+	/// it's filled in from the term for subjects that have one
 	pub year: String,
 	pub grade_formula: String,
 	pub kind: Kind,
 	pub teacher: Uuid,
+	/// maximum number of enrolled students, unlimited if not set
+	#[serde(default)]
+	pub capacity: Option<u32>,
+	/// the [`Term`] the subject is taught in, the whole year if not set
+	#[serde(default)]
+	pub term: Option<Uuid>,
+	/// the [`GradingScale`] of the subject, the school's default if not set
+	#[serde(default)]
+	pub scale: Option<Uuid>,
+	#[serde(default)]
+	pub categories: Vec<Category>,
+	#[serde(default)]
+	pub bonus_rules: BonusRules,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NewSubject {
 	pub name: String,
 	pub description: String,
+	/// ignored when `term` is set
+	#[serde(default)]
 	pub year: String,
 	pub grade_formula: String,
 	pub kind: Kind,
+	#[serde(default)]
+	pub capacity: Option<u32>,
+	#[serde(default)]
+	pub term: Option<Uuid>,
+	#[serde(default)]
+	pub scale: Option<Uuid>,
+	#[serde(default)]
+	pub categories: Vec<Category>,
+	#[serde(default)]
+	pub bonus_rules: BonusRules,
+}
+
+/// a kind of grades in a subject, e.g. tests or homework
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Category {
+	/// can be used in the grade formula, so only letters, digits and `_`
+	pub name: String,
+	/// weight of grades that don't have their own
+	pub weight: f32,
+}
+
+/// how points of bonuses or penalisations move the final mark
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PointRule {
+	/// every point moves the mark by `step`
+	Additive { step: f32 },
+	/// every full `points` points move the mark by `shift`
+	Threshold { points: u32, shift: f32 },
+}
+
+/// what `GradeVal::Bonus` and `GradeVal::Penalisation` do to the final mark,
+/// bonuses move it to the better end of the scale, penalisations to the worse
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BonusRules {
+	pub bonus: PointRule,
+	pub penalisation: PointRule,
+	/// the most bonuses can move the mark by
+	pub bonus_cap: Option<f32>,
+	/// the most penalisations can move the mark by
+	pub penalisation_cap: Option<f32>,
+	/// 1 is the best mark in Czech schools
+	pub lower_is_better: bool,
+}
+
+impl Default for BonusRules {
+	fn default() -> Self {
+		BonusRules {
+			bonus: PointRule::Additive { step: 0.1 },
+			penalisation: PointRule::Additive { step: 0.1 },
+			bonus_cap: Some(1.0),
+			penalisation_cap: Some(1.0),
+			lower_is_better: true,
+		}
+	}
 }
 
 impl Table for Subject {
@@ -120,21 +227,437 @@ impl NewEntry for Subject {
 			kind: src.kind,
 			teacher: Uuid::new_v4(),
 			name: src.name,
+			capacity: src.capacity,
+			term: src.term,
+			scale: src.scale,
+			categories: src.categories,
+			bonus_rules: src.bonus_rules,
+		})
+	}
+}
+
+
+/// a letter of a letter scale and the number it counts as
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Letter {
+	pub letter: String,
+	pub value: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScaleKind {
+	/// numbers from `min` to `max` in steps of `step`, e.g. 1-5 or percents
+	Numeric { min: f32, max: f32, step: f32 },
+	/// letters, stored and averaged as their values
+	Letter { letters: Vec<Letter> },
+	/// stored as `pass` or `fail`
+	PassFail { pass: f32, fail: f32 },
+}
+
+/// what grades of a subject can look like
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GradingScale {
+	pub id: Uuid,
+	pub name: String,
+	pub kind: ScaleKind,
+	/// the worst value that still passes, pass/fail scales don't need it
+	#[serde(default)]
+	pub pass_mark: Option<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewGradingScale {
+	pub name: String,
+	pub kind: ScaleKind,
+	#[serde(default)]
+	pub pass_mark: Option<f32>,
+}
+
+impl NewEntry for GradingScale {
+	type Input = NewGradingScale;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewGradingScale) -> (Uuid, GradingScale) {
+		let id = Uuid::new_v4();
+		(id, GradingScale {
+			id,
+			name: src.name,
+			kind: src.kind,
+			pass_mark: src.pass_mark,
 		})
 	}
 }
 
+impl Table for GradingScale {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"grading_scale"
+	}
+}
+
+/// a school year, e.g. 2019/20
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SchoolYear {
+	pub id: Uuid,
+	pub name: String,
+	pub start: NaiveDate,
+	/// the last day of the year
+	pub end: NaiveDate,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewSchoolYear {
+	pub name: String,
+	pub start: NaiveDate,
+	pub end: NaiveDate,
+}
+
+impl NewEntry for SchoolYear {
+	type Input = NewSchoolYear;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewSchoolYear) -> (Uuid, SchoolYear) {
+		let id = Uuid::new_v4();
+		(id, SchoolYear {
+			id,
+			name: src.name,
+			start: src.start,
+			end: src.end,
+		})
+	}
+}
+
+impl Table for SchoolYear {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"school_year"
+	}
+}
+
+/// a part of a school year, e.g. the first semester, terms don't overlap
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Term {
+	pub id: Uuid,
+	pub year: Uuid,
+	pub name: String,
+	pub start: NaiveDate,
+	/// the last day of the term
+	pub end: NaiveDate,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewTerm {
+	pub year: Uuid,
+	pub name: String,
+	pub start: NaiveDate,
+	pub end: NaiveDate,
+}
+
+impl NewEntry for Term {
+	type Input = NewTerm;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewTerm) -> (Uuid, Term) {
+		let id = Uuid::new_v4();
+		(id, Term {
+			id,
+			year: src.year,
+			name: src.name,
+			start: src.start,
+			end: src.end,
+		})
+	}
+}
+
+impl Table for Term {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"term"
+	}
+}
+
+impl Term {
+	/// whether the date falls into the term
+	pub fn contains(&self, date: NaiveDate) -> bool {
+		self.start <= date && date <= self.end
+	}
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrollmentStatus {
+	/// waiting for the teacher
+	Requested,
+	/// the subject was full, becomes requested when a place frees up
+	Waitlisted,
+	Approved,
+	Rejected,
+	/// the student left the subject
+	Withdrawn,
+}
+
+impl EnrollmentStatus {
+	/// whether the student can't ask for the subject again
+	pub fn is_active(self) -> bool {
+		match self {
+			EnrollmentStatus::Requested | EnrollmentStatus::Waitlisted | EnrollmentStatus::Approved => true,
+			EnrollmentStatus::Rejected | EnrollmentStatus::Withdrawn => false,
+		}
+	}
+}
+
+/// a teacher's comment and a student's absences in a subject, printed on
+/// the report card of the term
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReportNote {
+	pub id: Uuid,
+	pub term: Uuid,
+	pub subject: Uuid,
+	pub student: Uuid,
+	pub comment: String,
+	/// excused absent hours
+	pub excused: u32,
+	/// unexcused absent hours
+	pub unexcused: u32,
+	/// account that wrote the note last
+	pub author: Uuid,
+	pub updated: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewReportNote {
+	#[serde(default)]
+	pub comment: String,
+	#[serde(default)]
+	pub excused: u32,
+	#[serde(default)]
+	pub unexcused: u32,
+}
+
+impl Table for ReportNote {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"report_note"
+	}
+}
+
+impl Indexed for ReportNote {
+	fn indexes() -> &'static [&'static str] {
+		&["student"]
+	}
+
+	fn index_fields(value: &Self) -> Vec<(&'static str, Vec<u8>)> {
+		vec![("student", serde_cbor::to_vec(&value.student).unwrap())]
+	}
+}
+
+/// how report cards look, `html` is shown in the browser and `text` is
+/// printed to PDF
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReportTemplate {
+	pub id: Uuid,
+	pub name: String,
+	pub html: String,
+	pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewReportTemplate {
+	pub name: String,
+	pub html: String,
+	pub text: String,
+}
+
+impl NewEntry for ReportTemplate {
+	type Input = NewReportTemplate;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewReportTemplate) -> (Uuid, ReportTemplate) {
+		let id = Uuid::new_v4();
+		(id, ReportTemplate {
+			id,
+			name: src.name,
+			html: src.html,
+			text: src.text,
+		})
+	}
+}
+
+impl Table for ReportTemplate {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"report_template"
+	}
+}
+
+/// a student's request to attend a subject
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Enrollment {
+	pub id: Uuid,
+	pub student: Uuid,
+	pub subject: Uuid,
+	pub status: EnrollmentStatus,
+	pub requested: DateTime<Utc>,
+	/// when the status last changed
+	pub updated: DateTime<Utc>,
+}
+
+impl Table for Enrollment {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"enrollment"
+	}
+}
+
+impl Indexed for Enrollment {
+	fn indexes() -> &'static [&'static str] {
+		&["subject", "student"]
+	}
+
+	fn index_fields(value: &Self) -> Vec<(&'static str, Vec<u8>)> {
+		vec![
+			("subject", serde_cbor::to_vec(&value.subject).unwrap()),
+			("student", serde_cbor::to_vec(&value.student).unwrap()),
+		]
+	}
+}
+
+/// roles a person can have, one account can hold several of them
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	Student,
+	Teacher,
+	Admin,
+	Guardian,
+}
+
+impl fmt::Display for Role {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Role::Student => "student",
+			Role::Teacher => "teacher",
+			Role::Admin => "admin",
+			Role::Guardian => "guardian",
+		})
+	}
+}
+
+impl FromStr for Role {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"student" => Ok(Role::Student),
+			"teacher" => Ok(Role::Teacher),
+			"admin" => Ok(Role::Admin),
+			"guardian" => Ok(Role::Guardian),
+			_ => Err(()),
+		}
+	}
+}
+
+/// identity of a person, shared by all their roles
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Account {
+	pub id: Uuid,
+	pub email: String,
+	pub pass: String,
+	/// the record of each role, e.g. `Role::Teacher` -> id of the [`Teacher`]
+	pub roles: BTreeMap<Role, Uuid>,
+	pub created: DateTime<Utc>,
+	#[serde(default)]
+	pub verified: bool,
+	#[serde(default)]
+	pub totp: Option<Totp>,
+	/// disabled accounts can't log in and their tokens stop working
+	#[serde(default)]
+	pub disabled: bool,
+}
+
+/// TOTP second factor of an account
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Totp {
+	pub secret: Vec<u8>,
+	/// unconfirmed secrets are not asked for when logging in
+	pub confirmed: bool,
+	/// the last time step a code was used for, codes can't be reused
+	pub last_step: u64,
+	/// hashes of unused recovery codes
+	pub recovery_codes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewAccount {
+	pub email: String,
+	pub pass: String,
+}
+
+impl NewEntry for Account {
+	type Input = NewAccount;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewAccount) -> (Uuid, Account) {
+		let id = Uuid::new_v4();
+
+		(id.clone(), Account {
+			id,
+			email: src.email,
+			pass: src.pass,
+			roles: BTreeMap::new(),
+			created: Utc::now(),
+			verified: false,
+			totp: None,
+			disabled: false,
+		})
+	}
+}
+
+impl Table for Account {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"account"
+	}
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Teacher {
 	pub id: Uuid,
+	#[serde(default)]
+	pub account: Uuid,
 	pub name: String,
 	pub email: String,
 	pub info: String,
-	pub pass: String,
+	/// only present in records from before accounts, moved to [`Account`] on startup
+	#[serde(default, skip_serializing)]
+	pub pass: Option<String>,
 	pub subjects: Vec<Uuid>,
-	pub pub_key: String,
-	pub priv_key: String,
+	/// only present in records from before key rotation, moved to [`UserKey`] on startup
+	#[serde(default, skip_serializing)]
+	pub pub_key: Option<String>,
+	/// only present in records from before key rotation, moved to [`UserKey`] on startup
+	#[serde(default, skip_serializing)]
+	pub priv_key: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -142,6 +665,9 @@ pub struct NewTeacher {
 	pub name: String,
 	pub email: String,
 	pub pass: String,
+	/// code of an [`Invite`]
+	#[serde(default)]
+	pub invite: Option<String>,
 }
 
 impl NewEntry for Teacher {
@@ -151,24 +677,16 @@ impl NewEntry for Teacher {
 
 	fn create(src: NewTeacher) -> (Uuid, Teacher) {
 		let id = Uuid::new_v4();
-		let keys = String::from_utf8(
-			Command::new("nim/keymaster").output().expect("sad story").stdout,
-		)
-			.unwrap()
-			.split('|')
-			.map(|x| x.to_string())
-			.collect::<Vec<String>>();
-		let (priv_key, pub_key) = (keys[0].to_string(), keys[1].to_string());
-
 		(id.clone(), Teacher {
 			id,
+			account: Uuid::nil(),
 			name: src.name,
 			email: src.email,
 			info: String::new(),
 			subjects: vec![],
-			pass: src.pass,
-			pub_key,
-			priv_key,
+			pass: None,
+			pub_key: None,
+			priv_key: None,
 		})
 	}
 }
@@ -185,12 +703,22 @@ impl Table for Teacher {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Student {
 	pub id: Uuid,
+	#[serde(default)]
+	pub account: Uuid,
 	pub name: String,
 	pub email: String,
 	pub subjects: Vec<Uuid>,
-	pub pass: String,
-	pub pub_key: String,
-	pub priv_key: String,
+	#[serde(default)]
+	pub class: Option<String>,
+	/// only present in records from before accounts, moved to [`Account`] on startup
+	#[serde(default, skip_serializing)]
+	pub pass: Option<String>,
+	/// only present in records from before key rotation, moved to [`UserKey`] on startup
+	#[serde(default, skip_serializing)]
+	pub pub_key: Option<String>,
+	/// only present in records from before key rotation, moved to [`UserKey`] on startup
+	#[serde(default, skip_serializing)]
+	pub priv_key: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -198,6 +726,9 @@ pub struct NewStudent {
 	pub name: String,
 	pub email: String,
 	pub pass: String,
+	/// code of an [`Invite`]
+	#[serde(default)]
+	pub invite: Option<String>,
 }
 
 impl NewEntry for Student {
@@ -207,6 +738,224 @@ impl NewEntry for Student {
 
 	fn create(src: NewStudent) -> (Uuid, Student) {
 		let id = Uuid::new_v4();
+		(id.clone(), Student {
+			id,
+			account: Uuid::nil(),
+			name: src.name,
+			email: src.email,
+			subjects: vec![],
+			class: None,
+			pass: None,
+			pub_key: None,
+			priv_key: None,
+		})
+	}
+}
+
+impl Table for Student {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"student"
+	}
+}
+
+/// a school administrator
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Admin {
+	pub id: Uuid,
+	pub account: Uuid,
+	pub name: String,
+	pub email: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewAdmin {
+	pub name: String,
+	pub email: String,
+	pub pass: String,
+}
+
+impl NewEntry for Admin {
+	type Input = NewAdmin;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewAdmin) -> (Uuid, Admin) {
+		let id = Uuid::new_v4();
+		(id.clone(), Admin {
+			id,
+			account: Uuid::nil(),
+			name: src.name,
+			email: src.email,
+		})
+	}
+}
+
+impl Table for Admin {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"admin"
+	}
+}
+
+/// a parent or another guardian of students
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Guardian {
+	pub id: Uuid,
+	pub account: Uuid,
+	pub name: String,
+	pub email: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewGuardian {
+	pub name: String,
+	pub email: String,
+	pub pass: String,
+}
+
+impl NewEntry for Guardian {
+	type Input = NewGuardian;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewGuardian) -> (Uuid, Guardian) {
+		let id = Uuid::new_v4();
+		(id, Guardian {
+			id,
+			account: Uuid::nil(),
+			name: src.name,
+			email: src.email,
+		})
+	}
+}
+
+impl Table for Guardian {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"guardian"
+	}
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+	/// waiting for the student or an admin
+	Pending,
+	Approved,
+}
+
+/// a guardian can see the grades of a student once the link is approved
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GuardianLink {
+	pub id: Uuid,
+	pub guardian: Uuid,
+	pub student: Uuid,
+	pub status: LinkStatus,
+	pub requested: DateTime<Utc>,
+	/// account that approved the link
+	pub approved_by: Option<Uuid>,
+}
+
+impl Table for GuardianLink {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"guardian_link"
+	}
+}
+
+/// school-wide settings admins can change at runtime
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Policy {
+	/// teachers can't do anything but set up TOTP until they have it
+	pub require_teacher_2fa: bool,
+	/// [`GradingScale`] of subjects that don't have their own
+	#[serde(default)]
+	pub default_scale: Option<Uuid>,
+}
+
+impl Table for Policy {
+	type Key = String;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"policy"
+	}
+}
+
+/// an invitation to register with a role, created by a teacher or an admin
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invite {
+	pub code: String,
+	pub role: Role,
+	/// subjects the new student is signed up to
+	pub subjects: Vec<Uuid>,
+	pub class: Option<String>,
+	/// account that created the invite
+	pub created_by: Uuid,
+	pub created: DateTime<Utc>,
+	pub expires: Option<DateTime<Utc>>,
+	/// how many people can register with the code, unlimited if not set
+	pub max_uses: Option<u32>,
+	pub uses: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewInvite {
+	pub role: Role,
+	#[serde(default)]
+	pub subjects: Vec<Uuid>,
+	#[serde(default)]
+	pub class: Option<String>,
+	#[serde(default)]
+	pub expires: Option<DateTime<Utc>>,
+	#[serde(default)]
+	pub max_uses: Option<u32>,
+}
+
+impl Table for Invite {
+	type Key = String;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"invite"
+	}
+}
+
+/// a signing key of a user, users can have several of them,
+/// but only one that is not retired
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserKey {
+	pub kid: Uuid,
+	pub owner: Uuid,
+	pub typ: String,
+	pub pub_key: String,
+	pub priv_key: String,
+	pub created: DateTime<Utc>,
+	pub retired: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewUserKey {
+	pub owner: Uuid,
+	pub typ: String,
+}
+
+impl NewEntry for UserKey {
+	type Input = NewUserKey;
+	type Key = <Self as Table>::Key;
+	type Table = Self;
+
+	fn create(src: NewUserKey) -> (Uuid, UserKey) {
+		let kid = Uuid::new_v4();
 		let keys = String::from_utf8(
 			Command::new("nim/keymaster").output().expect("sad story").stdout,
 		)
@@ -216,23 +965,79 @@ impl NewEntry for Student {
 			.collect::<Vec<String>>();
 		let (priv_key, pub_key) = (keys[0].to_string(), keys[1].to_string());
 
-		(id.clone(), Student {
-			id,
-			name: src.name,
-			email: src.email,
-			subjects: vec![],
-			pass: src.pass,
+		(kid.clone(), UserKey {
+			kid,
+			owner: src.owner,
+			typ: src.typ,
 			pub_key,
 			priv_key,
+			created: Utc::now(),
+			retired: None,
 		})
 	}
 }
 
-impl Table for Student {
+impl Table for UserKey {
 	type Key = Uuid;
 	type Value = Self;
 
 	fn name() -> &'static str {
-		"student"
+		"user_key"
+	}
+}
+
+impl Indexed for UserKey {
+	fn indexes() -> &'static [&'static str] {
+		&["owner"]
+	}
+
+	fn index_fields(value: &Self) -> Vec<(&'static str, Vec<u8>)> {
+		vec![("owner", serde_cbor::to_vec(&value.owner).unwrap())]
+	}
+}
+
+/// failed login attempts for one email or client address,
+/// keyed by `email:<email>` or `ip:<address>`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoginThrottle {
+	pub failures: u32,
+	pub last_failure: DateTime<Utc>,
+	pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl Table for LoginThrottle {
+	type Key = String;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"login_throttle"
+	}
+}
+
+/// what a [`OneTimeToken`] may be used for
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+	PasswordReset,
+	EmailVerification,
+	/// password was right, a second factor is still missing
+	LoginChallenge,
+}
+
+/// an issued single-use token, deleted once it's used
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OneTimeToken {
+	pub jti: Uuid,
+	pub account: Uuid,
+	pub purpose: TokenPurpose,
+	pub expires: DateTime<Utc>,
+}
+
+impl Table for OneTimeToken {
+	type Key = Uuid;
+	type Value = Self;
+
+	fn name() -> &'static str {
+		"one_time_token"
 	}
 }
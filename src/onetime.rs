@@ -0,0 +1,104 @@
+//! Jednorázové tokeny
+//!
+//! Tokens for password resets and email verification are HS256 JWTs
+//! whose `jti` is also stored in the database. Redeeming a token removes
+//! the stored copy, so every token works exactly once, and issuing a new
+//! token of the same purpose drops the previous ones.
+use crate::config::CONFIG;
+use crate::db::Database;
+use crate::models::{OneTimeToken, TokenPurpose};
+
+use uuid::Uuid;
+use chrono::{Duration, Utc};
+use serde_json::json;
+use rejwt::{Algorithm, DecodingKey, EncodingKey};
+
+lazy_static! {
+	/// tokens issued with a generated secret stop working on restart
+	static ref SECRET: Vec<u8> = match CONFIG.action_token_secret {
+		Some(ref secret) => secret.as_bytes().to_vec(),
+		None => {
+			let mut secret = vec![0; 32];
+			openssl::rand::rand_bytes(&mut secret).expect("failed to generate a token secret");
+			secret
+		}
+	};
+}
+
+fn lifetime(purpose: TokenPurpose) -> Duration {
+	Duration::seconds(match purpose {
+		TokenPurpose::PasswordReset => CONFIG.reset_token_lifetime,
+		TokenPurpose::EmailVerification => CONFIG.verify_token_lifetime,
+		TokenPurpose::LoginChallenge => CONFIG.login_challenge_lifetime,
+	})
+}
+
+/// issues a token for the account, invalidating older ones of the same purpose
+pub fn issue(account: Uuid, purpose: TokenPurpose) -> Option<String> {
+	let mut db = Database::<OneTimeToken>::open()?;
+	let now = Utc::now();
+
+	let stale = db
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.filter(|x| x.expires < now || (x.account == account && x.purpose == purpose))
+		.collect::<Vec<_>>();
+	for token in stale {
+		let _ = db.write().delete(token.jti);
+	}
+
+	let token = OneTimeToken {
+		jti: Uuid::new_v4(),
+		account,
+		purpose,
+		expires: now + lifetime(purpose),
+	};
+	db.write().insert(token.jti, &token).ok()?;
+
+	let claims = json!({
+		"jti": token.jti,
+		"sub": account,
+		"purpose": purpose,
+		"exp": token.expires.timestamp(),
+	});
+	rejwt::encode(json!({}), &EncodingKey::from_secret(&SECRET), &claims, Algorithm::HS256).ok()
+}
+
+/// uses up the token, returning the account it was issued for
+pub fn redeem(token: &str, purpose: TokenPurpose) -> Option<Uuid> {
+	let (_, claims) = rejwt::decode(token.trim(), &DecodingKey::from_secret(&SECRET), &[Algorithm::HS256]).ok()?;
+	let jti = Uuid::parse_str(claims["jti"].as_str()?).ok()?;
+
+	// a token shown to the wrong endpoint stays usable for the right one
+	if claims["purpose"] != serde_json::to_value(purpose).ok()? {
+		return None;
+	}
+
+	// removing is atomic, only one of concurrent attempts gets the token
+	let removed = Database::<OneTimeToken>::open()?.write().delete(jti).ok()??;
+	let stored = serde_cbor::from_slice::<OneTimeToken>(&removed).ok()?;
+
+	if stored.purpose != purpose || stored.expires < Utc::now() {
+		return None;
+	}
+
+	Some(stored.account)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn wrong_purpose_leaves_the_token_alone() {
+		testing::setup();
+		let account = Uuid::new_v4();
+		let token = issue(account, TokenPurpose::PasswordReset).unwrap();
+
+		assert_eq!(redeem(&token, TokenPurpose::EmailVerification), None);
+		assert_eq!(redeem(&token, TokenPurpose::PasswordReset), Some(account));
+		assert_eq!(redeem(&token, TokenPurpose::PasswordReset), None);
+	}
+}
@@ -0,0 +1,65 @@
+//! Přehled známek studenta
+//!
+//! Everything a student (or their guardian) wants to see at once: the
+//! subjects they attend with their grades, averages, points and marks,
+//! and how the mark changed since the end of the previous term.
+use crate::calc::{self, Summary};
+use crate::db::Database;
+use crate::models::{Grade, Student, Subject, Term};
+
+use uuid::Uuid;
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use rocket::http::Status;
+
+/// a subject of the student with their grades in it
+#[derive(Clone, Debug, Serialize)]
+pub struct SubjectOverview {
+	pub subject: Subject,
+	pub grades: Vec<Grade>,
+	#[serde(flatten)]
+	pub summary: Summary,
+	/// the mark now minus the mark at the end of the previous term
+	pub trend: Option<f64>,
+}
+
+/// the last term that ended before the date
+fn previous_term(today: NaiveDate) -> Option<Term> {
+	Database::<Term>::open()?
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.filter(|x| x.end < today)
+		.max_by_key(|x| x.end)
+}
+
+/// the overview of all subjects the student attends
+pub fn for_student(student: Uuid) -> Result<Vec<SubjectOverview>, Status> {
+	let student = Database::<Student>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(student)
+		.ok_or(Status::NotFound)?;
+	let subjects = Database::<Subject>::open().ok_or(Status::InternalServerError)?;
+	let grades = Database::<Grade>::open()
+		.ok_or(Status::InternalServerError)?
+		.find("student", &student.id);
+	let previous = previous_term(Utc::now().naive_utc().date());
+
+	Ok(student.subjects
+		.iter()
+		.filter_map(|id| subjects.read().get(id))
+		.map(|subject| {
+			let mut grades = grades.iter().filter(|x| x.subject == subject.id).cloned().collect::<Vec<_>>();
+			grades.sort_by_key(|x| x.date);
+
+			let summary = calc::summarize(&subject, &grades);
+			let trend = previous.as_ref().and_then(|term| {
+				let until = grades.iter().take_while(|x| x.date.naive_utc().date() <= term.end).count();
+				Some(summary.mark? - calc::summarize(&subject, &grades[..until]).mark?)
+			});
+
+			SubjectOverview { subject, grades, summary, trend }
+		})
+		.collect())
+}
@@ -0,0 +1,191 @@
+//! Tisk do PDF
+//!
+//! Just enough of PDF to print report cards: pages of plain text in the
+//! fonts every PDF reader has built in, so nothing has to be installed or
+//! embedded. Lines starting with `# ` are printed as headings. The built-in
+//! fonts only know the Windows-1252 letters, Czech letters missing from it
+//! lose their diacritics (`č` prints as `c`).
+use std::io::Write;
+
+/// A4 in points
+const WIDTH: u32 = 595;
+const HEIGHT: u32 = 842;
+const MARGIN: u32 = 56;
+const SIZE: u32 = 11;
+const HEADING_SIZE: u32 = 15;
+const LEADING: u32 = 16;
+/// what fits on a line in Helvetica, roughly
+const COLUMNS: usize = 85;
+
+/// the Windows-1252 byte of a character
+fn encode(c: char) -> u8 {
+	match c {
+		' '..='~' => c as u8,
+		'\u{a0}'..='\u{ff}' => c as u32 as u8,
+		'Š' => 0x8a,
+		'š' => 0x9a,
+		'Ž' => 0x8e,
+		'ž' => 0x9e,
+		'–' => 0x96,
+		'—' => 0x97,
+		'„' => 0x84,
+		'“' => 0x93,
+		'”' => 0x94,
+		'…' => 0x85,
+		'č' | 'ć' => b'c',
+		'Č' | 'Ć' => b'C',
+		'ď' => b'd',
+		'Ď' => b'D',
+		'ě' | 'ę' => b'e',
+		'Ě' | 'Ę' => b'E',
+		'ľ' | 'ĺ' | 'ł' => b'l',
+		'Ľ' | 'Ĺ' | 'Ł' => b'L',
+		'ň' | 'ń' => b'n',
+		'Ň' | 'Ń' => b'N',
+		'ř' | 'ŕ' => b'r',
+		'Ř' | 'Ŕ' => b'R',
+		'ś' => b's',
+		'Ś' => b'S',
+		'ť' => b't',
+		'Ť' => b'T',
+		'ů' | 'ű' => b'u',
+		'Ů' | 'Ű' => b'U',
+		'ź' | 'ż' => b'z',
+		'Ź' | 'Ż' => b'Z',
+		'\t' => b' ',
+		_ => b'?',
+	}
+}
+
+/// a PDF string literal
+fn literal(text: &str) -> String {
+	text.chars().map(encode).fold(String::from("("), |mut out, b| {
+		match b {
+			b'(' | b')' | b'\\' => {
+				out.push('\\');
+				out.push(b as char);
+			}
+			0x20..=0x7e => out.push(b as char),
+			_ => out.push_str(&format!("\\{:03o}", b)),
+		}
+		out
+	}) + ")"
+}
+
+/// splits a line so that it fits on the page
+fn wrap(line: &str, columns: usize) -> Vec<String> {
+	let mut lines = vec![String::new()];
+
+	for word in line.split_whitespace() {
+		let current = lines.last_mut().unwrap();
+		if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > columns {
+			lines.push(word.to_string());
+		} else {
+			if !current.is_empty() {
+				current.push(' ');
+			}
+			current.push_str(word);
+		}
+	}
+
+	lines
+}
+
+/// the content streams of the pages of one text, it goes on as many pages as needed
+fn layout(text: &str) -> Vec<String> {
+	let mut pages = vec![String::new()];
+	let mut y = HEIGHT - MARGIN;
+
+	for line in text.lines() {
+		let (font, size, line) = match line.strip_prefix("# ") {
+			Some(heading) => ("F2", HEADING_SIZE, heading),
+			None => ("F1", SIZE, line),
+		};
+
+		for part in wrap(line, COLUMNS * SIZE as usize / size as usize) {
+			if y < MARGIN + LEADING {
+				pages.push(String::new());
+				y = HEIGHT - MARGIN;
+			}
+			y -= LEADING.max(size + 4);
+
+			if !part.is_empty() {
+				let page = pages.last_mut().unwrap();
+				page.push_str(&format!("BT /{} {} Tf {} {} Td {} Tj ET\n", font, size, MARGIN, y, literal(&part)));
+			}
+		}
+	}
+
+	pages
+}
+
+/// a PDF with every text starting on a new page
+pub fn document(texts: &[String]) -> Vec<u8> {
+	let contents = texts.iter().flat_map(|x| layout(x)).collect::<Vec<_>>();
+	// catalog, pages, two fonts, then a page and its content for every page
+	let pages = (0..contents.len()).map(|i| format!("{} 0 R", 5 + 2 * i)).collect::<Vec<_>>();
+
+	let mut objects = vec![
+		"<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+		format!("<< /Type /Pages /Kids [{}] /Count {} >>", pages.join(" "), pages.len()),
+		"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_string(),
+		"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_string(),
+	];
+	for (i, content) in contents.iter().enumerate() {
+		objects.push(format!(
+			"<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+			WIDTH,
+			HEIGHT,
+			6 + 2 * i,
+		));
+		objects.push(format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content));
+	}
+
+	let mut out = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+	let mut offsets = vec![];
+	for (i, object) in objects.iter().enumerate() {
+		offsets.push(out.len());
+		write!(out, "{} 0 obj\n{}\nendobj\n", i + 1, object).unwrap();
+	}
+
+	let xref = out.len();
+	write!(out, "xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).unwrap();
+	for offset in offsets {
+		writeln!(out, "{:010} 00000 n ", offset).unwrap();
+	}
+	write!(out, "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).unwrap();
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn text_is_encoded_and_wrapped() {
+		assert_eq!(literal("Šťastný (a) \\"), "(\\212tastn\\375 \\(a\\) \\\\)");
+		assert_eq!(wrap("a bb ccc dd", 6), vec!["a bb", "ccc dd"]);
+		assert_eq!(wrap("", 6), vec![""]);
+	}
+
+	#[test]
+	fn cross_references_point_at_objects() {
+		let long = (0..100).map(|i| format!("line {}", i)).collect::<Vec<_>>().join("\n");
+		let pdf = document(&["# Heading\nline".to_string(), long]);
+		let text = String::from_utf8_lossy(&pdf);
+
+		assert!(pdf.starts_with(b"%PDF-1.4"));
+		assert!(text.ends_with("%%EOF\n"));
+		// the long text takes three pages of its own
+		assert!(text.contains("/Count 4"));
+
+		let xref = text.split("startxref\n").nth(1).unwrap().lines().next().unwrap().parse::<usize>().unwrap();
+		let table = std::str::from_utf8(&pdf[xref..]).unwrap();
+		assert!(table.starts_with("xref"));
+		for (i, line) in table.lines().skip(3).take_while(|x| x.ends_with(" n ")).enumerate() {
+			let offset = line[..10].parse::<usize>().unwrap();
+			assert!(pdf[offset..].starts_with(format!("{} 0 obj", i + 1).as_bytes()));
+		}
+	}
+}
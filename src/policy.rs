@@ -0,0 +1,38 @@
+//! Nastavení školy
+//!
+//! Settings admins can change while the server runs. They are stored
+//! in the database and mirrored in memory, since some of them are checked
+//! on every request. Until an admin changes something, the values
+//! come from the configuration.
+use crate::config::CONFIG;
+use crate::db::Database;
+use crate::models::Policy;
+
+use std::sync::RwLock;
+
+const KEY: &str = "school";
+
+lazy_static! {
+	static ref POLICY: RwLock<Policy> = RwLock::new(load());
+}
+
+fn load() -> Policy {
+	Database::<Policy>::open()
+		.and_then(|db| db.read().get(KEY.to_string()))
+		.unwrap_or_else(|| Policy {
+			require_teacher_2fa: CONFIG.require_teacher_2fa,
+			default_scale: None,
+		})
+}
+
+/// the settings in effect
+pub fn current() -> Policy {
+	POLICY.read().expect("the policy rwlock has been poisoned").clone()
+}
+
+/// changes the settings
+pub fn set(policy: Policy) -> Option<()> {
+	Database::<Policy>::open()?.write().insert(KEY.to_string(), &policy).ok()?;
+	*POLICY.write().expect("the policy rwlock has been poisoned") = policy;
+	Some(())
+}
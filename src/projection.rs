@@ -0,0 +1,202 @@
+//! Co potřebuji na zbývající testy
+//!
+//! A student picks a target mark and the grade events still to come, and
+//! we find the easiest grade that, given on all of them, gets the final
+//! mark to the target. Values of the subject's scale are tried with the
+//! subject's formula and rules, which are expected to give better marks
+//! for better grades.
+use crate::calc::{self, Summary};
+use crate::scales;
+use crate::db::Database;
+use crate::models::{Grade, GradeVal, ScaleKind, Subject};
+
+use uuid::Uuid;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use rocket::http::Status;
+
+/// a grade event still to come
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedGrade {
+	pub name: String,
+	#[serde(default)]
+	pub category: Option<String>,
+	#[serde(default)]
+	pub weight: Option<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProjectionForm {
+	/// the final mark the student wants, as written on the scale
+	pub target: String,
+	pub planned: Vec<PlannedGrade>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Projection {
+	/// whether any grade on the planned events gets to the target
+	pub reachable: bool,
+	/// the easiest grade needed on every planned event
+	pub required: Option<f64>,
+	/// the required grade as written on the scale
+	pub display: Option<String>,
+	/// the final mark with the required grades
+	pub mark: Option<f64>,
+	/// where the student is now
+	pub current: Summary,
+}
+
+/// whether the mark is at least as good as the target once put on the scale
+fn meets(scale: &ScaleKind, lower_is_better: bool, mark: f64, target: f64) -> bool {
+	let mark = scale.snap(mark);
+
+	if lower_is_better { mark <= target } else { mark >= target }
+}
+
+/// the most planned grades one projection can have
+pub const MAX_PLANNED: usize = 50;
+
+/// the easiest value that reaches the target and the mark it gives; marks
+/// only get better with better grades, so the scale is bisected
+pub fn solve(subject: &Subject, scale: &ScaleKind, grades: &[Grade], planned: &[PlannedGrade], target: f64) -> Option<(f64, f64)> {
+	let lower_is_better = subject.bonus_rules.lower_is_better;
+	let mut candidates = scale.values();
+	// from the worst grade to the best
+	if lower_is_better {
+		candidates.reverse();
+	}
+
+	let mut all = grades.to_vec();
+	all.extend(planned.iter().map(|p| Grade {
+		id: Uuid::nil(),
+		name: p.name.clone(),
+		val: GradeVal::Regular(0.0),
+		description: None,
+		date: Utc::now(),
+		subject: subject.id,
+		student: Uuid::nil(),
+		category: p.category.clone(),
+		weight: p.weight,
+		term: None,
+	}));
+	let mut mark = |value: f64| {
+		all[grades.len()..].iter_mut().for_each(|x| x.val = GradeVal::Regular(value as f32));
+		calc::summarize_with(subject, Some(scale), &all).mark
+	};
+
+	let first = candidates.partition_point(|v| !mark(*v).is_some_and(|m| meets(scale, lower_is_better, m, target)));
+	let value = *candidates.get(first)?;
+	Some((value, mark(value)?))
+}
+
+/// what the student needs in the subject, the subject must have a scale
+pub fn project(subject: Uuid, student: Uuid, input: &ProjectionForm) -> Result<Projection, Status> {
+	let subject = Database::<Subject>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(subject)
+		.ok_or(Status::NotFound)?;
+	let scale = scales::for_subject(&subject).ok_or(Status::Conflict)?.kind;
+	let target = scale.parse(&input.target).map(f64::from).ok_or(Status::BadRequest)?;
+	if scale.values().len() > scales::MAX_VALUES {
+		return Err(Status::Conflict);
+	}
+	if input.planned.is_empty()
+		|| input.planned.len() > MAX_PLANNED
+		|| input.planned.iter().any(|p| {
+			p.category.as_ref().is_some_and(|c| !subject.categories.iter().any(|x| x.name == *c))
+				|| p.weight.is_some_and(|w| !(w >= 0.0 && w.is_finite()))
+		})
+	{
+		return Err(Status::BadRequest);
+	}
+
+	let grades = Database::<Grade>::open()
+		.ok_or(Status::InternalServerError)?
+		.find("student", &student)
+		.into_iter()
+		.filter(|x| x.subject == subject.id)
+		.collect::<Vec<_>>();
+	let solution = solve(&subject, &scale, &grades, &input.planned, target);
+
+	Ok(Projection {
+		reachable: solution.is_some(),
+		required: solution.map(|(v, _)| v),
+		display: solution.map(|(v, _)| scale.display(v)),
+		mark: solution.map(|(_, m)| m),
+		current: calc::summarize_with(&subject, Some(&scale), &grades),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::models::{BonusRules, Category, Kind};
+
+	fn subject(formula: &str) -> Subject {
+		Subject {
+			id: Uuid::nil(),
+			name: String::new(),
+			description: String::new(),
+			year: String::new(),
+			grade_formula: formula.to_string(),
+			kind: Kind::Other,
+			teacher: Uuid::nil(),
+			capacity: None,
+			term: None,
+			scale: None,
+			categories: vec![Category { name: "test".to_string(), weight: 2.0 }],
+			bonus_rules: BonusRules::default(),
+		}
+	}
+
+	fn grade(value: f32) -> Grade {
+		Grade {
+			id: Uuid::new_v4(),
+			name: String::new(),
+			val: GradeVal::Regular(value),
+			description: None,
+			date: Utc::now(),
+			subject: Uuid::nil(),
+			student: Uuid::nil(),
+			category: None,
+			weight: None,
+			term: None,
+		}
+	}
+
+	fn planned(category: Option<&str>) -> PlannedGrade {
+		PlannedGrade { name: "test".to_string(), category: category.map(str::to_string), weight: None }
+	}
+
+	const SCALE: ScaleKind = ScaleKind::Numeric { min: 1.0, max: 5.0, step: 1.0 };
+
+	#[test]
+	fn finds_the_easiest_grade() {
+		let subject = subject("wavg");
+		let grades = vec![grade(3.0), grade(3.0)];
+
+		// (3 + 3 + x) / 3 rounds to 2 for x <= 1.5
+		assert_eq!(solve(&subject, &SCALE, &grades, &[planned(None)], 2.0), Some((1.0, 7.0 / 3.0)));
+		// two planned grades: (6 + 2x) / 4 rounds to 2 for x < 2
+		assert_eq!(solve(&subject, &SCALE, &grades, &[planned(None), planned(None)], 2.0), Some((1.0, 2.0)));
+		// (6 + x) / 3 rounds to 3 for x <= 4, a 5 would make it a 4
+		assert_eq!(solve(&subject, &SCALE, &grades, &[planned(None)], 3.0).map(|x| x.0), Some(4.0));
+
+		// on a finer scale (6 + x) / 3 rounds to 2.5 for x < 2.25
+		let halves = ScaleKind::Numeric { min: 1.0, max: 5.0, step: 0.5 };
+		assert_eq!(solve(&subject, &halves, &grades, &[planned(None)], 2.5), Some((2.0, 8.0 / 3.0)));
+		assert_eq!(solve(&subject, &halves, &grades, &[planned(None)], 2.0), None);
+	}
+
+	#[test]
+	fn weights_and_unreachable_targets() {
+		let subject = subject("wavg");
+		let grades = vec![grade(5.0), grade(5.0)];
+
+		assert_eq!(solve(&subject, &SCALE, &grades, &[planned(None)], 1.0), None);
+		// a test counts twice: (10 + 2x) / 4 rounds to 4 for x <= 3
+		assert_eq!(solve(&subject, &SCALE, &grades, &[planned(Some("test"))], 4.0).map(|x| x.0), Some(3.0));
+	}
+}
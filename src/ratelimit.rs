@@ -0,0 +1,228 @@
+//! Omezení počtu pokusů o přihlášení
+//!
+//! Failed logins are counted separately for the email and for the client
+//! address. After [`Config::login_free_attempts`](crate::config::Config)
+//! failures every further attempt has to wait exponentially longer and
+//! after [`Config::login_lockout_threshold`](crate::config::Config) failures
+//! the email (or address) is locked out for a while. Counters live
+//! in a sled tree, so restarting the server doesn't reset them.
+use crate::config::CONFIG;
+use crate::db::Database;
+use crate::models::LoginThrottle;
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use rocket::Outcome;
+use rocket::Request;
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::Json;
+
+use std::net::IpAddr;
+
+/// address of the client, the peer unless the peer is a trusted proxy,
+/// whose `X-Real-IP` is used then
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+	type Error = ();
+
+	fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+		let peer = request.remote().map(|x| x.ip());
+		Outcome::Success(ClientIp(client_ip(peer, request.real_ip(), &CONFIG.trusted_proxies)))
+	}
+}
+
+/// anybody else can put anything into the header
+fn client_ip(peer: Option<IpAddr>, forwarded: Option<IpAddr>, trusted: &[IpAddr]) -> Option<IpAddr> {
+	match peer {
+		Some(peer) if trusted.contains(&peer) => forwarded.or(Some(peer)),
+		peer => peer,
+	}
+}
+
+/// 429 with a `Retry-After` header
+#[derive(Clone, Copy, Debug)]
+pub struct TooManyRequests {
+	/// seconds until the next attempt is allowed
+	pub retry_after: i64,
+}
+
+impl<'r> Responder<'r> for TooManyRequests {
+	fn respond_to(self, req: &Request) -> response::Result<'r> {
+		let body = Json(json!({
+			"error": "too_many_attempts",
+			"message": "too many failed login attempts, try again later",
+			"retry_after": self.retry_after,
+		}));
+
+		Response::build_from(body.respond_to(req)?)
+			.status(Status::TooManyRequests)
+			.header(Header::new("Retry-After", self.retry_after.to_string()))
+			.ok()
+	}
+}
+
+fn email_key(email: &str) -> String {
+	format!("email:{}", email.trim().to_lowercase())
+}
+
+fn ip_key(ip: ClientIp) -> Option<String> {
+	ip.0.map(|ip| format!("ip:{}", ip))
+}
+
+fn keys(email: &str, ip: ClientIp) -> Vec<String> {
+	let mut keys = vec![email_key(email)];
+	keys.extend(ip_key(ip));
+	keys
+}
+
+/// how long to wait after `failures` failed attempts
+fn backoff(failures: u32) -> Duration {
+	if failures < CONFIG.login_free_attempts {
+		return Duration::zero();
+	}
+
+	let exponent = (failures - CONFIG.login_free_attempts).min(30);
+	Duration::seconds(
+		CONFIG.login_backoff_secs
+			.saturating_mul(1 << exponent)
+			.min(CONFIG.login_backoff_max_secs),
+	)
+}
+
+/// until when the throttled key has to wait, if at all
+fn blocked_until(throttle: &LoginThrottle, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+	let until = match throttle.locked_until {
+		Some(locked) if locked > now => locked,
+		_ => throttle.last_failure + backoff(throttle.failures),
+	};
+
+	Some(until).filter(|until| *until > now)
+}
+
+/// fails with the time to wait if the email or the address may not log in yet
+pub fn check(email: &str, ip: ClientIp) -> Result<(), TooManyRequests> {
+	let db = match Database::<LoginThrottle>::open() {
+		Some(db) => db,
+		None => return Ok(()),
+	};
+	let now = Utc::now();
+
+	let wait = keys(email, ip)
+		.iter()
+		.filter_map(|k| db.read().get(k))
+		.filter_map(|t| blocked_until(&t, now))
+		.max();
+
+	match wait {
+		Some(until) => Err(TooManyRequests { retry_after: (until - now).num_seconds().max(1) }),
+		None => Ok(()),
+	}
+}
+
+/// records a failed attempt
+pub fn failure(email: &str, ip: ClientIp) {
+	let mut db = match Database::<LoginThrottle>::open() {
+		Some(db) => db,
+		None => return,
+	};
+	let now = Utc::now();
+	let window = Duration::seconds(CONFIG.login_lockout_secs);
+
+	for key in keys(email, ip) {
+		let _ = db
+			.write()
+			.update::<_, LoginThrottle, _>(key, |t| {
+				let failures = match t {
+					Some(ref t) if now - t.last_failure <= window => t.failures + 1,
+					_ => 1,
+				};
+
+				Some(LoginThrottle {
+					failures,
+					last_failure: now,
+					locked_until: (failures >= CONFIG.login_lockout_threshold).then(|| now + window),
+				})
+			});
+	}
+}
+
+/// forgets failures of the email after a successful login;
+/// the address keeps its counter, otherwise logging into one's own
+/// account would reset it
+pub fn success(email: &str) {
+	if let Some(mut db) = Database::<LoginThrottle>::open() {
+		let _ = db.write().delete(email_key(email));
+	}
+}
+
+/// lifts the lockout of an email and/or an address
+pub fn unlock(email: Option<&str>, ip: Option<IpAddr>) -> Option<()> {
+	let mut db = Database::<LoginThrottle>::open()?;
+
+	if let Some(email) = email {
+		db.write().delete(email_key(email)).ok()?;
+	}
+	if let Some(key) = ip_key(ClientIp(ip)) {
+		db.write().delete(key).ok()?;
+	}
+
+	Some(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn throttle(failures: u32, ago: i64, locked_for: Option<i64>) -> (LoginThrottle, DateTime<Utc>) {
+		let now = Utc::now();
+		(LoginThrottle {
+			failures,
+			last_failure: now - Duration::seconds(ago),
+			locked_until: locked_for.map(|s| now + Duration::seconds(s)),
+		}, now)
+	}
+
+	#[test]
+	fn backoff_grows_exponentially_up_to_the_cap() {
+		let free = CONFIG.login_free_attempts;
+
+		assert_eq!(backoff(0), Duration::zero());
+		assert_eq!(backoff(free), Duration::seconds(CONFIG.login_backoff_secs));
+		assert_eq!(backoff(free + 1), Duration::seconds(CONFIG.login_backoff_secs * 2));
+		assert_eq!(backoff(free + 2), Duration::seconds(CONFIG.login_backoff_secs * 4));
+		assert_eq!(backoff(u32::MAX), Duration::seconds(CONFIG.login_backoff_max_secs));
+	}
+
+	#[test]
+	fn waits_only_while_blocked() {
+		let (t, now) = throttle(1, 0, None);
+		assert_eq!(blocked_until(&t, now), None);
+
+		let (t, now) = throttle(CONFIG.login_free_attempts + 3, 0, None);
+		assert_eq!(blocked_until(&t, now), Some(t.last_failure + backoff(t.failures)));
+
+		let (t, now) = throttle(CONFIG.login_free_attempts + 3, 24 * 60 * 60, None);
+		assert_eq!(blocked_until(&t, now), None);
+
+		let (t, now) = throttle(CONFIG.login_lockout_threshold, 24 * 60 * 60, Some(60));
+		assert_eq!(blocked_until(&t, now), t.locked_until);
+	}
+
+	#[test]
+	fn forwarded_address_only_from_trusted_proxies() {
+		let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+		let proxy = ip("10.0.0.1");
+		let client = ip("203.0.113.7");
+		let spoofed = ip("198.51.100.1");
+
+		assert_eq!(client_ip(Some(client), Some(spoofed), &[]), Some(client));
+		assert_eq!(client_ip(Some(client), Some(spoofed), &[proxy]), Some(client));
+		assert_eq!(client_ip(Some(proxy), Some(client), &[proxy]), Some(client));
+		assert_eq!(client_ip(Some(proxy), None, &[proxy]), Some(proxy));
+		assert_eq!(client_ip(None, Some(spoofed), &[proxy]), None);
+	}
+}
@@ -0,0 +1,319 @@
+//! Vysvědčení
+//!
+//! At the end of a term every student gets a report card with the final
+//! marks of their subjects, the teachers' comments and the absences the
+//! teachers recorded. Cards are rendered from a [`ReportTemplate`] or from
+//! the built-in one in `templates/`, to HTML from its `html` part or to PDF
+//! from its `text` part, for one student or for a whole class at once.
+use crate::pdf;
+use crate::terms;
+use crate::template::Template;
+use crate::auth::AuthToken;
+use crate::db::{Database, NewEntry, NewEntryPartial};
+use crate::models::{NewReportNote, NewReportTemplate, ReportNote, ReportTemplate, SchoolYear, Student, Subject, Teacher, Term};
+
+use uuid::Uuid;
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use rocket::http::{ContentType, Status};
+use rocket::response::content::Content;
+
+use std::sync::Mutex;
+
+const DEFAULT_HTML: &str = include_str!("../templates/report_card.html");
+const DEFAULT_TEXT: &str = include_str!("../templates/report_card.txt");
+
+lazy_static! {
+	/// there is only one note per student, subject and term
+	static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// a subject on the report card
+#[derive(Clone, Debug, Serialize)]
+pub struct ReportLine {
+	pub subject: String,
+	pub teacher: String,
+	/// the final mark as shown on the scale
+	pub mark: Option<String>,
+	pub average: Option<f64>,
+	pub comment: Option<String>,
+	pub excused: u32,
+	pub unexcused: u32,
+}
+
+/// absent hours in all subjects
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Attendance {
+	pub excused: u32,
+	pub unexcused: u32,
+	pub total: u32,
+}
+
+/// everything a report card template can use
+#[derive(Clone, Debug, Serialize)]
+pub struct ReportCard {
+	pub term: Term,
+	/// name of the school year
+	pub year: String,
+	pub student: Student,
+	pub subjects: Vec<ReportLine>,
+	pub attendance: Attendance,
+	pub issued: NaiveDate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+	Html,
+	Pdf,
+}
+
+fn can_write(subject: &Subject, info: &AuthToken) -> bool {
+	info.typ == "admin" || (info.typ == "teacher" && subject.teacher == info.id)
+}
+
+/// the subject, if the user can write its notes for the term
+fn subject_in_term(term: Uuid, subject: Uuid, info: &AuthToken) -> Result<Subject, Status> {
+	let subject = Database::<Subject>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(subject)
+		.filter(|x| can_write(x, info))
+		.ok_or(Status::NotFound)?;
+	let term = Database::<Term>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(term)
+		.ok_or(Status::NotFound)?;
+
+	if subject.term.is_some_and(|t| t != term.id) {
+		return Err(Status::NotFound);
+	}
+	Ok(subject)
+}
+
+/// sets the comment and the absences of a student attending the subject
+pub fn set_note(term: Uuid, subject: Uuid, student: Uuid, input: NewReportNote, info: &AuthToken) -> Result<ReportNote, Status> {
+	let subject = subject_in_term(term, subject, info)?;
+	Database::<Student>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(student)
+		.filter(|x| x.subjects.contains(&subject.id))
+		.ok_or(Status::NotFound)?;
+
+	let _lock = LOCK.lock().unwrap();
+	let mut db = Database::<ReportNote>::open().ok_or(Status::InternalServerError)?;
+	let existing = db
+		.find("student", &student)
+		.into_iter()
+		.find(|x| x.term == term && x.subject == subject.id);
+
+	let note = ReportNote {
+		id: existing.map_or_else(Uuid::new_v4, |x| x.id),
+		term,
+		subject: subject.id,
+		student,
+		comment: input.comment.trim().to_string(),
+		excused: input.excused,
+		unexcused: input.unexcused,
+		author: info.account,
+		updated: Utc::now(),
+	};
+	db.insert_indexed(&note.id, &note).map_err(|_| Status::InternalServerError)?;
+
+	Ok(note)
+}
+
+/// notes of all the students of a subject in a term
+pub fn notes(term: Uuid, subject: Uuid, info: &AuthToken) -> Result<Vec<ReportNote>, Status> {
+	let subject = subject_in_term(term, subject, info)?;
+
+	Ok(Database::<ReportNote>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.filter(|x| x.term == term && x.subject == subject.id)
+		.collect())
+}
+
+/// the report card of a student for a term
+pub fn card(term: Uuid, student: Uuid) -> Result<ReportCard, Status> {
+	let marks = terms::marks(term, student)?;
+	let term = Database::<Term>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(term)
+		.ok_or(Status::NotFound)?;
+	let student = Database::<Student>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(student)
+		.ok_or(Status::NotFound)?;
+	let year = Database::<SchoolYear>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(term.year)
+		.map(|x| x.name)
+		.unwrap_or_default();
+	let teachers = Database::<Teacher>::open().ok_or(Status::InternalServerError)?;
+	let notes = Database::<ReportNote>::open()
+		.ok_or(Status::InternalServerError)?
+		.find("student", &student.id)
+		.into_iter()
+		.filter(|x| x.term == term.id)
+		.collect::<Vec<_>>();
+
+	let mut subjects = marks
+		.into_iter()
+		.map(|x| {
+			let note = notes.iter().find(|n| n.subject == x.subject.id);
+			let mark = x.summary.mark.map(|m| format!("{}", (m * 100.0).round() / 100.0));
+			ReportLine {
+				teacher: teachers.read().get(x.subject.teacher).map(|t| t.name).unwrap_or_default(),
+				subject: x.subject.name,
+				mark: x.summary.display.or(mark),
+				average: x.summary.average,
+				comment: note.map(|n| n.comment.clone()).filter(|c| !c.is_empty()),
+				excused: note.map_or(0, |n| n.excused),
+				unexcused: note.map_or(0, |n| n.unexcused),
+			}
+		})
+		.collect::<Vec<_>>();
+	subjects.sort_by(|a, b| a.subject.cmp(&b.subject));
+
+	let attendance = attendance(&subjects);
+
+	Ok(ReportCard { term, year, student, subjects, attendance, issued: Utc::now().naive_utc().date() })
+}
+
+/// absences summed over the subjects, the hours are whatever teachers
+/// typed in, so the sums stop at the largest value instead of overflowing
+fn attendance(subjects: &[ReportLine]) -> Attendance {
+	let mut attendance = subjects.iter().fold(Attendance::default(), |mut a, x| {
+		a.excused = a.excused.saturating_add(x.excused);
+		a.unexcused = a.unexcused.saturating_add(x.unexcused);
+		a
+	});
+	attendance.total = attendance.excused.saturating_add(attendance.unexcused);
+	attendance
+}
+
+/// report cards of all the students of a class, by name
+pub fn class(term: Uuid, class: &str) -> Result<Vec<ReportCard>, Status> {
+	let mut students = Database::<Student>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.filter(|x| x.class.as_deref() == Some(class))
+		.collect::<Vec<_>>();
+	students.sort_by(|a, b| a.name.cmp(&b.name));
+
+	if students.is_empty() {
+		return Err(Status::NotFound);
+	}
+	students.iter().map(|x| card(term, x.id)).collect()
+}
+
+/// reads the `format` and `template` query parameters
+pub fn options(format: Option<&str>, template: Option<&str>) -> Result<(Format, Option<Uuid>), Status> {
+	let format = match format.unwrap_or("html") {
+		"html" => Format::Html,
+		"pdf" => Format::Pdf,
+		_ => return Err(Status::BadRequest),
+	};
+	let template = template
+		.map(|x| x.parse::<Uuid>().map_err(|_| Status::BadRequest))
+		.transpose()?;
+
+	Ok((format, template))
+}
+
+/// wraps the rendered cards into a page, every card is printed on its own sheet
+fn page(cards: &[String]) -> String {
+	let mut page = String::from(concat!(
+		"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Vysvědčení</title>\n",
+		"<style>.report-card { page-break-after: always; }</style>\n</head>\n<body>\n",
+	));
+	for card in cards {
+		page.push_str("<section class=\"report-card\">\n");
+		page.push_str(card);
+		page.push_str("</section>\n");
+	}
+	page.push_str("</body>\n</html>\n");
+
+	page
+}
+
+/// renders the cards with the template, the built-in one if there is none
+pub fn render(cards: &[ReportCard], template: Option<Uuid>, format: Format) -> Result<Content<Vec<u8>>, Status> {
+	let (html, text) = match template {
+		Some(id) => Database::<ReportTemplate>::open()
+			.ok_or(Status::InternalServerError)?
+			.read()
+			.get(id)
+			.map(|x| (x.html, x.text))
+			.ok_or(Status::NotFound)?,
+		None => (DEFAULT_HTML.to_string(), DEFAULT_TEXT.to_string()),
+	};
+	let data = cards
+		.iter()
+		.map(serde_json::to_value)
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|_| Status::InternalServerError)?;
+
+	match format {
+		Format::Html => {
+			let template = Template::parse(&html).map_err(|_| Status::InternalServerError)?;
+			let cards = data.iter().map(|x| template.render(x, true)).collect::<Vec<_>>();
+			Ok(Content(ContentType::HTML, page(&cards).into_bytes()))
+		}
+		Format::Pdf => {
+			let template = Template::parse(&text).map_err(|_| Status::InternalServerError)?;
+			let cards = data.iter().map(|x| template.render(x, false)).collect::<Vec<_>>();
+			Ok(Content(ContentType::PDF, pdf::document(&cards)))
+		}
+	}
+}
+
+/// adds a template, both of its parts have to parse
+pub fn create_template(input: NewReportTemplate) -> Result<ReportTemplate, Status> {
+	if input.name.trim().is_empty() || Template::parse(&input.html).is_err() || Template::parse(&input.text).is_err() {
+		return Err(Status::BadRequest);
+	}
+
+	let (id, template) = ReportTemplate::create(input);
+	(id, template.clone()).save().map_err(|_| Status::InternalServerError)?;
+	Ok(template)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn default_templates_parse() {
+		assert!(Template::parse(DEFAULT_HTML).is_ok());
+		assert!(Template::parse(DEFAULT_TEXT).is_ok());
+	}
+
+	#[test]
+	fn absences_do_not_overflow() {
+		let line = |excused, unexcused| ReportLine {
+			subject: String::new(),
+			teacher: String::new(),
+			mark: None,
+			average: None,
+			comment: None,
+			excused,
+			unexcused,
+		};
+
+		let sums = attendance(&[line(2, 1), line(3, 0)]);
+		assert_eq!((sums.excused, sums.unexcused, sums.total), (5, 1, 6));
+
+		let sums = attendance(&[line(u32::MAX, 1), line(1, u32::MAX)]);
+		assert_eq!((sums.excused, sums.unexcused, sums.total), (u32::MAX, u32::MAX, u32::MAX));
+	}
+}
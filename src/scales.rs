@@ -0,0 +1,249 @@
+//! Klasifikační stupnice
+//!
+//! A subject grades on its own [`GradingScale`], or on the school's default
+//! one from [`Policy`](crate::models::Policy). Grades are always stored as
+//! numbers, letters and pass/fail are converted on the way in and back
+//! for display, so averages work the same on every scale.
+use crate::policy;
+use crate::db::{Database, NewEntry, NewEntryPartial};
+use crate::models::{GradeVal, GradingScale, NewGradingScale, ScaleKind, Subject};
+
+use rocket::http::Status;
+
+use std::ops::RangeInclusive;
+
+/// tolerance for floats landing on a step
+const EPSILON: f32 = 1e-4;
+/// the most values a scale can have
+pub const MAX_VALUES: usize = 1000;
+/// grades of subjects without any scale are on the usual 1 to 5
+pub const UNSCALED: RangeInclusive<f32> = 1.0..=5.0;
+
+impl ScaleKind {
+	/// whether the scale itself makes sense
+	pub fn is_valid(&self) -> bool {
+		match self {
+			ScaleKind::Numeric { min, max, step } => {
+				min < max && *step > 0.0 && (max - min) / step <= MAX_VALUES as f32 - 1.0
+			}
+			ScaleKind::Letter { letters } => {
+				!letters.is_empty() && letters.len() <= MAX_VALUES && letters.iter().enumerate().all(|(i, x)| {
+					!x.letter.trim().is_empty()
+						&& letters[..i].iter().all(|y| !y.letter.eq_ignore_ascii_case(&x.letter) && y.value != x.value)
+				})
+			}
+			ScaleKind::PassFail { pass, fail } => pass != fail,
+		}
+	}
+
+	/// whether a stored value belongs to the scale
+	pub fn accepts(&self, value: f32) -> bool {
+		match self {
+			ScaleKind::Numeric { min, max, step } => {
+				let steps = (value - min) / step;
+				*min - EPSILON <= value && value <= *max + EPSILON && (steps - steps.round()).abs() < EPSILON
+			}
+			ScaleKind::Letter { letters } => letters.iter().any(|x| (x.value - value).abs() < EPSILON),
+			ScaleKind::PassFail { pass, fail } => (value - pass).abs() < EPSILON || (value - fail).abs() < EPSILON,
+		}
+	}
+
+	/// reads a grade as a teacher writes it, `None` if it's not on the scale
+	pub fn parse(&self, input: &str) -> Option<f32> {
+		let input = input.trim();
+
+		let value = match self {
+			ScaleKind::Numeric { .. } => input.replace(',', ".").parse().ok()?,
+			ScaleKind::Letter { letters } => letters.iter().find(|x| x.letter.eq_ignore_ascii_case(input))?.value,
+			ScaleKind::PassFail { pass, fail } => match input.to_lowercase().as_str() {
+				"pass" => *pass,
+				"fail" => *fail,
+				_ => return None,
+			},
+		};
+
+		Some(value).filter(|v| self.accepts(*v))
+	}
+
+	/// the lowest and the highest value of the scale
+	pub fn range(&self) -> (f64, f64) {
+		let values = match self {
+			ScaleKind::Numeric { min, max, .. } => vec![*min, *max],
+			ScaleKind::Letter { letters } => letters.iter().map(|x| x.value).collect(),
+			ScaleKind::PassFail { pass, fail } => vec![*pass, *fail],
+		};
+
+		values.into_iter().map(f64::from).fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)))
+	}
+
+	/// all the values of the scale, from the lowest, at most [`MAX_VALUES`] + 1
+	/// of them so that a scale stored before the limit can be told apart
+	pub fn values(&self) -> Vec<f64> {
+		let mut values = match self {
+			ScaleKind::Numeric { min, max, step } => {
+				let (min, max, step) = (f64::from(*min), f64::from(*max), f64::from(*step));
+				(0..)
+					.map(|i| min + f64::from(i) * step)
+					.take_while(|x| *x <= max + f64::from(EPSILON))
+					.take(MAX_VALUES + 1)
+					.collect()
+			}
+			ScaleKind::Letter { letters } => letters.iter().map(|x| f64::from(x.value)).collect(),
+			ScaleKind::PassFail { pass, fail } => vec![f64::from(*pass), f64::from(*fail)],
+		};
+
+		values.sort_by(f64::total_cmp);
+		values
+	}
+
+	/// the closest value on the scale, for averages
+	pub fn snap(&self, value: f64) -> f64 {
+		match self {
+			ScaleKind::Numeric { min, max, step } => {
+				let (min, max, step) = (f64::from(*min), f64::from(*max), f64::from(*step));
+				(min + ((value - min) / step).round() * step).max(min).min(max)
+			}
+			ScaleKind::Letter { letters } => letters
+				.iter()
+				.map(|x| f64::from(x.value))
+				.min_by(|a, b| (a - value).abs().total_cmp(&(b - value).abs()))
+				.unwrap_or(value),
+			ScaleKind::PassFail { pass, fail } => {
+				let (pass, fail) = (f64::from(*pass), f64::from(*fail));
+				if (pass - value).abs() <= (fail - value).abs() { pass } else { fail }
+			}
+		}
+	}
+
+	/// how to show a value, averages are snapped to the scale first
+	pub fn display(&self, value: f64) -> String {
+		let snapped = self.snap(value);
+
+		match self {
+			ScaleKind::Numeric { .. } => format!("{}", (snapped * 100.0).round() / 100.0),
+			ScaleKind::Letter { letters } => letters
+				.iter()
+				.find(|x| f64::from(x.value) == snapped)
+				.map(|x| x.letter.clone())
+				.unwrap_or_default(),
+			ScaleKind::PassFail { pass, .. } => {
+				if snapped == f64::from(*pass) { "pass".to_string() } else { "fail".to_string() }
+			}
+		}
+	}
+}
+
+/// reads a grade of the type (`Bonus`, `Penalisation`, anything else is
+/// regular), regular grades have to be on the scale if there is one
+/// and within [`UNSCALED`] if there isn't
+pub fn parse_value(scale: Option<&ScaleKind>, typ: &str, number: &str) -> Option<GradeVal> {
+	match typ {
+		"Bonus" => number.trim().parse().ok().map(GradeVal::Bonus),
+		"Penalisation" => number.trim().parse().ok().map(GradeVal::Penalisation),
+		_ => match scale {
+			Some(scale) => scale.parse(number),
+			None => number.trim().replace(',', ".").parse().ok().filter(|x| UNSCALED.contains(x)),
+		}
+		// NaN and infinities would spoil every average they get into
+		.filter(|x: &f32| x.is_finite())
+		.map(GradeVal::Regular),
+	}
+}
+
+/// whether a value passes, `None` if the scale doesn't say
+pub fn passes(scale: &GradingScale, lower_is_better: bool, value: f64) -> Option<bool> {
+	match (&scale.kind, scale.pass_mark.map(f64::from)) {
+		(ScaleKind::PassFail { pass, .. }, _) => Some((value - f64::from(*pass)).abs() < f64::from(EPSILON)),
+		(_, Some(mark)) if lower_is_better => Some(value <= mark),
+		(_, Some(mark)) => Some(value >= mark),
+		(_, None) => None,
+	}
+}
+
+/// adds a scale, it has to make sense
+pub fn create(input: NewGradingScale) -> Result<GradingScale, Status> {
+	if !input.kind.is_valid() || input.pass_mark.is_some_and(|x| !input.kind.accepts(x)) {
+		return Err(Status::BadRequest);
+	}
+
+	let (id, scale) = GradingScale::create(input);
+	(id, scale.clone()).save().map_err(|_| Status::InternalServerError)?;
+	Ok(scale)
+}
+
+/// the scale a subject grades on, if there is any
+pub fn for_subject(subject: &Subject) -> Option<GradingScale> {
+	let id = subject.scale.or_else(|| policy::current().default_scale)?;
+	Database::<GradingScale>::open()?.read().get(id)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::models::Letter;
+
+	fn letters() -> ScaleKind {
+		ScaleKind::Letter {
+			letters: ["A", "B", "C", "D", "E", "F"]
+				.iter()
+				.enumerate()
+				.map(|(i, l)| Letter { letter: l.to_string(), value: i as f32 + 1.0 })
+				.collect(),
+		}
+	}
+
+	#[test]
+	fn numeric_scales_respect_steps() {
+		let scale = ScaleKind::Numeric { min: 1.0, max: 5.0, step: 0.5 };
+
+		assert_eq!(scale.parse("2,5"), Some(2.5));
+		assert_eq!(scale.parse("2.4"), None);
+		assert_eq!(scale.parse("6"), None);
+		assert_eq!(scale.parse("x"), None);
+		assert_eq!(scale.display(2.3), "2.5");
+		assert_eq!(scale.display(0.2), "1");
+		assert!(scale.is_valid());
+		assert!(!ScaleKind::Numeric { min: 1.0, max: 5.0, step: 0.001 }.is_valid());
+		assert_eq!(ScaleKind::Numeric { min: 0.0, max: 1.0, step: 1e-6 }.values().len(), MAX_VALUES + 1);
+	}
+
+	#[test]
+	fn letter_scales_map_to_numbers() {
+		let scale = letters();
+
+		assert!(scale.is_valid());
+		assert_eq!(scale.parse("b"), Some(2.0));
+		assert_eq!(scale.parse("G"), None);
+		assert_eq!(scale.display(2.4), "B");
+		assert_eq!(scale.display(2.6), "C");
+		assert!(!ScaleKind::Letter { letters: vec![] }.is_valid());
+	}
+
+	#[test]
+	fn pass_fail_scales() {
+		let scale = ScaleKind::PassFail { pass: 1.0, fail: 0.0 };
+
+		assert_eq!(scale.parse("Pass"), Some(1.0));
+		assert_eq!(scale.parse("1"), None);
+		assert_eq!(scale.display(0.75), "pass");
+		assert_eq!(scale.display(0.25), "fail");
+		assert!(scale.accepts(0.0));
+		assert!(!scale.accepts(0.5));
+	}
+
+	#[test]
+	fn values_have_to_be_finite_numbers_in_range() {
+		let regular = |scale, number| parse_value(scale, "Regular", number);
+		let scale = ScaleKind::Numeric { min: 1.0, max: 5.0, step: 0.5 };
+
+		for number in &["NaN", "nan", "inf", "-inf", "infinity", "1e39", "0", "6"] {
+			assert!(regular(None, number).is_none(), "{}", number);
+			assert!(regular(Some(&scale), number).is_none(), "{}", number);
+		}
+		assert!(matches!(regular(None, "2,5"), Some(GradeVal::Regular(x)) if x == 2.5));
+		assert!(matches!(regular(Some(&scale), "4.5"), Some(GradeVal::Regular(x)) if x == 4.5));
+		assert!(parse_value(None, "Bonus", "inf").is_none());
+		assert!(matches!(parse_value(None, "Bonus", "2"), Some(GradeVal::Bonus(2))));
+	}
+}
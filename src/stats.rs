@@ -0,0 +1,375 @@
+//! Statistiky známek
+//!
+//! Statistics of regular grades per subject, per grade event, per term and
+//! per teacher. The regular grades of every subject are cached in memory
+//! the first time they are needed and dropped whenever a grade of the
+//! subject is written, so the `grade` tree isn't scanned on every call.
+use crate::scales;
+use crate::terms;
+use crate::auth::AuthToken;
+use crate::db::Database;
+use crate::models::{Grade, GradeVal, GradingScale, Subject, Teacher, Term};
+
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use rocket::http::Status;
+
+use std::sync::{Arc, RwLock};
+use std::collections::{BTreeSet, HashMap};
+
+lazy_static! {
+	/// regular grades of each subject
+	static ref CACHE: RwLock<Cache> = RwLock::new(Cache::default());
+}
+
+/// a regular grade, only what statistics need
+#[derive(Clone, Debug)]
+struct Entry {
+	value: f64,
+	name: String,
+	date: DateTime<Utc>,
+	/// the term the grade was written in
+	term: Option<Uuid>,
+}
+
+/// cached grades of the subjects; every invalidation bumps the subject's
+/// generation, so grades loaded before a write are never stored after it
+#[derive(Default)]
+struct Cache {
+	entries: HashMap<Uuid, Arc<Vec<Entry>>>,
+	generations: HashMap<Uuid, u64>,
+}
+
+impl Cache {
+	fn generation(&self, subject: Uuid) -> u64 {
+		self.generations.get(&subject).copied().unwrap_or(0)
+	}
+
+	fn invalidate(&mut self, subject: Uuid) {
+		self.entries.remove(&subject);
+		*self.generations.entry(subject).or_insert(0) += 1;
+	}
+
+	/// stores the grades unless the subject was invalidated since `generation`
+	fn store(&mut self, subject: Uuid, generation: u64, entries: Arc<Vec<Entry>>) {
+		if self.generation(subject) == generation {
+			self.entries.insert(subject, entries);
+		}
+	}
+}
+
+/// drops the cached grades of the subject, call after writing its grades
+pub fn invalidate(subject: Uuid) {
+	CACHE.write().expect("the stats cache rwlock has been poisoned").invalidate(subject);
+}
+
+fn entries(subject: Uuid) -> Option<Arc<Vec<Entry>>> {
+	let generation = {
+		let cache = CACHE.read().expect("the stats cache rwlock has been poisoned");
+		if let Some(cached) = cache.entries.get(&subject) {
+			return Some(cached.clone());
+		}
+		cache.generation(subject)
+	};
+
+	let entries = Arc::new(Database::<Grade>::open()?
+		.find("subject", &subject)
+		.into_iter()
+		.filter_map(|x| match x.val {
+			GradeVal::Regular(v) => Some(Entry { value: f64::from(v), name: x.name, date: x.date, term: x.term }),
+			_ => None,
+		})
+		.collect::<Vec<_>>());
+
+	CACHE.write()
+		.expect("the stats cache rwlock has been poisoned")
+		.store(subject, generation, entries.clone());
+	Some(entries)
+}
+
+/// how many grades had the value
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Bucket {
+	pub value: f64,
+	pub count: usize,
+}
+
+/// statistics of a set of regular grades
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct Stats {
+	pub count: usize,
+	pub mean: Option<f64>,
+	pub median: Option<f64>,
+	/// population standard deviation
+	pub std_dev: Option<f64>,
+	/// counts of the values, from the lowest
+	pub histogram: Vec<Bucket>,
+	/// share of passing grades, `None` if the scale has no pass mark
+	pub pass_rate: Option<f64>,
+}
+
+impl Stats {
+	/// statistics of values, each with whether it passes if that's known
+	pub fn compute(values: &[(f64, Option<bool>)]) -> Stats {
+		if values.is_empty() {
+			return Stats::default();
+		}
+
+		let mut sorted = values.iter().map(|(v, _)| *v).collect::<Vec<_>>();
+		sorted.sort_by(f64::total_cmp);
+		let n = sorted.len() as f64;
+		let mean = sorted.iter().sum::<f64>() / n;
+		let median = match sorted.len() % 2 {
+			0 => (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0,
+			_ => sorted[sorted.len() / 2],
+		};
+		let variance = sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+
+		let mut histogram = Vec::<Bucket>::new();
+		for value in &sorted {
+			match histogram.last_mut() {
+				Some(bucket) if bucket.value == *value => bucket.count += 1,
+				_ => histogram.push(Bucket { value: *value, count: 1 }),
+			}
+		}
+
+		let passed = values.iter().map(|(_, p)| *p).collect::<Option<Vec<_>>>();
+
+		Stats {
+			count: sorted.len(),
+			mean: Some(mean),
+			median: Some(median),
+			std_dev: Some(variance.sqrt()),
+			histogram,
+			pass_rate: passed.map(|p| p.iter().filter(|x| **x).count() as f64 / n),
+		}
+	}
+}
+
+/// regular grades of one or more subjects with whether they pass
+struct Sample(Vec<(f64, Option<bool>)>);
+
+impl Sample {
+	fn new() -> Self {
+		Sample(vec![])
+	}
+
+	fn add<'a>(&mut self, subject: &Subject, scale: Option<&GradingScale>, entries: impl Iterator<Item = &'a Entry>) {
+		let lower_is_better = subject.bonus_rules.lower_is_better;
+		self.0.extend(entries.map(|x| (x.value, scale.and_then(|s| scales::passes(s, lower_is_better, x.value)))));
+	}
+
+	fn stats(&self) -> Stats {
+		Stats::compute(&self.0)
+	}
+}
+
+/// statistics of one grade event
+#[derive(Clone, Debug, Serialize)]
+pub struct EventStats {
+	pub name: String,
+	pub date: DateTime<Utc>,
+	pub stats: Stats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TermStats {
+	pub term: Term,
+	pub stats: Stats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubjectStats {
+	pub subject: Uuid,
+	pub overall: Stats,
+	pub events: Vec<EventStats>,
+	pub terms: Vec<TermStats>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TeacherStats {
+	pub teacher: Uuid,
+	pub name: String,
+	pub stats: Stats,
+}
+
+fn terms() -> Vec<Term> {
+	let mut terms = Database::<Term>::open()
+		.map(|db| db.read().iter().map(|(_, x)| x).collect::<Vec<_>>())
+		.unwrap_or_default();
+	terms.sort_by_key(|x| x.start);
+	terms
+}
+
+/// whether the grade counts in the term, the same way as [`terms::attributed`]
+fn in_term(entry: &Entry, term: &Term) -> bool {
+	terms::counts_in(entry.term, entry.date.naive_utc().date(), term)
+}
+
+/// statistics of a subject, for its teacher and admins
+pub fn for_subject(subject: Uuid, info: &AuthToken) -> Result<SubjectStats, Status> {
+	let subject = Database::<Subject>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(subject)
+		.ok_or(Status::NotFound)?;
+	if info.typ != "admin" && !(info.typ == "teacher" && subject.teacher == info.id) {
+		return Err(Status::NotFound);
+	}
+
+	let entries = entries(subject.id).ok_or(Status::InternalServerError)?;
+	let scale = scales::for_subject(&subject);
+	let sample = |filter: &dyn Fn(&Entry) -> bool| {
+		let mut sample = Sample::new();
+		sample.add(&subject, scale.as_ref(), entries.iter().filter(|x| filter(x)));
+		sample.stats()
+	};
+
+	let events = entries
+		.iter()
+		.map(|x| (x.date, x.name.clone()))
+		.collect::<BTreeSet<_>>();
+
+	Ok(SubjectStats {
+		subject: subject.id,
+		overall: sample(&|_| true),
+		events: events
+			.into_iter()
+			.map(|(date, name)| {
+				let stats = sample(&|x| x.date == date && x.name == name);
+				EventStats { name, date, stats }
+			})
+			.collect(),
+		terms: terms()
+			.into_iter()
+			.filter(|term| subject.term.is_none_or(|t| t == term.id))
+			.map(|term| {
+				let stats = sample(&|x| in_term(x, &term));
+				TermStats { term, stats }
+			})
+			.filter(|x| x.stats.count > 0)
+			.collect(),
+	})
+}
+
+/// a subject with its scale and cached grades
+type CachedSubject = (Subject, Option<GradingScale>, Arc<Vec<Entry>>);
+
+fn all_subjects() -> Option<Vec<CachedSubject>> {
+	Database::<Subject>::open()?
+		.read()
+		.iter()
+		.map(|(_, subject)| {
+			let scale = scales::for_subject(&subject);
+			let entries = entries(subject.id)?;
+			Some((subject, scale, entries))
+		})
+		.collect()
+}
+
+/// the whole school in every term
+pub fn by_term() -> Option<Vec<TermStats>> {
+	let subjects = all_subjects()?;
+
+	Some(terms()
+		.into_iter()
+		.map(|term| {
+			let mut sample = Sample::new();
+			for (subject, scale, entries) in &subjects {
+				sample.add(subject, scale.as_ref(), entries.iter().filter(|x| in_term(x, &term)));
+			}
+			TermStats { term, stats: sample.stats() }
+		})
+		.collect())
+}
+
+/// grades given in the subjects of each teacher
+pub fn by_teacher() -> Option<Vec<TeacherStats>> {
+	let subjects = all_subjects()?;
+
+	Some(Database::<Teacher>::open()?
+		.read()
+		.iter()
+		.map(|(_, teacher)| {
+			let mut sample = Sample::new();
+			for (subject, scale, entries) in subjects.iter().filter(|(s, _, _)| s.teacher == teacher.id) {
+				sample.add(subject, scale.as_ref(), entries.iter());
+			}
+			TeacherStats { teacher: teacher.id, name: teacher.name, stats: sample.stats() }
+		})
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::{NaiveDate, TimeZone};
+
+	#[test]
+	fn basic_statistics() {
+		let values = [1.0, 2.0, 2.0, 5.0].iter().map(|v| (*v, Some(*v <= 4.0))).collect::<Vec<_>>();
+		let stats = Stats::compute(&values);
+
+		assert_eq!(stats.count, 4);
+		assert_eq!(stats.mean, Some(2.5));
+		assert_eq!(stats.median, Some(2.0));
+		assert_eq!(stats.std_dev, Some(1.5));
+		assert_eq!(stats.pass_rate, Some(0.75));
+		assert_eq!(stats.histogram, vec![
+			Bucket { value: 1.0, count: 1 },
+			Bucket { value: 2.0, count: 2 },
+			Bucket { value: 5.0, count: 1 },
+		]);
+	}
+
+	#[test]
+	fn odd_counts_and_unknown_pass_marks() {
+		let stats = Stats::compute(&[(3.0, Some(true)), (1.0, None), (2.0, Some(false))]);
+
+		assert_eq!(stats.median, Some(2.0));
+		assert_eq!(stats.pass_rate, None);
+		assert_eq!(Stats::compute(&[]), Stats::default());
+	}
+
+	#[test]
+	fn grades_loaded_before_a_write_are_not_cached() {
+		let mut cache = Cache::default();
+		let subject = Uuid::new_v4();
+		let entries = || Arc::new(vec![Entry { value: 1.0, name: String::new(), date: Utc::now(), term: None }]);
+
+		// a reader loads the grades, a grade is written meanwhile
+		let generation = cache.generation(subject);
+		cache.invalidate(subject);
+		cache.store(subject, generation, entries());
+		assert!(!cache.entries.contains_key(&subject));
+
+		let generation = cache.generation(subject);
+		cache.store(subject, generation, entries());
+		assert!(cache.entries.contains_key(&subject));
+	}
+
+	#[test]
+	fn grades_count_in_the_term_they_were_written_in() {
+		let day = |month| NaiveDate::from_ymd_opt(2020, month, 15).unwrap();
+		let term = |start, end| Term {
+			id: Uuid::new_v4(),
+			year: Uuid::nil(),
+			name: String::new(),
+			start: day(start),
+			end: day(end),
+		};
+		let (first, second) = (term(1, 6), term(7, 12));
+		let entry = |month, term| Entry {
+			value: 1.0,
+			name: String::new(),
+			date: Utc.from_utc_datetime(&day(month).and_hms_opt(0, 0, 0).unwrap()),
+			term,
+		};
+
+		// written in the first term, dated in the second
+		assert!(in_term(&entry(8, Some(first.id)), &first));
+		assert!(!in_term(&entry(8, Some(first.id)), &second));
+		assert!(in_term(&entry(8, None), &second));
+	}
+}
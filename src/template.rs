@@ -0,0 +1,197 @@
+//! Šablony
+//!
+//! A small subset of Mustache for report cards. `{{a.b}}` inserts a value,
+//! `{{#list}}...{{/list}}` repeats for every item of a list (or shows the
+//! part once if the value is there), `{{^a}}...{{/a}}` shows the part if
+//! the value is missing, empty or false. Inside a section names are looked
+//! up in the item first and `{{.}}` is the item itself.
+use std::fmt;
+
+use serde_json::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+	Text(String),
+	Var(String),
+	/// name, whether it is inverted, content
+	Section(String, bool, Vec<Node>),
+}
+
+/// why a template can't be parsed
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateError {
+	/// a tag or a section that is never closed
+	Unclosed(String),
+	/// a section closed without being opened
+	Unexpected(String),
+}
+
+impl fmt::Display for TemplateError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TemplateError::Unclosed(v) => write!(f, "{} is never closed", v),
+			TemplateError::Unexpected(v) => write!(f, "{} is closed but never opened", v),
+		}
+	}
+}
+
+/// a parsed template
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Vec<Node>);
+
+impl Template {
+	pub fn parse(src: &str) -> Result<Template, TemplateError> {
+		// the open sections, the bottom one is the template itself
+		let mut stack = vec![(String::new(), false, vec![])];
+		let mut rest = src;
+
+		while let Some(start) = rest.find("{{") {
+			let nodes = &mut stack.last_mut().unwrap().2;
+			if start > 0 {
+				nodes.push(Node::Text(rest[..start].to_string()));
+			}
+
+			let after = &rest[start + 2..];
+			let end = after.find("}}").ok_or_else(|| TemplateError::Unclosed("{{".to_string()))?;
+			let tag = after[..end].trim();
+			rest = &after[end + 2..];
+
+			if let Some(name) = tag.strip_prefix('#') {
+				stack.push((name.trim().to_string(), false, vec![]));
+			} else if let Some(name) = tag.strip_prefix('^') {
+				stack.push((name.trim().to_string(), true, vec![]));
+			} else if let Some(name) = tag.strip_prefix('/') {
+				let name = name.trim();
+				if stack.len() < 2 || stack.last().unwrap().0 != name {
+					return Err(TemplateError::Unexpected(name.to_string()));
+				}
+				let (name, inverted, content) = stack.pop().unwrap();
+				stack.last_mut().unwrap().2.push(Node::Section(name, inverted, content));
+			} else {
+				nodes.push(Node::Var(tag.to_string()));
+			}
+		}
+
+		if stack.len() > 1 {
+			return Err(TemplateError::Unclosed(stack.pop().unwrap().0));
+		}
+		let mut nodes = stack.pop().unwrap().2;
+		if !rest.is_empty() {
+			nodes.push(Node::Text(rest.to_string()));
+		}
+
+		Ok(Template(nodes))
+	}
+
+	/// fills the template in, `escape` makes the values safe for HTML
+	pub fn render(&self, data: &Value, escape: bool) -> String {
+		let mut out = String::new();
+		render(&self.0, &mut vec![data], escape, &mut out);
+		out
+	}
+}
+
+fn lookup<'a>(context: &[&'a Value], path: &str) -> Option<&'a Value> {
+	if path == "." {
+		return context.last().copied();
+	}
+
+	let mut parts = path.split('.');
+	let first = parts.next()?;
+	let start = context.iter().rev().find_map(|x| x.get(first))?;
+	parts.try_fold(start, |value, part| value.get(part))
+}
+
+fn truthy(value: &Value) -> bool {
+	match value {
+		Value::Null | Value::Bool(false) => false,
+		Value::String(s) => !s.is_empty(),
+		Value::Array(a) => !a.is_empty(),
+		_ => true,
+	}
+}
+
+fn text(value: &Value) -> String {
+	match value {
+		Value::Null => String::new(),
+		Value::String(s) => s.clone(),
+		Value::Number(n) => n.as_f64().map_or_else(|| n.to_string(), |x| format!("{}", (x * 100.0).round() / 100.0)),
+		other => other.to_string(),
+	}
+}
+
+fn escape_html(src: &str) -> String {
+	src.chars().fold(String::with_capacity(src.len()), |mut out, c| {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			'\'' => out.push_str("&#39;"),
+			c => out.push(c),
+		}
+		out
+	})
+}
+
+fn render<'a>(nodes: &'a [Node], context: &mut Vec<&'a Value>, escape: bool, out: &mut String) {
+	for node in nodes {
+		match node {
+			Node::Text(t) => out.push_str(t),
+			Node::Var(path) => {
+				let value = lookup(context, path).map(text).unwrap_or_default();
+				out.push_str(&if escape { escape_html(&value) } else { value });
+			}
+			Node::Section(path, inverted, content) => {
+				let value = lookup(context, path).filter(|x| truthy(x));
+
+				match (value, inverted) {
+					(None, true) => render(content, context, escape, out),
+					(Some(Value::Array(items)), false) => items.iter().for_each(|item| {
+						context.push(item);
+						render(content, context, escape, out);
+						context.pop();
+					}),
+					(Some(value), false) => {
+						context.push(value);
+						render(content, context, escape, out);
+						context.pop();
+					}
+					_ => (),
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use serde_json::json;
+
+	#[test]
+	fn values_and_sections() {
+		let data = json!({
+			"name": "Tom & Jerry",
+			"term": { "name": "1." },
+			"marks": [{ "subject": "Math", "mark": 1.5 }, { "subject": "Art", "mark": null }],
+			"notes": [],
+		});
+		let template = Template::parse(
+			"{{name}} {{ term.name }}:{{#marks}} {{subject}}={{mark}}{{^mark}}-{{/mark}} ({{term.name}}){{/marks}}{{^notes}} none{{/notes}}",
+		)
+		.unwrap();
+
+		assert_eq!(template.render(&data, false), "Tom & Jerry 1.: Math=1.5 (1.) Art=- (1.) none");
+		assert!(template.render(&data, true).starts_with("Tom &amp; Jerry"));
+	}
+
+	#[test]
+	fn broken_templates() {
+		assert_eq!(Template::parse("{{#a}}x"), Err(TemplateError::Unclosed("a".to_string())));
+		assert_eq!(Template::parse("{{#a}}x{{/b}}"), Err(TemplateError::Unexpected("b".to_string())));
+		assert_eq!(Template::parse("x{{/a}}"), Err(TemplateError::Unexpected("a".to_string())));
+		assert_eq!(Template::parse("{{a"), Err(TemplateError::Unclosed("{{".to_string())));
+	}
+}
@@ -0,0 +1,174 @@
+//! Školní roky a pololetí
+//!
+//! School years are split into terms that don't overlap, so every grade
+//! belongs to the term its date falls into. Subjects can be bound to a term,
+//! subjects without one are taught the whole year. Final marks are computed
+//! per term from the grades given during it.
+use crate::calc::{self, Summary};
+use crate::guardians;
+use crate::auth::AuthToken;
+use crate::db::{Database, NewEntry, NewEntryPartial};
+use crate::models::{Grade, NewSchoolYear, NewTerm, SchoolYear, Student, Subject, Term};
+
+use uuid::Uuid;
+use chrono::NaiveDate;
+use serde::Serialize;
+use rocket::http::Status;
+
+/// the final mark of a subject in a term
+#[derive(Clone, Debug, Serialize)]
+pub struct TermMark {
+	pub subject: Subject,
+	pub grades: Vec<Grade>,
+	#[serde(flatten)]
+	pub summary: Summary,
+}
+
+fn overlaps(a: (NaiveDate, NaiveDate), b: (NaiveDate, NaiveDate)) -> bool {
+	a.0 <= b.1 && b.0 <= a.1
+}
+
+/// adds a school year, it must not overlap another one
+pub fn create_year(input: NewSchoolYear) -> Result<SchoolYear, Status> {
+	if input.start > input.end {
+		return Err(Status::BadRequest);
+	}
+
+	let db = Database::<SchoolYear>::open().ok_or(Status::InternalServerError)?;
+	if db.read().iter().any(|(_, x)| overlaps((x.start, x.end), (input.start, input.end))) {
+		return Err(Status::Conflict);
+	}
+
+	let (id, year) = SchoolYear::create(input);
+	(id, year.clone()).save().map_err(|_| Status::InternalServerError)?;
+	Ok(year)
+}
+
+/// adds a term, it must lie in its year and not overlap another term
+pub fn create_term(input: NewTerm) -> Result<Term, Status> {
+	let year = Database::<SchoolYear>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(input.year)
+		.ok_or(Status::NotFound)?;
+
+	if input.start > input.end || input.start < year.start || input.end > year.end {
+		return Err(Status::BadRequest);
+	}
+
+	let db = Database::<Term>::open().ok_or(Status::InternalServerError)?;
+	if db.read().iter().any(|(_, x)| overlaps((x.start, x.end), (input.start, input.end))) {
+		return Err(Status::Conflict);
+	}
+
+	let (id, term) = Term::create(input);
+	(id, term.clone()).save().map_err(|_| Status::InternalServerError)?;
+	Ok(term)
+}
+
+/// the term a date belongs to, grades are attributed to it when they're written
+pub fn term_of(date: NaiveDate) -> Option<Term> {
+	Database::<Term>::open()?
+		.read()
+		.iter()
+		.map(|(_, x)| x)
+		.find(|x| x.contains(date))
+}
+
+/// whether a grade counts in the term, grades given before the term
+/// was set up go by their date
+pub fn attributed(grade: &Grade, term: &Term) -> bool {
+	counts_in(grade.term, grade.date.naive_utc().date(), term)
+}
+
+/// [`attributed`] for whoever keeps only the grade's term and day
+pub fn counts_in(written_in: Option<Uuid>, day: NaiveDate, term: &Term) -> bool {
+	written_in.map_or_else(|| term.contains(day), |t| t == term.id)
+}
+
+/// students see themselves, guardians their linked students,
+/// teachers and admins everybody
+pub fn can_see(student: Uuid, info: &AuthToken) -> bool {
+	match info.typ.as_str() {
+		"teacher" | "admin" => true,
+		"student" => info.id == student,
+		"guardian" => guardians::is_linked(info.id, student),
+		_ => false,
+	}
+}
+
+/// final marks of a student's subjects in a term
+pub fn marks(term: Uuid, student: Uuid) -> Result<Vec<TermMark>, Status> {
+	let term = Database::<Term>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(term)
+		.ok_or(Status::NotFound)?;
+	let student = Database::<Student>::open()
+		.ok_or(Status::InternalServerError)?
+		.read()
+		.get(student)
+		.ok_or(Status::NotFound)?;
+	let subjects = Database::<Subject>::open().ok_or(Status::InternalServerError)?;
+	let grades = Database::<Grade>::open()
+		.ok_or(Status::InternalServerError)?
+		.find("student", &student.id)
+		.into_iter()
+		.filter(|x| attributed(x, &term))
+		.collect::<Vec<_>>();
+
+	Ok(student.subjects
+		.iter()
+		.filter_map(|id| subjects.read().get(id))
+		.filter(|x| x.term.is_none_or(|t| t == term.id))
+		.map(|subject| {
+			let grades = grades.iter().filter(|x| x.subject == subject.id).cloned().collect::<Vec<_>>();
+			let summary = calc::summarize(&subject, &grades);
+			TermMark { subject, grades, summary }
+		})
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use chrono::{TimeZone, Utc};
+	use crate::models::GradeVal;
+
+	fn term(start: u32, end: u32) -> Term {
+		Term {
+			id: Uuid::new_v4(),
+			year: Uuid::nil(),
+			name: String::new(),
+			start: NaiveDate::from_ymd_opt(2020, start, 1).unwrap(),
+			end: NaiveDate::from_ymd_opt(2020, end, 28).unwrap(),
+		}
+	}
+
+	fn grade(month: u32, term: Option<Uuid>) -> Grade {
+		Grade {
+			id: Uuid::new_v4(),
+			name: String::new(),
+			val: GradeVal::Regular(1.0),
+			description: None,
+			date: Utc.from_utc_datetime(&NaiveDate::from_ymd_opt(2020, month, 15).unwrap().and_hms_opt(0, 0, 0).unwrap()),
+			subject: Uuid::nil(),
+			student: Uuid::nil(),
+			category: None,
+			weight: None,
+			term,
+		}
+	}
+
+	#[test]
+	fn grades_are_attributed_to_terms() {
+		let (first, second) = (term(1, 6), term(7, 12));
+
+		assert!(attributed(&grade(3, Some(first.id)), &first));
+		assert!(!attributed(&grade(3, Some(first.id)), &second));
+		// written before the terms existed
+		assert!(attributed(&grade(8, None), &second));
+		assert!(!attributed(&grade(8, None), &first));
+	}
+}
@@ -0,0 +1,16 @@
+//! Pomůcky pro testy
+//!
+//! The database is opened once per process, so every test that touches it
+//! has to agree on where it lives.
+
+use std::env;
+use std::sync::Once;
+
+/// points `DATABASE_URL` to a scratch directory of this test run
+pub fn setup() {
+	static INIT: Once = Once::new();
+	INIT.call_once(|| {
+		let path = env::temp_dir().join(format!("grades-test-{}", std::process::id()));
+		env::set_var("DATABASE_URL", path);
+	});
+}
@@ -0,0 +1,178 @@
+//! Dvoufázové ověření pomocí TOTP (RFC 6238)
+//!
+//! Codes have 6 digits, a step of 30 seconds and use HMAC-SHA1, which is
+//! what all the usual authenticator apps expect. A code from the previous
+//! or next step is accepted as well to tolerate clock drift, but a step
+//! can only be used once.
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+
+/// length of a time step in seconds
+pub const STEP: i64 = 30;
+
+const DIGITS: u32 = 6;
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// a new random 160-bit secret
+pub fn generate_secret() -> Vec<u8> {
+	let mut secret = vec![0; 20];
+	rand_bytes(&mut secret).expect("failed to generate a TOTP secret");
+	secret
+}
+
+/// unpadded base32, the format authenticator apps want secrets in
+pub fn base32(data: &[u8]) -> String {
+	let mut out = String::new();
+
+	for chunk in data.chunks(5) {
+		let mut buf = [0_u8; 5];
+		buf[..chunk.len()].copy_from_slice(chunk);
+		let bits = buf.iter().fold(0_u64, |acc, b| acc << 8 | u64::from(*b));
+
+		for i in 0..(chunk.len() * 8).div_ceil(5) {
+			out.push(ALPHABET[(bits >> (35 - i * 5) & 31) as usize] as char);
+		}
+	}
+
+	out
+}
+
+/// URI to put in a QR code
+pub fn otpauth_uri(secret: &[u8], account: &str) -> String {
+	format!(
+		"otpauth://totp/Znamky:{}?secret={}&issuer=Znamky&algorithm=SHA1&digits={}&period={}",
+		urlencode(account),
+		base32(secret),
+		DIGITS,
+		STEP,
+	)
+}
+
+fn urlencode(s: &str) -> String {
+	s.bytes()
+		.map(|b| match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+			_ => format!("%{:02X}", b),
+		})
+		.collect()
+}
+
+/// HOTP value for a counter (RFC 4226)
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+	let key = PKey::hmac(secret).expect("HMAC keys can have any length");
+	let mut signer = Signer::new(MessageDigest::sha1(), &key).expect("SHA-1 is always available");
+	signer.update(&counter.to_be_bytes()).expect("HMAC can't fail");
+	let mac = signer.sign_to_vec().expect("HMAC can't fail");
+
+	let offset = (mac[mac.len() - 1] & 0xf) as usize;
+	let value = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+	value % 10_u32.pow(DIGITS)
+}
+
+/// the code for a unix timestamp
+pub fn code(secret: &[u8], timestamp: i64) -> String {
+	format!("{:0width$}", hotp(secret, (timestamp / STEP) as u64), width = DIGITS as usize)
+}
+
+/// checks a code, returns the step it belongs to;
+/// steps up to `last_step` were already used and are refused
+pub fn verify(secret: &[u8], code: &str, timestamp: i64, last_step: u64) -> Option<u64> {
+	let code = code.trim();
+	if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+		return None;
+	}
+
+	let current = timestamp / STEP;
+	(current - 1..=current + 1)
+		.filter(|step| *step >= 0 && *step as u64 > last_step)
+		.find(|step| constant_time_eq(code, &code_at(secret, *step)))
+		.map(|step| step as u64)
+}
+
+fn code_at(secret: &[u8], step: i64) -> String {
+	code(secret, step * STEP)
+}
+
+/// constant time comparison, codes and passwords must not leak through timing
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+	a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// ten new recovery codes
+pub fn generate_recovery_codes() -> Vec<String> {
+	(0..10)
+		.map(|_| {
+			let mut bytes = vec![0; 5];
+			rand_bytes(&mut bytes).expect("failed to generate a recovery code");
+			let code = base32(&bytes).to_lowercase();
+			format!("{}-{}", &code[..4], &code[4..])
+		})
+		.collect()
+}
+
+/// recovery codes are only stored hashed
+pub fn hash_recovery_code(code: &str) -> String {
+	let normalized = code.trim().to_lowercase().replace('-', "");
+	let digest = hash(MessageDigest::sha256(), normalized.as_bytes()).expect("SHA-256 is always available");
+
+	digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+	#[test]
+	fn rfc_6238_vectors() {
+		// the RFC lists 8 digits, we use the last 6
+		assert_eq!(code(RFC_SECRET, 59), "287082");
+		assert_eq!(code(RFC_SECRET, 1111111109), "081804");
+		assert_eq!(code(RFC_SECRET, 1111111111), "050471");
+		assert_eq!(code(RFC_SECRET, 1234567890), "005924");
+		assert_eq!(code(RFC_SECRET, 2000000000), "279037");
+	}
+
+	#[test]
+	fn base32_matches_rfc_4648() {
+		assert_eq!(base32(b""), "");
+		assert_eq!(base32(b"f"), "MY");
+		assert_eq!(base32(b"fo"), "MZXQ");
+		assert_eq!(base32(b"foo"), "MZXW6");
+		assert_eq!(base32(b"foob"), "MZXW6YQ");
+		assert_eq!(base32(b"fooba"), "MZXW6YTB");
+		assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+	}
+
+	#[test]
+	fn verify_tolerates_drift_but_not_replays() {
+		let now = 1111111111;
+
+		assert_eq!(verify(RFC_SECRET, "050471", now, 0), Some(now as u64 / 30));
+		assert_eq!(verify(RFC_SECRET, "050471", now + 30, 0), Some(now as u64 / 30));
+		assert_eq!(verify(RFC_SECRET, "050471", now + 90, 0), None);
+		assert_eq!(verify(RFC_SECRET, "050471", now, now as u64 / 30), None);
+		assert_eq!(verify(RFC_SECRET, "05047", now, 0), None);
+		assert_eq!(verify(RFC_SECRET, "05047a", now, 0), None);
+	}
+
+	#[test]
+	fn recovery_codes_hash_loosely() {
+		let codes = generate_recovery_codes();
+
+		assert_eq!(codes.len(), 10);
+		assert_eq!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[0].to_uppercase().replace('-', "")));
+		assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+	}
+
+	#[test]
+	fn otpauth_uri_is_escaped() {
+		assert_eq!(
+			otpauth_uri(b"foo", "a b@c.cz"),
+			"otpauth://totp/Znamky:a%20b%40c.cz?secret=MZXW6&issuer=Znamky&algorithm=SHA1&digits=6&period=30",
+		);
+	}
+}